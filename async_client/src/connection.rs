@@ -0,0 +1,179 @@
+use std::io;
+use std::time::Duration;
+
+use queue_client::reply;
+use queue_client::reply::Fenced;
+use queue_client::Error;
+use queue_experiments::commands::Command;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+
+// One connection to the server. Requests on it run one at a time, a Pipeline sends several at once.
+pub struct Connection {
+    reader: BufReader<OwnedReadHalf>,
+    writer: OwnedWriteHalf,
+    // Set while a request is unanswered. A request given up half way, e.g. by a timeout,
+    // leaves its reply in the way of the next one so the connection can't be reused.
+    broken: bool,
+}
+
+impl Connection {
+    pub async fn connect(address: &str) -> Result<Connection, Error> {
+        let stream = TcpStream::connect(address).await?;
+        stream.set_nodelay(true)?;
+        let (reader, writer) = stream.into_split();
+        Ok(Connection {
+            reader: BufReader::new(reader),
+            writer,
+            broken: false,
+        })
+    }
+
+    pub async fn auth(&mut self, user: &str, password: &str) -> Result<(), Error> {
+        let line = self.request(&Command::Auth(user.to_string(), password.to_string())).await?;
+        reply::success(line)
+    }
+
+    pub async fn push(&mut self, queue_name: &str, data: &str) -> Result<(), Error> {
+        self.push_item(queue_name, data, None, None).await
+    }
+
+    pub async fn push_item(&mut self, queue_name: &str, data: &str, group: Option<&str>, ttl: Option<Duration>) -> Result<(), Error> {
+        let line = self.request(&push_command(queue_name, data, group, ttl)).await?;
+        reply::success(line)
+    }
+
+    // None when the queue is empty or doesn't exist
+    pub async fn pop(&mut self, queue_name: &str) -> Result<Option<String>, Error> {
+        let line = self.request(&Command::Pop(queue_name.to_string())).await?;
+        reply::item(line)
+    }
+
+    // Waits for an item, nothing else can be sent on the connection meanwhile
+    pub async fn bpop(&mut self, queue_name: &str) -> Result<String, Error> {
+        let line = self.request(&Command::BlockingPop(queue_name.to_string(), None, false)).await?;
+        reply::blocking_item(line)
+    }
+
+    // Waits up to timeout for an item, given with how many times it has been tried. That is 1
+    // unless transactions that popped it rolled back. None once the timeout passed.
+    pub async fn bpop_attempt(&mut self, queue_name: &str, timeout: Duration) -> Result<Option<(u32, String)>, Error> {
+        let line = self.request(&Command::BlockingPop(queue_name.to_string(), Some(timeout), true)).await?;
+        reply::attempted_item(line)
+    }
+
+    // Sends every command before reading any reply. The outer error is for the connection,
+    // the inner ones are each command's, pushes and empty pops give None.
+    pub async fn pipeline(&mut self, pipeline: &Pipeline) -> Result<Vec<Result<Option<String>, Error>>, Error> {
+        let mut message = String::new();
+        for command in pipeline.commands.iter() {
+            message.push_str(&reply::encode(command)?);
+        }
+        self.broken = true;
+        self.writer.write_all(message.as_bytes()).await?;
+        let mut replies = Vec::with_capacity(pipeline.commands.len());
+        for command in pipeline.commands.iter() {
+            let line = self.read_line().await?;
+            replies.push(match *command {
+                Command::Push(..) => reply::success(line).map(|_| None),
+                _ => reply::item(line),
+            });
+        }
+        self.broken = false;
+        Ok(replies)
+    }
+
+    pub fn is_broken(&self) -> bool {
+        self.broken
+    }
+
+    // Keeps the pool from reusing the connection, it is closed once dropped
+    pub(crate) fn discard(&mut self) {
+        self.broken = true;
+    }
+
+    // Whether the server closed the connection while it sat in the pool
+    pub(crate) fn is_closed(&self) -> bool {
+        let mut buffer = [0; 1];
+        !matches!(self.reader.get_ref().try_read(&mut buffer), Err(ref error) if error.kind() == io::ErrorKind::WouldBlock)
+    }
+
+    // For commands answered with a single line
+    pub(crate) async fn request(&mut self, command: &Command) -> Result<String, Error> {
+        let message = reply::encode(command)?;
+        self.broken = true;
+        self.writer.write_all(message.as_bytes()).await?;
+        let line = self.read_line().await?;
+        self.broken = false;
+        Ok(line)
+    }
+
+    // For commands that aren't answered when they succeed, see reply::encode_fenced
+    pub(crate) async fn request_fenced(&mut self, command: &Command) -> Result<Vec<String>, Error> {
+        let message = reply::encode_fenced(command)?;
+        self.broken = true;
+        self.writer.write_all(message.as_bytes()).await?;
+        let mut lines = Vec::new();
+        loop {
+            let line = self.read_line().await?;
+            match reply::fenced(&line, lines.is_empty()) {
+                Fenced::Line => {
+                    lines.push(line);
+                }
+                Fenced::Done => {
+                    break;
+                }
+                Fenced::SkipOne => {
+                    self.read_line().await?;
+                    lines.push(line);
+                    break;
+                }
+            }
+        }
+        self.broken = false;
+        Ok(lines)
+    }
+
+    // Data may contain a bare \n, only \r\n ends a reply
+    async fn read_line(&mut self) -> Result<String, Error> {
+        let mut line = Vec::new();
+        while !line.ends_with(b"\r\n") {
+            if self.reader.read_until(b'\n', &mut line).await? == 0 {
+                return Err(Error::Io(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed")));
+            }
+        }
+        line.truncate(line.len() - 2);
+        String::from_utf8(line).map_err(|_| Error::Server("Non utf8 characters in reply".to_string()))
+    }
+}
+
+// Commands sent together by Connection::pipeline
+#[derive(Default)]
+pub struct Pipeline {
+    commands: Vec<Command>,
+}
+
+impl Pipeline {
+    pub fn new() -> Pipeline {
+        Pipeline::default()
+    }
+
+    pub fn push(&mut self, queue_name: &str, data: &str) -> &mut Pipeline {
+        self.push_item(queue_name, data, None, None)
+    }
+
+    pub fn push_item(&mut self, queue_name: &str, data: &str, group: Option<&str>, ttl: Option<Duration>) -> &mut Pipeline {
+        self.commands.push(push_command(queue_name, data, group, ttl));
+        self
+    }
+
+    pub fn pop(&mut self, queue_name: &str) -> &mut Pipeline {
+        self.commands.push(Command::Pop(queue_name.to_string()));
+        self
+    }
+}
+
+pub(crate) fn push_command(queue_name: &str, data: &str, group: Option<&str>, ttl: Option<Duration>) -> Command {
+    Command::Push(data.to_string(), queue_name.to_string(), group.map(|group| group.to_string()), ttl)
+}
@@ -0,0 +1,15 @@
+extern crate log;
+extern crate queue_client;
+extern crate queue_experiments;
+extern crate tokio;
+
+pub mod connection;
+pub mod pool;
+pub mod transaction;
+pub mod worker;
+
+pub use connection::{Connection,Pipeline};
+pub use pool::{Pool,PoolOptions,PooledConnection};
+pub use queue_client::{Error};
+pub use transaction::{Transaction};
+pub use worker::{Job,RetryPolicy,Worker};
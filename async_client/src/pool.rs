@@ -0,0 +1,157 @@
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use queue_client::Error;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time;
+
+use crate::connection::Connection;
+use crate::transaction::Transaction;
+
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct PoolOptions {
+    // Most connections open at once, callers wait for one to be returned beyond that
+    pub size: usize,
+    pub auth: Option<(String, String)>,
+    // Connecting is retried this many times, waiting twice as long after each failure
+    pub connect_attempts: u32,
+    pub min_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for PoolOptions {
+    fn default() -> PoolOptions {
+        PoolOptions {
+            size: 8,
+            auth: None,
+            connect_attempts: 6,
+            min_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(5),
+        }
+    }
+}
+
+struct PoolInner {
+    address: String,
+    options: PoolOptions,
+    idle: Mutex<Vec<Connection>>,
+    permits: Arc<Semaphore>,
+}
+
+// Connections to one server, opened when first needed. A connection that fails is closed
+// and the next caller gets a new one.
+#[derive(Clone)]
+pub struct Pool {
+    inner: Arc<PoolInner>,
+}
+
+impl Pool {
+    pub fn new(address: &str, options: PoolOptions) -> Pool {
+        Pool {
+            inner: Arc::new(PoolInner {
+                address: address.to_string(),
+                permits: Arc::new(Semaphore::new(options.size)),
+                options,
+                idle: Mutex::new(Vec::new()),
+            }),
+        }
+    }
+
+    pub async fn get(&self) -> Result<PooledConnection, Error> {
+        let permit = self.inner.permits.clone().acquire_owned().await.expect("pool semaphore closed");
+        let idle = self.take_idle();
+        let connection = match idle {
+            Some(connection) => connection,
+            None => self.connect().await?,
+        };
+        Ok(PooledConnection {
+            connection: Some(connection),
+            pool: self.inner.clone(),
+            _permit: permit,
+        })
+    }
+
+    pub async fn push(&self, queue_name: &str, data: &str) -> Result<(), Error> {
+        self.get().await?.push(queue_name, data).await
+    }
+
+    pub async fn pop(&self, queue_name: &str) -> Result<Option<String>, Error> {
+        self.get().await?.pop(queue_name).await
+    }
+
+    // Holds one of the pool's connections until an item arrives
+    pub async fn bpop(&self, queue_name: &str) -> Result<String, Error> {
+        self.get().await?.bpop(queue_name).await
+    }
+
+    pub async fn transaction(&self) -> Result<Transaction, Error> {
+        let connection = self.get().await?;
+        Transaction::begin(connection).await
+    }
+
+    // Connections the server closed while idle are dropped
+    fn take_idle(&self) -> Option<Connection> {
+        let mut idle = self.inner.idle.lock().unwrap();
+        while let Some(connection) = idle.pop() {
+            if !connection.is_closed() {
+                return Some(connection);
+            }
+        }
+        None
+    }
+
+    // Failing AUTH isn't retried
+    async fn connect(&self) -> Result<Connection, Error> {
+        let options = &self.inner.options;
+        let mut backoff = options.min_backoff;
+        let mut attempt = 1;
+        let mut connection = loop {
+            match Connection::connect(&self.inner.address).await {
+                Ok(connection) => break connection,
+                Err(error) if attempt >= options.connect_attempts => return Err(error),
+                Err(_) => {
+                    time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(options.max_backoff);
+                    attempt += 1;
+                }
+            }
+        };
+        if let Some((ref user, ref password)) = options.auth {
+            connection.auth(user, password).await?;
+        }
+        Ok(connection)
+    }
+}
+
+// Goes back to the pool when dropped unless it failed
+pub struct PooledConnection {
+    connection: Option<Connection>,
+    pool: Arc<PoolInner>,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl Deref for PooledConnection {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        self.connection.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledConnection {
+    fn deref_mut(&mut self) -> &mut Connection {
+        self.connection.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledConnection {
+    fn drop(&mut self) {
+        if let Some(connection) = self.connection.take() {
+            if !connection.is_broken() {
+                self.pool.idle.lock().unwrap().push(connection);
+            }
+        }
+    }
+}
@@ -0,0 +1,93 @@
+use std::time::Duration;
+
+use queue_client::reply;
+use queue_client::Error;
+use queue_experiments::commands::Command;
+use tokio::runtime::Handle;
+
+use crate::connection::push_command;
+use crate::pool::PooledConnection;
+
+// An open transaction on a connection of its own. Pushes are held until commit, popped items
+// until commit or abort. Dropping it without either aborts, so a task that returns early
+// or panics can't leave items held.
+pub struct Transaction {
+    // None once committed or aborted
+    connection: Option<PooledConnection>,
+}
+
+impl Transaction {
+    pub(crate) async fn begin(mut connection: PooledConnection) -> Result<Transaction, Error> {
+        let lines = connection.request_fenced(&Command::Begin).await?;
+        reply::silent(lines)?;
+        Ok(Transaction { connection: Some(connection) })
+    }
+
+    pub async fn push(&mut self, queue_name: &str, data: &str) -> Result<(), Error> {
+        self.push_item(queue_name, data, None, None).await
+    }
+
+    pub async fn push_item(&mut self, queue_name: &str, data: &str, group: Option<&str>, ttl: Option<Duration>) -> Result<(), Error> {
+        let lines = self.connection().request_fenced(&push_command(queue_name, data, group, ttl)).await?;
+        reply::silent(lines)
+    }
+
+    pub async fn pop(&mut self, queue_name: &str) -> Result<Option<String>, Error> {
+        self.connection().pop(queue_name).await
+    }
+
+    pub async fn bpop(&mut self, queue_name: &str) -> Result<String, Error> {
+        self.connection().bpop(queue_name).await
+    }
+
+    pub async fn bpop_attempt(&mut self, queue_name: &str, timeout: Duration) -> Result<Option<(u32, String)>, Error> {
+        self.connection().bpop_attempt(queue_name, timeout).await
+    }
+
+    // Error::Rejected when something didn't fit, the transaction is then rolled back
+    pub async fn commit(mut self) -> Result<(), Error> {
+        let mut connection = self.connection.take().unwrap();
+        let lines = connection.request_fenced(&Command::Commit).await?;
+        reply::committed(lines)
+    }
+
+    pub async fn abort(mut self) -> Result<(), Error> {
+        let mut connection = self.connection.take().unwrap();
+        let lines = connection.request_fenced(&Command::Abort(None)).await?;
+        reply::silent(lines)
+    }
+
+    // Aborts, with popped items only handed out again once delay has passed
+    pub async fn abort_after(mut self, delay: Duration) -> Result<(), Error> {
+        let mut connection = self.connection.take().unwrap();
+        let lines = connection.request_fenced(&Command::Abort(Some(delay))).await?;
+        reply::silent(lines)
+    }
+
+    fn connection(&mut self) -> &mut PooledConnection {
+        self.connection.as_mut().unwrap()
+    }
+}
+
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        let mut connection = match self.connection.take() {
+            Some(connection) => connection,
+            None => {
+                return;
+            }
+        };
+        // The server rolls back when the connection closes, which is all that can be done
+        // outside a runtime or when a reply is still outstanding
+        match Handle::try_current() {
+            Ok(handle) if !connection.is_broken() => {
+                handle.spawn(async move {
+                    let _ = connection.request_fenced(&Command::Abort(None)).await;
+                });
+            }
+            _ => {
+                connection.discard();
+            }
+        }
+    }
+}
@@ -0,0 +1,203 @@
+use std::error::Error as StdError;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{debug, error, warn};
+use tokio::sync::watch;
+use tokio::time;
+
+use crate::pool::Pool;
+use crate::transaction::Transaction;
+
+pub type HandlerError = Box<dyn StdError + Send + Sync>;
+type HandlerFuture = Pin<Box<dyn Future<Output = Result<(), HandlerError>> + Send>>;
+type Handler = Arc<dyn Fn(Job) -> HandlerFuture + Send + Sync>;
+
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct Job {
+    pub queue_name: String,
+    pub data: String,
+    // 1 on the first try. Kept by the server, so a job whose worker died counts as tried.
+    pub attempt: u32,
+}
+
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct RetryPolicy {
+    // Including the first try
+    pub max_attempts: u32,
+    pub min_backoff: Duration,
+    pub max_backoff: Duration,
+    // Where a job goes once it is out of attempts, so one that always fails isn't retried forever.
+    // Under sharding it must be in the slot of the job's queue.
+    pub dead_letter: String,
+}
+
+impl RetryPolicy {
+    pub fn new(dead_letter: &str) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 3,
+            min_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+            dead_letter: dead_letter.to_string(),
+        }
+    }
+
+    // How long to wait after attempt failed, doubling each time
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let doublings = attempt.saturating_sub(1).min(31);
+        self.min_backoff.saturating_mul(1 << doublings).min(self.max_backoff)
+    }
+}
+
+struct Route {
+    queue_name: String,
+    handler: Handler,
+    retry: RetryPolicy,
+}
+
+// Runs handlers for items popped from their queues. Each job is popped inside a transaction
+// that is committed once its handler succeeds. A handler that fails or panics aborts it
+// so the job goes back on its queue after a backoff, and once out of attempts it is moved to the dead letter queue.
+pub struct Worker {
+    pool: Pool,
+    routes: Vec<Arc<Route>>,
+    concurrency: usize,
+    poll_interval: Duration,
+}
+
+impl Worker {
+    pub fn new(pool: Pool) -> Worker {
+        Worker {
+            pool,
+            routes: Vec::new(),
+            concurrency: 1,
+            poll_interval: Duration::from_secs(1),
+        }
+    }
+
+    // How many jobs run at once, the pool needs at least as many connections
+    pub fn concurrency(&mut self, concurrency: usize) -> &mut Worker {
+        self.concurrency = concurrency;
+        self
+    }
+
+    // How long an idle worker waits on one queue before trying the next, and so how long
+    // shutdown may wait for it
+    pub fn poll_interval(&mut self, poll_interval: Duration) -> &mut Worker {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    pub fn handle<F, R>(&mut self, queue_name: &str, retry: RetryPolicy, handler: F) -> &mut Worker
+        where F: Fn(Job) -> R + Send + Sync + 'static,
+              R: Future<Output = Result<(), HandlerError>> + Send + 'static
+    {
+        let handler: Handler = Arc::new(move |job| Box::pin(handler(job)) as HandlerFuture);
+        self.routes.push(Arc::new(Route { queue_name: queue_name.to_string(), handler, retry }));
+        self
+    }
+
+    // Works until shutdown completes, then waits for the jobs in flight
+    pub async fn run<S: Future<Output = ()>>(&self, shutdown: S) {
+        let (stop, stopping) = watch::channel(false);
+        let routes = Arc::new(self.routes.clone());
+        let workers: Vec<_> = (0..self.concurrency).map(|first| {
+            tokio::spawn(work(self.pool.clone(), routes.clone(), first, self.poll_interval, stopping.clone()))
+        }).collect();
+        shutdown.await;
+        debug!(workers = workers.len(); "worker shutting down");
+        let _ = stop.send(true);
+        for worker in workers {
+            let _ = worker.await;
+        }
+    }
+}
+
+// Takes the queues in turn, starting at first so workers spread over them. Each waits
+// on the server for up to poll_interval, so idle workers don't keep sending requests.
+async fn work(pool: Pool, routes: Arc<Vec<Arc<Route>>>, first: usize, poll_interval: Duration, mut stopping: watch::Receiver<bool>) {
+    if routes.is_empty() {
+        return;
+    }
+    let mut next = first;
+    while !*stopping.borrow() {
+        let route = routes[next % routes.len()].clone();
+        next += 1;
+        match take_job(&pool, &route.queue_name, poll_interval).await {
+            Ok(Some((transaction, attempt, data))) => {
+                run_job(transaction, &route, attempt, data).await;
+            }
+            Ok(None) => {
+            }
+            // Waited out so a server that is down isn't sent a request per route as fast as it refuses them
+            Err(error) => {
+                warn!(queue = route.queue_name.as_str(), error:% = error; "could not pop job");
+                let _ = time::timeout(poll_interval, stopping.changed()).await;
+            }
+        }
+    }
+}
+
+async fn take_job(pool: &Pool, queue_name: &str, timeout: Duration) -> Result<Option<(Transaction, u32, String)>, queue_client::Error> {
+    let mut transaction = pool.transaction().await?;
+    match transaction.bpop_attempt(queue_name, timeout).await? {
+        Some((attempt, data)) => Ok(Some((transaction, attempt, data))),
+        None => {
+            transaction.abort().await?;
+            Ok(None)
+        }
+    }
+}
+
+// The job isn't held while it waits to be retried, the abort makes the server hand it out
+// again once the backoff has passed, to this or any other worker
+async fn run_job(transaction: Transaction, route: &Route, attempt: u32, data: String) {
+    let queue_name = route.queue_name.as_str();
+    let job = Job { queue_name: route.queue_name.clone(), data: data.clone(), attempt };
+    // Spawned so a panic only ends the handler
+    let result = match tokio::spawn((route.handler)(job)).await {
+        Ok(result) => result,
+        Err(_) => Err("handler panicked".into()),
+    };
+    let failure = match result {
+        Ok(()) => {
+            if let Err(error) = transaction.commit().await {
+                warn!(queue = queue_name, error:% = error; "could not commit job");
+            }
+            return;
+        }
+        Err(failure) => failure,
+    };
+    warn!(queue = queue_name, attempt = attempt, error:% = failure; "job failed");
+    if attempt >= route.retry.max_attempts {
+        give_up(transaction, route, data).await;
+        return;
+    }
+    if let Err(error) = transaction.abort_after(route.retry.backoff(attempt)).await {
+        warn!(queue = queue_name, error:% = error; "could not abort job");
+    }
+}
+
+async fn give_up(mut transaction: Transaction, route: &Route, data: String) {
+    let queue_name = route.queue_name.as_str();
+    let result = match transaction.push(&route.retry.dead_letter, &data).await {
+        Ok(()) => transaction.commit().await,
+        Err(error) => Err(error),
+    };
+    // A rejected dead letter push rolls back, putting the job back on its queue
+    match result {
+        Ok(()) => {
+            debug!(queue = queue_name, dead_letter = route.retry.dead_letter.as_str(); "job out of attempts");
+        }
+        Err(queue_client::Error::Rejected(lines)) => {
+            error!(queue = queue_name, data = data.as_str(), rejected:? = lines; "job put back, dead letter queue rejected it");
+        }
+        Err(error) => {
+            warn!(queue = queue_name, error:% = error; "could not give up job");
+        }
+    }
+}
@@ -0,0 +1,110 @@
+#![feature(plugin)]
+#![cfg_attr(test, plugin(stainless))]
+
+#[cfg(test)]
+mod tests {
+    pub use mio::net::TcpListener;
+    pub use queue_async_client::{Pipeline, Pool, PoolOptions};
+    pub use queue_experiments::auth::Users;
+    pub use queue_experiments::event_loop::EventLoop;
+    pub use queue_experiments::net::Listener;
+    pub use queue_experiments::queue_table::QueueTable;
+    pub use std::io::{Read, Write};
+    pub use std::net;
+    pub use std::thread;
+    pub use std::time::Duration;
+    pub use tokio::runtime::Runtime;
+
+    describe! async_client {
+        before_each {
+            let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.set_nonblocking(true).unwrap();
+            let address = listener.local_addr().unwrap().to_string();
+            let queue_table = QueueTable::new();
+            let server_queue_table = queue_table.clone();
+            thread::spawn(move || {
+                let listeners = vec![Listener::Tcp(TcpListener::from_std(listener))];
+                EventLoop::new(listeners, &server_queue_table, &Users::new()).unwrap().run().unwrap();
+            });
+            let runtime = Runtime::new().unwrap();
+            // A single connection, so each request waits for the last to give it back
+            let pool = Pool::new(&address, PoolOptions { size: 1, ..PoolOptions::default() });
+        }
+
+        it "pipelines_requests" {
+            runtime.block_on(async {
+                let mut connection = pool.get().await.unwrap();
+                let replies = connection.pipeline(Pipeline::new().push("jobs", "a").push("jobs", "b").pop("jobs").pop("empty")).await.unwrap();
+                assert_eq!(replies.into_iter().map(|reply| reply.unwrap()).collect::<Vec<Option<String>>>(),
+                           vec![None, None, Some("a".to_string()), None]);
+            });
+        }
+
+        it "commits_transactions" {
+            runtime.block_on(async {
+                pool.push("jobs", "a").await.unwrap();
+                let mut transaction = pool.transaction().await.unwrap();
+                assert_eq!(transaction.pop("jobs").await.unwrap(), Some("a".to_string()));
+                transaction.push("done", "a").await.unwrap();
+                transaction.commit().await.unwrap();
+                assert_eq!(pool.pop("done").await.unwrap(), Some("a".to_string()));
+                assert_eq!(pool.pop("jobs").await.unwrap(), None);
+            });
+        }
+
+        it "aborts_dropped_transactions" {
+            runtime.block_on(async {
+                pool.push("jobs", "a").await.unwrap();
+                let mut transaction = pool.transaction().await.unwrap();
+                transaction.pop("jobs").await.unwrap();
+                drop(transaction);
+                assert_eq!(pool.pop("jobs").await.unwrap(), Some("a".to_string()));
+            });
+        }
+
+        it "aborts_transactions_of_tasks_that_panic" {
+            runtime.block_on(async {
+                pool.push("jobs", "a").await.unwrap();
+                let task_pool = pool.clone();
+                let task = tokio::spawn(async move {
+                    let mut transaction = task_pool.transaction().await.unwrap();
+                    transaction.pop("jobs").await.unwrap();
+                    panic!("handler failed");
+                });
+                assert!(task.await.is_err());
+                assert_eq!(pool.pop("jobs").await.unwrap(), Some("a".to_string()));
+            });
+        }
+
+        it "replaces_connections_the_server_closed" {
+            runtime.block_on(async {
+                pool.push("jobs", "a").await.unwrap();
+            });
+            let mut admin = net::TcpStream::connect(&address).unwrap();
+            admin.write_all(b"CLIENT 'kill' '1';").unwrap();
+            let mut reply = [0; 9];
+            admin.read_exact(&mut reply).unwrap();
+            assert_eq!(&reply, b"SUCCESS\r\n");
+            thread::sleep(Duration::from_millis(50));
+            runtime.block_on(async {
+                assert_eq!(pool.pop("jobs").await.unwrap(), Some("a".to_string()));
+            });
+        }
+    }
+
+    describe! reconnect {
+        it "retries_connecting_with_backoff" {
+            let address = net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(200));
+                let listeners = vec![Listener::Tcp(TcpListener::bind(address).unwrap())];
+                EventLoop::new(listeners, &QueueTable::new(), &Users::new()).unwrap().run().unwrap();
+            });
+            let runtime = Runtime::new().unwrap();
+            let pool = Pool::new(&address.to_string(), PoolOptions::default());
+            runtime.block_on(async {
+                pool.push("jobs", "a").await.unwrap();
+            });
+        }
+    }
+}
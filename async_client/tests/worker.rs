@@ -0,0 +1,166 @@
+#![feature(plugin)]
+#![cfg_attr(test, plugin(stainless))]
+
+#[cfg(test)]
+mod tests {
+    pub use mio::net::TcpListener;
+    pub use queue_async_client::worker::HandlerError;
+    pub use queue_async_client::{Job, Pool, PoolOptions, RetryPolicy, Worker};
+    pub use queue_experiments::auth::Users;
+    pub use queue_experiments::event_loop::EventLoop;
+    pub use queue_experiments::net::Listener;
+    pub use queue_experiments::queue_table::QueueTable;
+    pub use std::net;
+    pub use std::sync::{Arc, Mutex};
+    pub use std::thread;
+    pub use std::time::Duration;
+    pub use tokio::runtime::Runtime;
+    pub use tokio::time;
+
+    describe! worker {
+        before_each {
+            let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.set_nonblocking(true).unwrap();
+            let address = listener.local_addr().unwrap().to_string();
+            let queue_table = QueueTable::new();
+            let server_queue_table = queue_table.clone();
+            thread::spawn(move || {
+                let listeners = vec![Listener::Tcp(TcpListener::from_std(listener))];
+                EventLoop::new(listeners, &server_queue_table, &Users::new()).unwrap().run().unwrap();
+            });
+            let runtime = Runtime::new().unwrap();
+            let pool = Pool::new(&address, PoolOptions::default());
+            let mut worker = Worker::new(pool.clone());
+            worker.poll_interval(Duration::from_millis(10));
+            let retry = RetryPolicy { min_backoff: Duration::from_millis(10), ..RetryPolicy::new("failed") };
+            // Jobs seen by handlers as (data, attempt)
+            let seen = Arc::new(Mutex::new(Vec::<(String, u32)>::new()));
+            let queue_len = move |name: &str| queue_table.get_queue(&name.to_string()).map_or(0, |queue| queue.len());
+        }
+
+        it "runs_jobs_from_each_queue_concurrently" {
+            let handler_seen = seen.clone();
+            let handler = move |job: Job| {
+                let seen = handler_seen.clone();
+                async move {
+                    time::sleep(Duration::from_millis(100)).await;
+                    seen.lock().unwrap().push((job.data, job.attempt));
+                    Ok::<(), HandlerError>(())
+                }
+            };
+            worker.concurrency(4).handle("emails", retry.clone(), handler.clone()).handle("reports", retry.clone(), handler);
+            let started = std::time::Instant::now();
+            runtime.block_on(async {
+                for data in ["a", "b", "c"] {
+                    pool.push("emails", data).await.unwrap();
+                }
+                pool.push("reports", "d").await.unwrap();
+                worker.run(async {
+                    while seen.lock().unwrap().len() < 4 {
+                        time::sleep(Duration::from_millis(5)).await;
+                    }
+                }).await;
+            });
+            assert!(started.elapsed() < Duration::from_millis(300));
+            let mut seen = seen.lock().unwrap().clone();
+            seen.sort();
+            assert_eq!(seen, vec![("a".to_string(), 1), ("b".to_string(), 1), ("c".to_string(), 1), ("d".to_string(), 1)]);
+            assert_eq!((queue_len("emails"), queue_len("reports")), (0, 0));
+        }
+
+        it "retries_failed_jobs_after_a_backoff" {
+            let handler_seen = seen.clone();
+            worker.handle("jobs", retry, move |job| {
+                let seen = handler_seen.clone();
+                async move {
+                    seen.lock().unwrap().push((job.data, job.attempt));
+                    if job.attempt < 3 {
+                        return Err::<(), HandlerError>("not yet".into());
+                    }
+                    Ok(())
+                }
+            });
+            runtime.block_on(async {
+                pool.push("jobs", "a").await.unwrap();
+                worker.run(async {
+                    // Held by the server between attempts, so no worker can take it early
+                    while seen.lock().unwrap().len() < 3 {
+                        if !seen.lock().unwrap().is_empty() {
+                            assert_eq!(queue_len("jobs"), 0);
+                        }
+                        time::sleep(Duration::from_millis(5)).await;
+                    }
+                }).await;
+            });
+            assert_eq!(seen.lock().unwrap().clone(), vec![("a".to_string(), 1), ("a".to_string(), 2), ("a".to_string(), 3)]);
+            assert_eq!(queue_len("jobs"), 0);
+        }
+
+        it "counts_rollbacks_by_other_clients_as_attempts" {
+            let handler_seen = seen.clone();
+            worker.handle("jobs", retry, move |job| {
+                let seen = handler_seen.clone();
+                async move {
+                    seen.lock().unwrap().push((job.data, job.attempt));
+                    Ok::<(), HandlerError>(())
+                }
+            });
+            runtime.block_on(async {
+                pool.push("jobs", "a").await.unwrap();
+                let mut transaction = pool.transaction().await.unwrap();
+                assert_eq!(transaction.pop("jobs").await.unwrap(), Some("a".to_string()));
+                transaction.abort().await.unwrap();
+                worker.run(async {
+                    while seen.lock().unwrap().is_empty() {
+                        time::sleep(Duration::from_millis(5)).await;
+                    }
+                }).await;
+            });
+            assert_eq!(seen.lock().unwrap().clone(), vec![("a".to_string(), 2)]);
+        }
+
+        it "dead_letters_jobs_that_keep_panicking" {
+            let policy = RetryPolicy { max_attempts: 2, ..retry };
+            worker.handle("jobs", policy, |_job| async move {
+                if true {
+                    panic!("handler bug");
+                }
+                Ok::<(), HandlerError>(())
+            });
+            runtime.block_on(async {
+                pool.push("jobs", "a").await.unwrap();
+                worker.run(async {
+                    while queue_len("failed") == 0 {
+                        time::sleep(Duration::from_millis(5)).await;
+                    }
+                }).await;
+                assert_eq!(pool.pop("failed").await.unwrap(), Some("a".to_string()));
+            });
+            assert_eq!(queue_len("jobs"), 0);
+        }
+
+        it "finishes_jobs_in_flight_on_shutdown" {
+            let handler_seen = seen.clone();
+            worker.handle("jobs", retry, move |job| {
+                let seen = handler_seen.clone();
+                async move {
+                    seen.lock().unwrap().push((job.data, job.attempt));
+                    time::sleep(Duration::from_millis(100)).await;
+                    Ok::<(), HandlerError>(())
+                }
+            });
+            runtime.block_on(async {
+                pool.push("jobs", "a").await.unwrap();
+                pool.push("jobs", "b").await.unwrap();
+                worker.run(async {
+                    while seen.lock().unwrap().is_empty() {
+                        time::sleep(Duration::from_millis(5)).await;
+                    }
+                }).await;
+                // Committed, so the next pop gets the job that wasn't started
+                assert_eq!(pool.pop("jobs").await.unwrap(), Some("b".to_string()));
+            });
+            assert_eq!(seen.lock().unwrap().clone(), vec![("a".to_string(), 1)]);
+        }
+    }
+}
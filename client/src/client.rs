@@ -0,0 +1,142 @@
+use std::io::{BufRead,BufReader,Write};
+use std::io;
+use std::net::{TcpStream,ToSocketAddrs};
+use std::time::{Duration};
+
+use queue_experiments::commands::{Command};
+
+use crate::error::{Error};
+use crate::reply;
+use crate::reply::{Fenced};
+
+// A blocking connection to the server, one request at a time
+pub struct Client {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream
+}
+
+impl Client {
+    pub fn connect<A: ToSocketAddrs>(address: A) -> Result<Client, Error> {
+        let stream = TcpStream::connect(address)?;
+        stream.set_nodelay(true)?;
+        Ok(Client {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream
+        })
+    }
+
+    pub fn auth(&mut self, user: &str, password: &str) -> Result<(), Error> {
+        let line = self.request(&Command::Auth(user.to_string(), password.to_string()))?;
+        reply::success(line)
+    }
+
+    pub fn push(&mut self, queue_name: &str, data: &str) -> Result<(), Error> {
+        self.push_item(queue_name, data, None, None)
+    }
+
+    // Items of a message group are popped one at a time, an item is dropped once ttl passes
+    pub fn push_item(&mut self, queue_name: &str, data: &str, group: Option<&str>, ttl: Option<Duration>) -> Result<(), Error> {
+        let line = self.request(&push_command(queue_name, data, group, ttl))?;
+        reply::success(line)
+    }
+
+    // None when the queue is empty or doesn't exist
+    pub fn pop(&mut self, queue_name: &str) -> Result<Option<String>, Error> {
+        let line = self.request(&Command::Pop(queue_name.to_string()))?;
+        reply::item(line)
+    }
+
+    // Waits for an item
+    pub fn bpop(&mut self, queue_name: &str) -> Result<String, Error> {
+        let line = self.request(&Command::BlockingPop(queue_name.to_string(), None, false))?;
+        reply::blocking_item(line)
+    }
+
+    // Runs f inside BEGIN and COMMIT. If f fails the transaction is aborted,
+    // putting popped items back, and f's error is returned.
+    pub fn transaction<T, F>(&mut self, f: F) -> Result<T, Error>
+        where F: FnOnce(&mut Transaction) -> Result<T, Error>
+    {
+        let lines = self.request_fenced(&Command::Begin)?;
+        reply::silent(lines)?;
+        let result = f(&mut Transaction { client: self });
+        match result {
+            Ok(value) => {
+                let lines = self.request_fenced(&Command::Commit)?;
+                reply::committed(lines).map(|_| value)
+            }
+            Err(error) => {
+                let _ = self.request_fenced(&Command::Abort(None));
+                Err(error)
+            }
+        }
+    }
+
+    // For commands answered with a single line
+    fn request(&mut self, command: &Command) -> Result<String, Error> {
+        let message = reply::encode(command)?;
+        self.writer.write_all(message.as_bytes())?;
+        self.read_line()
+    }
+
+    fn request_fenced(&mut self, command: &Command) -> Result<Vec<String>, Error> {
+        let message = reply::encode_fenced(command)?;
+        self.writer.write_all(message.as_bytes())?;
+        let mut lines = Vec::new();
+        loop {
+            let line = self.read_line()?;
+            match reply::fenced(&line, lines.is_empty()) {
+                Fenced::Line => {
+                    lines.push(line);
+                }
+                Fenced::Done => {
+                    return Ok(lines);
+                }
+                Fenced::SkipOne => {
+                    self.read_line()?;
+                    return Ok(vec![line]);
+                }
+            }
+        }
+    }
+
+    // Data may contain a bare \n, only \r\n ends a reply
+    fn read_line(&mut self) -> Result<String, Error> {
+        let mut line = Vec::new();
+        while !line.ends_with(b"\r\n") {
+            if self.reader.read_until(b'\n', &mut line)? == 0 {
+                return Err(Error::Io(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed")));
+            }
+        }
+        line.truncate(line.len() - 2);
+        String::from_utf8(line).map_err(|_| Error::Server("Non utf8 characters in reply".to_string()))
+    }
+}
+
+// Pushes are held until COMMIT, popped items until COMMIT or ABORT
+pub struct Transaction<'a> {
+    client: &'a mut Client
+}
+
+impl<'a> Transaction<'a> {
+    pub fn push(&mut self, queue_name: &str, data: &str) -> Result<(), Error> {
+        self.push_item(queue_name, data, None, None)
+    }
+
+    pub fn push_item(&mut self, queue_name: &str, data: &str, group: Option<&str>, ttl: Option<Duration>) -> Result<(), Error> {
+        let lines = self.client.request_fenced(&push_command(queue_name, data, group, ttl))?;
+        reply::silent(lines)
+    }
+
+    pub fn pop(&mut self, queue_name: &str) -> Result<Option<String>, Error> {
+        self.client.pop(queue_name)
+    }
+
+    pub fn bpop(&mut self, queue_name: &str) -> Result<String, Error> {
+        self.client.bpop(queue_name)
+    }
+}
+
+fn push_command(queue_name: &str, data: &str, group: Option<&str>, ttl: Option<Duration>) -> Command {
+    Command::Push(data.to_string(), queue_name.to_string(), group.map(|group| group.to_string()), ttl)
+}
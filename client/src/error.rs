@@ -0,0 +1,36 @@
+use std::error;
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    // A reply other than the one expected, e.g. QUEUE FULL, NOT ALLOWED or MOVED slot address
+    Server(String),
+    // Why COMMIT rolled the transaction back, e.g. `QUEUE FULL: jobs` for a push that didn't fit
+    Rejected(Vec<String>),
+    // The server ends messages at the first ; and replies at the first \r\n,
+    // so arguments can't contain them. Pushed data also can't be the same as a reply
+    // other than an item, e.g. NO DATA or QUEUE FULL. Holds the command name.
+    InvalidArgument(&'static str)
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref error) => write!(f, "{}", error),
+            Error::Server(ref reply) => write!(f, "{}", reply),
+            Error::Rejected(ref lines) => write!(f, "commit rejected: {}", lines.join(", ")),
+            Error::InvalidArgument(name) => write!(f, "{} arguments can't contain ; or \\r\\n, nor push data that reads as a reply", name)
+        }
+    }
+}
+
+impl error::Error for Error {
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Error {
+        Error::Io(error)
+    }
+}
@@ -0,0 +1,6 @@
+pub mod client;
+pub mod error;
+pub mod reply;
+
+pub use client::{Client,Transaction};
+pub use error::{Error};
@@ -0,0 +1,135 @@
+use queue_experiments::commands::{Command};
+
+use crate::error::{Error};
+
+// MEMORY is answered even inside a transaction, with a line no other command sends
+const FENCE_REPLY: &str = "used_memory:";
+
+// Replies every command gets instead of its own while the client isn't authenticated
+// or its transaction was aborted, see Connection::dispatch
+const ALWAYS_SENT: [&str; 2] = ["NOT AUTHENTICATED", "TRANSACTION ABORTED"];
+
+const ERRORS: [&str; 11] = [
+    "NOT ALLOWED", "NOT AUTHENTICATED", "TRANSACTION ABORTED", "READONLY", "NO LEADER", "CROSSSLOT",
+    "SHUTTING DOWN", "OUT OF MEMORY", "QUEUE FULL", "REPLICATION TIMEOUT", "NOT COMMITTED"
+];
+
+// Replies to POP that aren't an item
+const NO_ITEM: [&str; 2] = ["NO DATA", "NO SUCH QUEUE"];
+
+// The message for command, or InvalidArgument if the server would split it or
+// if it pushes data that would be read back as a reply instead of an item
+pub fn encode(command: &Command) -> Result<String, Error> {
+    let message = command.encode();
+    if message[..message.len() - 1].contains(';') || message.contains("\r\n") {
+        return Err(Error::InvalidArgument(command.name()));
+    }
+    if let Command::Push(ref data, _, _, _) = *command {
+        if error(data).is_some() || NO_ITEM.contains(&&data[..]) || data.starts_with(FENCE_REPLY) {
+            return Err(Error::InvalidArgument(command.name()));
+        }
+    }
+    Ok(message)
+}
+
+// BEGIN, COMMIT, ABORT and pushes inside a transaction aren't answered when they succeed,
+// so they are sent followed by MEMORY and the lines before its reply are theirs
+pub fn encode_fenced(command: &Command) -> Result<String, Error> {
+    encode(command).map(|message| message + &Command::Memory.encode())
+}
+
+// Where the reply to a fenced command ends
+#[derive(PartialEq)]
+#[derive(Debug)]
+pub enum Fenced {
+    // More lines to come
+    Line,
+    // line was the fence, the lines before it are the reply
+    Done,
+    // line was the whole reply, the fence is answered with one more line to be skipped
+    SkipOne
+}
+
+// line is the first line after the command when first is true
+pub fn fenced(line: &str, first: bool) -> Fenced {
+    if line.starts_with(FENCE_REPLY) {
+        Fenced::Done
+    } else if first && ALWAYS_SENT.contains(&line) {
+        Fenced::SkipOne
+    } else {
+        Fenced::Line
+    }
+}
+
+// The only replies that aren't data. encode refuses to push data that looks like one,
+// so only items pushed by other clients can be mistaken for an error.
+pub fn error(line: &str) -> Option<Error> {
+    if ERRORS.contains(&line) || line.starts_with("REDIRECT ") || line.starts_with("MOVED ") {
+        Some(Error::Server(line.to_string()))
+    } else {
+        None
+    }
+}
+
+pub fn success(line: String) -> Result<(), Error> {
+    if line == "SUCCESS" {
+        Ok(())
+    } else {
+        Err(Error::Server(line))
+    }
+}
+
+// None when the queue is empty or doesn't exist
+pub fn item(line: String) -> Result<Option<String>, Error> {
+    if NO_ITEM.contains(&&line[..]) {
+        return Ok(None);
+    }
+    match error(&line) {
+        Some(error) => Err(error),
+        None => Ok(Some(line))
+    }
+}
+
+// BPOP only answers once there is an item
+pub fn blocking_item(line: String) -> Result<String, Error> {
+    match error(&line) {
+        Some(error) => Err(error),
+        None => Ok(line)
+    }
+}
+
+// For BPOP with attempts, an item comes after how many times it has been tried.
+// None when the timeout passed.
+pub fn attempted_item(line: String) -> Result<Option<(u32, String)>, Error> {
+    let line = match item(line)? {
+        Some(line) => line,
+        None => {
+            return Ok(None);
+        }
+    };
+    let attempt = line.split_once(' ').and_then(|(attempt, data)| attempt.parse().ok().map(|attempt| (attempt, data.to_string())));
+    match attempt {
+        Some(attempt) => Ok(Some(attempt)),
+        None => Err(Error::Server(line))
+    }
+}
+
+// The lines a fenced command was answered with, none is success
+pub fn silent(lines: Vec<String>) -> Result<(), Error> {
+    match lines.into_iter().next() {
+        Some(line) => Err(Error::Server(line)),
+        None => Ok(())
+    }
+}
+
+// A commit refused as a whole is answered with its reason, e.g. `QUEUE FULL: name`. Any other
+// error means nothing was committed or, for REPLICATION TIMEOUT, that it may not have been
+pub fn committed(lines: Vec<String>) -> Result<(), Error> {
+    if lines.is_empty() {
+        Ok(())
+    } else if lines.len() == 1 && error(&lines[0]).is_some() {
+        Err(Error::Server(lines[0].clone()))
+    } else {
+        Err(Error::Rejected(lines))
+    }
+}
@@ -0,0 +1,106 @@
+#![feature(plugin)]
+#![cfg_attr(test, plugin(stainless))]
+
+#[cfg(test)]
+mod tests {
+    extern crate mio;
+    extern crate queue_client;
+    extern crate queue_experiments;
+    pub use self::mio::net::{TcpListener};
+    pub use self::queue_client::{Client,Error};
+    pub use self::queue_experiments::auth::{Users};
+    pub use self::queue_experiments::event_loop::{EventLoop};
+    pub use self::queue_experiments::net::{Listener};
+    pub use self::queue_experiments::queue_table::{QueueTable,QueueLimits,OverflowPolicy};
+    pub use std::net;
+    pub use std::thread;
+    pub use std::time::{Duration};
+
+    describe! client {
+        before_each {
+            let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.set_nonblocking(true).unwrap();
+            let address = listener.local_addr().unwrap();
+            let queue_table = QueueTable::new();
+            let server_queue_table = queue_table.clone();
+            thread::spawn(move|| {
+                let listeners = vec![Listener::Tcp(TcpListener::from_std(listener))];
+                EventLoop::new(listeners, &server_queue_table, &Users::new()).unwrap().run().unwrap();
+            });
+            let mut client = Client::connect(address).unwrap();
+        }
+
+        it "pushes_and_pops_items" {
+            client.push("jobs", "it's a \\ job").unwrap();
+            assert_eq!(client.pop("jobs").unwrap(), Some("it's a \\ job".to_string()));
+            assert_eq!(client.pop("jobs").unwrap(), None);
+            assert_eq!(client.pop("missing").unwrap(), None);
+        }
+
+        it "refuses_arguments_the_server_would_split" {
+            match client.push("jobs", "a;b") {
+                Err(Error::InvalidArgument(name)) => assert_eq!(name, "PUSH"),
+                result => panic!("unexpected {:?}", result)
+            }
+            client.push("jobs", "still connected").unwrap();
+        }
+
+        it "refuses_to_push_data_that_reads_as_a_reply" {
+            for data in ["QUEUE FULL", "NO DATA", "MOVED 12 127.0.0.1:1", "used_memory:0"] {
+                match client.push("jobs", data) {
+                    Err(Error::InvalidArgument(name)) => assert_eq!(name, "PUSH"),
+                    result => panic!("unexpected {:?}", result)
+                }
+            }
+            client.push("jobs", "QUEUE FULL?").unwrap();
+            assert_eq!(client.pop("jobs").unwrap(), Some("QUEUE FULL?".to_string()));
+        }
+
+        it "bpop_waits_for_an_item" {
+            thread::spawn(move|| {
+                thread::sleep(Duration::from_millis(50));
+                Client::connect(address).unwrap().push("jobs", "late").unwrap();
+            });
+            assert_eq!(client.bpop("jobs").unwrap(), "late".to_string());
+        }
+
+        it "commits_transactions" {
+            client.push("jobs", "a").unwrap();
+            let popped = client.transaction(|tx| {
+                let item = try!(tx.pop("jobs"));
+                try!(tx.push("done", "a"));
+                Ok(item)
+            });
+            assert_eq!(popped.unwrap(), Some("a".to_string()));
+            assert_eq!(client.pop("done").unwrap(), Some("a".to_string()));
+            assert_eq!(client.pop("jobs").unwrap(), None);
+        }
+
+        it "aborts_transactions_that_fail" {
+            client.push("jobs", "a").unwrap();
+            let result: Result<(), Error> = client.transaction(|tx| {
+                try!(tx.pop("jobs"));
+                try!(tx.push("done", "a"));
+                Err(Error::Server("handler failed".to_string()))
+            });
+            assert_eq!(result.unwrap_err().to_string(), "handler failed".to_string());
+            assert_eq!(client.pop("done").unwrap(), None);
+            assert_eq!(client.pop("jobs").unwrap(), Some("a".to_string()));
+        }
+
+        it "rolls_back_commits_with_pushes_that_dont_fit" {
+            queue_table.get_or_create_queue("small".to_string()).set_limits(QueueLimits {
+                max_len: Some(1), max_bytes: None, overflow: OverflowPolicy::Reject
+            });
+            let result = client.transaction(|tx| {
+                try!(tx.push("small", "a"));
+                tx.push("small", "b")
+            });
+            match result {
+                Err(Error::Rejected(lines)) => assert_eq!(lines, vec!["QUEUE FULL: small".to_string()]),
+                result => panic!("unexpected {:?}", result)
+            }
+            assert_eq!(queue_table.get_queue(&"small".to_string()).map(|queue| queue.len()), Some(0));
+        }
+    }
+}
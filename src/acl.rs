@@ -0,0 +1,87 @@
+use auth::{UserName};
+
+#[derive(PartialEq)]
+#[derive(Debug)]
+#[derive(Clone,Copy)]
+pub enum Permission {
+    Push,
+    Pop,
+    Admin,
+    // Server-wide commands such as MAXMEMORY, CLIENT KILL or SYNC, kept apart from
+    // admin so admin on every queue doesn't also grant them
+    Server
+}
+
+// Grants a user permissions on every queue or stream whose name matches pattern.
+// Patterns may use * to match any run of characters, e.g. billing.*
+#[derive(PartialEq)]
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct AclRule {
+    pub user: UserName,
+    pub permissions: Vec<Permission>,
+    pub pattern: String
+}
+
+// Server-wide commands are checked against this name, so only a rule with the pattern * grants them
+pub const SERVER: &str = "*";
+
+impl AclRule {
+    // user permission[,permission...] pattern
+    pub fn parse(value: &str) -> Result<AclRule, String> {
+        let parts: Vec<&str> = value.split_whitespace().collect();
+        if parts.len() != 3 {
+            return Err("acl requires a user, permissions and a queue pattern".to_string());
+        }
+        let mut permissions = Vec::new();
+        for permission in parts[1].split(',') {
+            match &permission.to_lowercase() as &str {
+                "push"  => permissions.push(Permission::Push),
+                "pop"   => permissions.push(Permission::Pop),
+                "admin" => permissions.push(Permission::Admin),
+                "server" => {
+                    if parts[2] != SERVER {
+                        return Err("the server permission needs the pattern *".to_string());
+                    }
+                    permissions.push(Permission::Server);
+                }
+                "all"   => permissions.extend_from_slice(&[Permission::Push, Permission::Pop, Permission::Admin, Permission::Server]),
+                _       => return Err(format!("unknown permission {}", permission))
+            }
+        }
+        Ok(AclRule {
+            user: parts[0].to_string(),
+            permissions,
+            pattern: parts[2].to_string()
+        })
+    }
+
+    pub fn allows(&self, user: &str, permission: Permission, name: &str) -> bool {
+        self.user == user && self.permissions.contains(&permission) && matches(self.pattern.as_bytes(), name.as_bytes())
+    }
+}
+
+// On a mismatch only the last * needs to take one more character, an earlier * can't
+// do better by taking more, so this is linear in the length of pattern times name
+fn matches(pattern: &[u8], name: &[u8]) -> bool {
+    let mut p = 0;
+    let mut n = 0;
+    // Where to resume after the last *: the pattern after it and how much of name it took
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        if p < pattern.len() && pattern[p] == b'*' {
+            p += 1;
+            star = Some((p, n));
+        } else if p < pattern.len() && pattern[p] == name[n] {
+            p += 1;
+            n += 1;
+        } else if let Some((after_star, taken)) = star {
+            p = after_star;
+            n = taken + 1;
+            star = Some((after_star, taken + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
@@ -0,0 +1,198 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::num::NonZeroU32;
+use std::sync::{Arc,Mutex};
+use std::sync::mpsc;
+use std::sync::mpsc::{Receiver,Sender};
+use std::thread;
+
+use acl::{AclRule,Permission};
+use ring::pbkdf2;
+use ring::rand::{SecureRandom,SystemRandom};
+
+pub type UserName = String;
+
+const SCHEME: &str = "pbkdf2-sha256";
+const ITERATIONS: u32 = 100000;
+const SALT_LEN: usize = 16;
+const HASH_LEN: usize = 32;
+// Password checks are slow on purpose, so they run on this many threads instead of the event loop
+const VERIFIER_THREADS: usize = 2;
+
+// Stored as pbkdf2-sha256$iterations$salt$hash with the salt and hash hex encoded
+#[derive(PartialEq)]
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct PasswordHash {
+    iterations: NonZeroU32,
+    salt: Vec<u8>,
+    hash: Vec<u8>
+}
+
+// Shared by every connection, no users means authentication is off
+// and no acl rules means every authenticated user may do anything
+#[derive(PartialEq)]
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct Users {
+    users: Arc<HashMap<UserName, PasswordHash>>,
+    rules: Arc<Vec<AclRule>>
+}
+
+// A check waiting for a verifier thread, the answer is sent on result
+struct Check {
+    users: Users,
+    name: UserName,
+    password: String,
+    result: Sender<bool>
+}
+
+// Checks passwords on a few threads, calling wake after each so the waiting client is retried
+#[derive(Clone)]
+pub struct Verifier {
+    checks: Sender<Check>
+}
+
+impl PasswordHash {
+    pub fn new(password: &str) -> PasswordHash {
+        let mut salt = vec![0; SALT_LEN];
+        SystemRandom::new().fill(&mut salt).expect("no random numbers available for salt");
+        let iterations = NonZeroU32::new(ITERATIONS).unwrap();
+        let mut hash = vec![0; HASH_LEN];
+        pbkdf2::derive(pbkdf2::PBKDF2_HMAC_SHA256, iterations, &salt, password.as_bytes(), &mut hash);
+        PasswordHash {
+            iterations,
+            salt,
+            hash
+        }
+    }
+
+    pub fn parse(value: &str) -> Result<PasswordHash, String> {
+        let parts: Vec<&str> = value.split('$').collect();
+        if parts.len() != 4 || parts[0] != SCHEME {
+            return Err(format!("invalid password hash, expected {}$iterations$salt$hash", SCHEME));
+        }
+        let iterations = parts[1].parse::<u32>().ok().and_then(NonZeroU32::new);
+        match (iterations, from_hex(parts[2]), from_hex(parts[3])) {
+            (Some(iterations), Some(salt), Some(ref hash)) if !hash.is_empty() => {
+                Ok(PasswordHash {
+                    iterations,
+                    salt,
+                    hash: hash.clone()
+                })
+            }
+            _ => {
+                Err("invalid password hash".to_string())
+            }
+        }
+    }
+
+    // Constant time so the hash can't be guessed from response times
+    pub fn verify(&self, password: &str) -> bool {
+        pbkdf2::verify(pbkdf2::PBKDF2_HMAC_SHA256, self.iterations, &self.salt, password.as_bytes(), &self.hash).is_ok()
+    }
+}
+
+impl fmt::Display for PasswordHash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}${}${}${}", SCHEME, self.iterations, to_hex(&self.salt), to_hex(&self.hash))
+    }
+}
+
+impl Default for Users {
+    fn default() -> Users {
+        Users::new()
+    }
+}
+
+impl Users {
+    pub fn new() -> Users {
+        Users {
+            users: Arc::new(HashMap::new()),
+            rules: Arc::new(Vec::new())
+        }
+    }
+
+    pub fn add(&mut self, name: UserName, hash: PasswordHash) {
+        Arc::make_mut(&mut self.users).insert(name, hash);
+    }
+
+    pub fn add_rule(&mut self, rule: AclRule) {
+        Arc::make_mut(&mut self.rules).push(rule);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.users.is_empty()
+    }
+
+    // Unknown users are checked against a dummy hash so response times don't reveal which users exist
+    pub fn authenticate(&self, name: &str, password: &str) -> bool {
+        match self.users.get(name) {
+            Some(hash) => hash.verify(password),
+            None => {
+                let dummy = PasswordHash {
+                    iterations: NonZeroU32::new(ITERATIONS).unwrap(),
+                    salt: vec![0; SALT_LEN],
+                    hash: vec![0; HASH_LEN]
+                };
+                dummy.verify(password);
+                false
+            }
+        }
+    }
+
+    // Once any rule is configured users only get what their rules grant
+    pub fn allows(&self, user: &str, permission: Permission, name: &str) -> bool {
+        self.rules.is_empty() || self.rules.iter().any(|rule| rule.allows(user, permission, name))
+    }
+}
+
+impl Verifier {
+    pub fn new<F: Fn() + Send + Sync + 'static>(wake: F) -> Verifier {
+        let (checks, receiver) = mpsc::channel::<Check>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let wake = Arc::new(wake);
+        for _ in 0..VERIFIER_THREADS {
+            let receiver = receiver.clone();
+            let wake = wake.clone();
+            thread::spawn(move|| {
+                loop {
+                    let check = match receiver.lock().unwrap().recv() {
+                        Ok(check) => check,
+                        Err(_) => {
+                            return;
+                        }
+                    };
+                    let _ = check.result.send(check.users.authenticate(&check.name, &check.password));
+                    wake();
+                }
+            });
+        }
+        Verifier { checks }
+    }
+
+    // The answer arrives on the returned channel once a thread got to it
+    pub fn verify(&self, users: &Users, name: &str, password: &str) -> Receiver<bool> {
+        let (result, receiver) = mpsc::channel();
+        let check = Check {
+            users: users.clone(),
+            name: name.to_string(),
+            password: password.to_string(),
+            result
+        };
+        // Every thread only stops once all senders are gone, so this can't fail
+        let _ = self.checks.send(check);
+        receiver
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(value: &str) -> Option<Vec<u8>> {
+    if !value.len().is_multiple_of(2) || !value.is_ascii() {
+        return None;
+    }
+    (0..value.len()).step_by(2).map(|i| u8::from_str_radix(&value[i..i + 2], 16).ok()).collect()
+}
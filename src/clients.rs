@@ -0,0 +1,144 @@
+use std::collections::HashMap;
+use std::sync::{Arc,Mutex};
+use std::sync::atomic::{AtomicBool,Ordering};
+use std::time::{Duration,Instant};
+
+use queue_table::{QueueName};
+use stats::{ClientId};
+
+// What another client asked the event loop to do to a connection
+#[derive(PartialEq)]
+#[derive(Debug)]
+pub enum ClientAction {
+    Kill,
+    AbortTransaction
+}
+
+#[derive(Clone)]
+pub struct TransactionInfo {
+    pub began: Instant,
+    // Seconds since the unix epoch
+    pub began_at: u64,
+    // Queue and stream pushes buffered until commit
+    pub pushes: usize,
+    // Items held per queue, sorted by queue name
+    pub pops: Vec<(QueueName, usize)>
+}
+
+#[derive(Clone)]
+pub struct ClientInfo {
+    pub id: ClientId,
+    pub peer: String,
+    pub name: Option<String>,
+    // The last command the client sent, it may still be blocked on it
+    pub command: Option<&'static str>,
+    // Commands buffered by an open transaction
+    pub transaction_size: usize,
+    pub transaction: Option<TransactionInfo>,
+    pub last_active: Instant
+}
+
+// Each connection updates its own entry so commands never wait on other clients
+pub type ClientEntry = Arc<Mutex<ClientInfo>>;
+
+struct ClientsInner {
+    entries: Mutex<HashMap<ClientId, ClientEntry>>,
+    actions: Mutex<Vec<(ClientId, ClientAction)>>,
+    // Lets the event loop skip the lock when nothing was asked for
+    actions_pending: AtomicBool
+}
+
+// Every connected client, for CLIENT LIST, CLIENT KILL, TXLIST and TXABORT
+#[derive(Clone)]
+pub struct Clients {
+    inner: Arc<ClientsInner>
+}
+
+impl ClientInfo {
+    pub fn idle(&self) -> Duration {
+        self.last_active.elapsed()
+    }
+}
+
+impl Default for Clients {
+    fn default() -> Clients {
+        Clients::new()
+    }
+}
+
+impl Clients {
+    pub fn new() -> Clients {
+        Clients {
+            inner: Arc::new(ClientsInner {
+                entries: Mutex::new(HashMap::new()),
+                actions: Mutex::new(Vec::new()),
+                actions_pending: AtomicBool::new(false)
+            })
+        }
+    }
+
+    pub fn register(&self, id: ClientId) -> ClientEntry {
+        let entry = Arc::new(Mutex::new(ClientInfo {
+            id,
+            peer: "unknown".to_string(),
+            name: None,
+            command: None,
+            transaction_size: 0,
+            transaction: None,
+            last_active: Instant::now()
+        }));
+        let mut entries = self.inner.entries.lock().unwrap();
+        entries.insert(id, entry.clone());
+        entry
+    }
+
+    pub fn unregister(&self, id: ClientId) {
+        let mut entries = self.inner.entries.lock().unwrap();
+        entries.remove(&id);
+    }
+
+    // Sorted by id, i.e. by when they connected
+    pub fn list(&self) -> Vec<ClientInfo> {
+        let entries: Vec<ClientEntry> = {
+            let entries = self.inner.entries.lock().unwrap();
+            entries.values().cloned().collect()
+        };
+        let mut clients: Vec<ClientInfo> = entries.iter().map(|entry| entry.lock().unwrap().clone()).collect();
+        clients.sort_by_key(|client| client.id);
+        clients
+    }
+
+    // Returns false if there is no such client.
+    // The event loop disconnects the client, rolling back its transaction.
+    pub fn kill(&self, id: ClientId) -> bool {
+        let exists = self.inner.entries.lock().unwrap().contains_key(&id);
+        if exists {
+            self.request(id, ClientAction::Kill);
+        }
+        exists
+    }
+
+    // Returns false if the client has no open transaction.
+    // The event loop rolls it back, the client is told when it next sends a command.
+    pub fn abort_transaction(&self, id: ClientId) -> bool {
+        let entry = self.inner.entries.lock().unwrap().get(&id).cloned();
+        let open = entry.is_some_and(|entry| entry.lock().unwrap().transaction.is_some());
+        if open {
+            self.request(id, ClientAction::AbortTransaction);
+        }
+        open
+    }
+
+    pub fn take_actions(&self) -> Vec<(ClientId, ClientAction)> {
+        if !self.inner.actions_pending.swap(false, Ordering::Relaxed) {
+            return Vec::new();
+        }
+        let mut actions = self.inner.actions.lock().unwrap();
+        actions.drain(..).collect()
+    }
+
+    fn request(&self, id: ClientId, action: ClientAction) {
+        self.inner.actions.lock().unwrap().push((id, action));
+        self.inner.actions_pending.store(true, Ordering::Relaxed);
+    }
+}
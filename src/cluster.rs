@@ -0,0 +1,367 @@
+use std::collections::HashMap;
+use std::io::{Read,Write};
+use std::mem;
+use std::net::{SocketAddr,TcpStream};
+use std::sync::{Arc,Mutex,Condvar};
+use std::sync::atomic::{AtomicBool,Ordering};
+use std::thread;
+use std::time::{Duration,Instant};
+
+use auth::{UserName};
+use parse_commands::{quote};
+use queue_table::{QueueTable};
+use raft::{RaftNode,Message,NodeId,Index,Term,Role,EntryState};
+use replication;
+use replication::{Operation};
+
+// Compact the log into a snapshot once it holds this many entries
+const COMPACT_AFTER: usize = 10000;
+const TICK_FREQ: u64 = 10;
+const CONNECT_TIMEOUT: u64 = 1;
+const AUTH_TIMEOUT: u64 = 5;
+const RETRY_DELAY: u64 = 200;
+// How long a write waits for a majority before the client gets REPLICATION TIMEOUT
+pub const COMMIT_TIMEOUT: u64 = 5000;
+
+struct ClusterState {
+    node: RaftNode,
+    addresses: HashMap<NodeId, SocketAddr>,
+    // The last entry reflected in the queue table. The leader applies entries as they are
+    // proposed, followers once they are committed.
+    applied: Index,
+    // Set when the queue table has to be rebuilt from the log before applying anything else
+    rebuild: bool
+}
+
+struct ClusterInner {
+    state: Mutex<Option<ClusterState>>,
+    enabled: AtomicBool,
+    // Set when the event loop has entries to apply or proposals to send
+    dirty: AtomicBool,
+    outboxes: Mutex<HashMap<NodeId, Vec<Message>>>,
+    outbox_ready: Condvar
+}
+
+// For INFO
+pub struct ClusterInfo {
+    pub node: NodeId,
+    pub role: Role,
+    pub term: Term,
+    pub leader: Option<SocketAddr>,
+    pub commit_index: Index,
+    pub applied: Index
+}
+
+// This node's part in a Raft cluster. Queue operations are proposed to the log by the leader
+// and applied by every node once committed, see raft.rs for the consensus itself.
+#[derive(Clone)]
+pub struct Cluster {
+    inner: Arc<ClusterInner>
+}
+
+impl Default for Cluster {
+    fn default() -> Cluster {
+        Cluster::new()
+    }
+}
+
+impl Cluster {
+    pub fn new() -> Cluster {
+        Cluster {
+            inner: Arc::new(ClusterInner {
+                state: Mutex::new(None),
+                enabled: AtomicBool::new(false),
+                dirty: AtomicBool::new(false),
+                outboxes: Mutex::new(HashMap::new()),
+                outbox_ready: Condvar::new()
+            })
+        }
+    }
+
+    // nodes includes this one
+    pub fn enable(&self, id: NodeId, nodes: Vec<(NodeId, SocketAddr)>, election_timeout: Duration) {
+        let peers = nodes.iter().map(|&(node, _)| node).filter(|&node| node != id).collect();
+        let mut state = self.inner.state.lock().unwrap();
+        *state = Some(ClusterState {
+            node: RaftNode::new(id, peers, election_timeout, Instant::now()),
+            addresses: nodes.into_iter().collect(),
+            applied: 0,
+            rebuild: false
+        });
+        self.inner.enabled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.inner.enabled.load(Ordering::Relaxed)
+    }
+
+    // The leader takes writes once it has applied the entries it inherited
+    pub fn accepts_writes(&self) -> bool {
+        if !self.is_enabled() {
+            return true;
+        }
+        let state = self.inner.state.lock().unwrap();
+        let state = state.as_ref().unwrap();
+        state.node.role() == Role::Leader && state.applied == state.node.last_index()
+    }
+
+    // What a client writing to this node is told instead
+    pub fn redirect(&self) -> String {
+        let state = self.inner.state.lock().unwrap();
+        let state = state.as_ref().unwrap();
+        match state.node.leader() {
+            Some(leader) if leader != state.node.id() => format!("REDIRECT {}", state.addresses[&leader]),
+            _ => "NO LEADER".to_string()
+        }
+    }
+
+    // Returns the index and term of the new entry, None unless this node accepts writes.
+    // The operation has already been applied to the leader's queue table.
+    pub fn propose(&self, operation: &Operation) -> Option<(Index, Term)> {
+        let mut state = self.inner.state.lock().unwrap();
+        let state = match state.as_mut() {
+            Some(state) => state,
+            None => {
+                return None;
+            }
+        };
+        if state.node.role() != Role::Leader || state.applied != state.node.last_index() {
+            return None;
+        }
+        let index = state.node.propose(operation.encode());
+        if let Some(index) = index {
+            state.applied = index;
+        }
+        self.inner.dirty.store(true, Ordering::Relaxed);
+        index.map(|index| (index, state.node.term()))
+    }
+
+    pub fn entry_state(&self, index: Index, term: Term) -> EntryState {
+        let state = self.inner.state.lock().unwrap();
+        state.as_ref().map_or(EntryState::Lost, |state| state.node.entry_state(index, term))
+    }
+
+    // A message from another node, received as a RAFT command
+    pub fn receive(&self, message: Message) {
+        let messages = {
+            let mut state = self.inner.state.lock().unwrap();
+            let state = match state.as_mut() {
+                Some(state) => state,
+                None => {
+                    return;
+                }
+            };
+            state.node.step(message, Instant::now());
+            state.node.take_messages()
+        };
+        self.send(messages);
+        self.inner.dirty.store(true, Ordering::Relaxed);
+    }
+
+    pub fn tick(&self) {
+        let (messages, changed) = {
+            let mut state = self.inner.state.lock().unwrap();
+            let state = match state.as_mut() {
+                Some(state) => state,
+                None => {
+                    return;
+                }
+            };
+            let role = state.node.role();
+            state.node.tick(Instant::now());
+            (state.node.take_messages(), role != state.node.role())
+        };
+        self.send(messages);
+        if changed {
+            self.inner.dirty.store(true, Ordering::Relaxed);
+        }
+    }
+
+    // Whether anything changed since the last call that the event loop must act on
+    pub fn take_dirty(&self) -> bool {
+        self.inner.dirty.swap(false, Ordering::Relaxed)
+    }
+
+    // True when the queue table is about to be rebuilt, so open transactions
+    // and blocked clients must be released first
+    pub fn take_reset(&self) -> bool {
+        let mut state = self.inner.state.lock().unwrap();
+        match state.as_mut() {
+            Some(state) => {
+                if state.node.take_reset() {
+                    state.rebuild = true;
+                }
+                state.rebuild
+            }
+            None => false
+        }
+    }
+
+    // Called by the event loop. Rebuilds the queue table if needed, applies committed entries,
+    // compacts the log and sends the leader's new entries.
+    pub fn apply(&self, queue_table: &QueueTable) {
+        let messages = {
+            let mut state = self.inner.state.lock().unwrap();
+            let state = match state.as_mut() {
+                Some(state) => state,
+                None => {
+                    return;
+                }
+            };
+            if state.rebuild {
+                warn!(node = state.node.id(), term = state.node.term(); "rebuilding queues from the cluster log");
+                queue_table.clear();
+                for data in state.node.base().iter() {
+                    apply_entry(queue_table, data);
+                }
+                state.applied = state.node.base_index();
+                state.rebuild = false;
+            }
+            let target = if state.node.role() == Role::Leader {
+                state.node.last_index()
+            } else {
+                state.node.commit_index()
+            };
+            while state.applied < target {
+                state.applied += 1;
+                apply_entry(queue_table, &state.node.entry(state.applied).unwrap().data);
+            }
+            let uncompacted = (state.node.last_index() - state.node.base_index()) as usize;
+            if uncompacted > COMPACT_AFTER && state.applied == state.node.commit_index() && state.applied == state.node.last_index() {
+                let base = replication::snapshot(queue_table).iter().map(|operation| operation.encode()).collect();
+                let index = state.applied;
+                state.node.compact(index, base);
+                debug!(node = state.node.id(), index = index; "compacted cluster log");
+            }
+            state.node.replicate();
+            state.node.take_messages()
+        };
+        self.send(messages);
+    }
+
+    pub fn info(&self) -> Option<ClusterInfo> {
+        let state = self.inner.state.lock().unwrap();
+        state.as_ref().map(|state| {
+            ClusterInfo {
+                node: state.node.id(),
+                role: state.node.role(),
+                term: state.node.term(),
+                leader: state.node.leader().map(|leader| state.addresses[&leader]),
+                commit_index: state.node.commit_index(),
+                applied: state.applied
+            }
+        })
+    }
+
+    fn peers(&self) -> Vec<(NodeId, SocketAddr)> {
+        let state = self.inner.state.lock().unwrap();
+        state.as_ref().map_or(Vec::new(), |state| {
+            state.addresses.iter().filter(|&(&node, _)| node != state.node.id()).map(|(&node, &address)| (node, address)).collect()
+        })
+    }
+
+    fn send(&self, messages: Vec<(NodeId, Message)>) {
+        if messages.is_empty() {
+            return;
+        }
+        let mut outboxes = self.inner.outboxes.lock().unwrap();
+        for (node, message) in messages {
+            outboxes.entry(node).or_default().push(message);
+        }
+        self.inner.outbox_ready.notify_all();
+    }
+
+    fn wait_for_messages(&self, node: NodeId) -> Vec<Message> {
+        let mut outboxes = self.inner.outboxes.lock().unwrap();
+        loop {
+            if let Some(messages) = outboxes.get_mut(&node) {
+                if !messages.is_empty() {
+                    return mem::take(messages);
+                }
+            }
+            outboxes = self.inner.outbox_ready.wait(outboxes).unwrap();
+        }
+    }
+}
+
+// Starts the threads that drive elections and heartbeats and carry messages to the other nodes
+pub fn run(cluster: Cluster, auth: Option<(UserName, String)>) {
+    for (node, address) in cluster.peers() {
+        let peer_cluster = cluster.clone();
+        let peer_auth = auth.clone();
+        thread::spawn(move|| send_to_peer(peer_cluster, node, address, peer_auth));
+    }
+    thread::spawn(move|| {
+        loop {
+            thread::sleep(Duration::from_millis(TICK_FREQ));
+            cluster.tick();
+        }
+    });
+}
+
+// Messages for a node that can't be reached are dropped, Raft sends them again
+fn send_to_peer(cluster: Cluster, node: NodeId, address: SocketAddr, auth: Option<(UserName, String)>) {
+    let mut stream: Option<TcpStream> = None;
+    loop {
+        let messages = cluster.wait_for_messages(node);
+        if stream.is_none() {
+            match connect(address, &auth) {
+                Ok(connected) => {
+                    info!(node = node, address:% = address; "connected to cluster node");
+                    stream = Some(connected);
+                }
+                Err(message) => {
+                    debug!(node = node, address:% = address, error:% = message; "could not reach cluster node");
+                    thread::sleep(Duration::from_millis(RETRY_DELAY));
+                    continue;
+                }
+            }
+        }
+        let data: String = messages.iter().map(|message| message.encode()).collect();
+        if let Err(error) = stream.as_mut().unwrap().write_all(data.as_bytes()) {
+            warn!(node = node, address:% = address, error:% = error; "lost connection to cluster node");
+            stream = None;
+        }
+    }
+}
+
+fn connect(address: SocketAddr, auth: &Option<(UserName, String)>) -> Result<TcpStream, String> {
+    let mut stream = TcpStream::connect_timeout(&address, Duration::from_secs(CONNECT_TIMEOUT)).map_err(|error| error.to_string())?;
+    stream.set_nodelay(true).map_err(|error| error.to_string())?;
+    if let Some((ref user, ref password)) = *auth {
+        stream.set_read_timeout(Some(Duration::from_secs(AUTH_TIMEOUT))).map_err(|error| error.to_string())?;
+        stream.write_all(format!("AUTH {} {};", quote(user), quote(password)).as_bytes()).map_err(|error| error.to_string())?;
+        let mut reply = Vec::new();
+        let mut buffer = [0; 64];
+        while !reply.ends_with(b"\r\n") {
+            match stream.read(&mut buffer) {
+                Ok(0) => {
+                    return Err("node closed the connection".to_string());
+                }
+                Ok(n) => {
+                    reply.extend_from_slice(&buffer[..n]);
+                }
+                Err(error) => {
+                    return Err(error.to_string());
+                }
+            }
+        }
+        if reply != b"SUCCESS\r\n" {
+            return Err(format!("node replied {}", String::from_utf8_lossy(&reply).trim()));
+        }
+    }
+    Ok(stream)
+}
+
+// Entries are encoded operations, or empty for the entry each leader starts its term with
+fn apply_entry(queue_table: &QueueTable, data: &str) {
+    if data.is_empty() {
+        return;
+    }
+    let result = Operation::parse(data.trim_end_matches(';').as_bytes().to_vec()).and_then(|operation| {
+        replication::apply(queue_table, operation)
+    });
+    if let Err(message) = result {
+        warn!(error:% = message; "could not apply cluster log entry");
+    }
+}
@@ -1,26 +1,232 @@
-use queue_table::{QueueName};
-use parse_commands::{parse_command, ParseResult};
+use queue_table::{QueueName,MessageGroup,Item,QueueLimits,OverflowPolicy};
+use stream::{GroupName,Offset,Retention};
+use std::time::{Duration};
+use acl::{Permission,SERVER};
+use auth::{UserName};
+use stats::{ClientId};
+use replication::{WriteConcern,Sequence};
+use raft;
+use parse_commands::{parse_command, quote, ParseResult};
 
 #[derive(PartialEq)]
 #[derive(Debug)]
 pub enum Command {
     Quit,
-    Push(String, QueueName),
+    Auth(UserName, String),
+    Push(String, QueueName, Option<MessageGroup>, Option<Duration>),
     Pop(QueueName),
-    BlockingPop(QueueName),
+    // Gives up after the timeout if set, and with attempts set the reply has the item's attempt first
+    BlockingPop(QueueName, Option<Duration>, bool),
     Begin,
     Commit,
-    Abort
+    // Items popped by the transaction are only handed out again after the delay
+    Abort(Option<Duration>),
+    StreamPush(String, QueueName),
+    StreamRead(QueueName, GroupName, usize),
+    StreamCommit(QueueName, GroupName, Offset),
+    StreamRetain(QueueName, Retention),
+    Limit(QueueName, QueueLimits),
+    Memory,
+    Info,
+    SlowLog(Option<usize>),
+    SlowLogReset,
+    Monitor,
+    ClientList,
+    ClientKill(ClientId),
+    ClientSetName(String),
+    TransactionList,
+    TransactionAbort(ClientId),
+    Sync,
+    Ack(Sequence),
+    Promote,
+    Raft(raft::Message),
+    ClusterSlots,
+    ClusterKeySlot(String),
+    WriteConcern(WriteConcern),
+    QueueWriteConcern(QueueName, WriteConcern),
+    MaxMemory(usize),
+    DeadLetter(QueueName, QueueName)
 }
 
 pub enum UncommittedCommand {
     Begin,
-    Push(String, QueueName),
-    Pop(String, QueueName),
+    Push(String, QueueName, Option<MessageGroup>, Option<Duration>),
+    Pop(Item, QueueName),
+    StreamPush(String, QueueName),
+    StreamCommit(QueueName, GroupName, Offset),
+    StreamRetain(QueueName, Retention),
+}
+
+impl UncommittedCommand {
+    pub fn required_permissions(&self) -> Vec<(Permission, &str)> {
+        match *self {
+            UncommittedCommand::Push(_, ref queue_name, _, _) => vec![(Permission::Push, queue_name)],
+            UncommittedCommand::Pop(_, ref queue_name) => vec![(Permission::Pop, queue_name)],
+            UncommittedCommand::StreamPush(_, ref stream_name) => vec![(Permission::Push, stream_name)],
+            UncommittedCommand::StreamCommit(ref stream_name, _, _) => vec![(Permission::Pop, stream_name)],
+            UncommittedCommand::StreamRetain(ref stream_name, _) => vec![(Permission::Admin, stream_name)],
+            UncommittedCommand::Begin => Vec::new()
+        }
+    }
+
+    // Bytes of data held in the transaction buffer
+    pub fn bytes(&self) -> usize {
+        match *self {
+            UncommittedCommand::Push(ref value, _, _, _) => value.len(),
+            UncommittedCommand::Pop(ref item, _) => item.data.len(),
+            UncommittedCommand::StreamPush(ref value, _) => value.len(),
+            _ => 0
+        }
+    }
 }
 
 impl Command  {
     pub fn parse(buffer: Vec<u8>) -> ParseResult {
         parse_command(buffer)
     }
+
+    // The message parse reads back as this command, ; included
+    pub fn encode(&self) -> String {
+        let arguments = match *self {
+            Command::Auth(ref user, ref password) => vec![user.clone(), password.clone()],
+            Command::Push(ref value, ref queue_name, ref group, ttl) => {
+                vec![queue_name.clone(), value.clone(), group.clone().unwrap_or(String::new()),
+                     ttl.map_or(0, |ttl| ttl.as_secs()).to_string()]
+            }
+            Command::Pop(ref queue_name) | Command::BlockingPop(ref queue_name, None, false) => vec![queue_name.clone()],
+            Command::BlockingPop(ref queue_name, timeout, attempts) => {
+                let mut arguments = vec![queue_name.clone(), timeout.map_or(0, |timeout| timeout.as_millis()).to_string()];
+                if attempts {
+                    arguments.push("withattempts".to_string());
+                }
+                arguments
+            }
+            Command::StreamPush(ref value, ref stream_name) => vec![stream_name.clone(), value.clone()],
+            Command::StreamRead(ref stream_name, ref group, count) => vec![stream_name.clone(), group.clone(), count.to_string()],
+            Command::StreamCommit(ref stream_name, ref group, offset) => vec![stream_name.clone(), group.clone(), offset.to_string()],
+            Command::StreamRetain(ref stream_name, ref retention) => {
+                vec![stream_name.clone(), retention.max_len.unwrap_or(0).to_string(),
+                     retention.max_age.map_or(0, |max_age| max_age.as_secs()).to_string()]
+            }
+            Command::Limit(ref queue_name, ref limits) => {
+                let overflow = match limits.overflow {
+                    OverflowPolicy::Reject => "reject",
+                    OverflowPolicy::DropOldest => "drop",
+                    OverflowPolicy::Block => "block"
+                };
+                vec![queue_name.clone(), limits.max_len.unwrap_or(0).to_string(),
+                     limits.max_bytes.unwrap_or(0).to_string(), overflow.to_string()]
+            }
+            Command::SlowLog(count) => count.map(|count| count.to_string()).into_iter().collect(),
+            Command::SlowLogReset => vec!["reset".to_string()],
+            Command::ClientList => vec!["list".to_string()],
+            Command::ClientKill(id) => vec!["kill".to_string(), id.to_string()],
+            Command::ClientSetName(ref name) => vec!["setname".to_string(), name.clone()],
+            Command::TransactionAbort(id) => vec![id.to_string()],
+            Command::Ack(sequence) => vec![sequence.to_string()],
+            Command::Abort(delay) => delay.map(|delay| delay.as_millis().to_string()).into_iter().collect(),
+            Command::Raft(ref message) => {
+                return message.encode();
+            }
+            Command::ClusterSlots => vec!["slots".to_string()],
+            Command::ClusterKeySlot(ref name) => vec!["keyslot".to_string(), name.clone()],
+            Command::WriteConcern(ref concern) => vec![concern.replicas.to_string(), concern.timeout.as_millis().to_string()],
+            Command::QueueWriteConcern(ref queue_name, ref concern) => {
+                vec![queue_name.clone(), concern.replicas.to_string(), concern.timeout.as_millis().to_string()]
+            }
+            Command::MaxMemory(max) => vec![max.to_string()],
+            Command::DeadLetter(ref queue_name, ref dead_letter_name) => vec![queue_name.clone(), dead_letter_name.clone()],
+            Command::Quit | Command::Begin | Command::Commit | Command::Memory | Command::Info |
+            Command::Monitor | Command::TransactionList | Command::Sync | Command::Promote => Vec::new()
+        };
+        let mut message = self.name().to_string();
+        for argument in arguments {
+            message.push(' ');
+            message.push_str(&quote(&argument));
+        }
+        message.push(';');
+        message
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Command::Quit => "QUIT",
+            Command::Auth(..) => "AUTH",
+            Command::Push(..) => "PUSH",
+            Command::Pop(..) => "POP",
+            Command::BlockingPop(..) => "BPOP",
+            Command::Begin => "BEGIN",
+            Command::Commit => "COMMIT",
+            Command::Abort(_) => "ABORT",
+            Command::StreamPush(..) => "SPUSH",
+            Command::StreamRead(..) => "SREAD",
+            Command::StreamCommit(..) => "SCOMMIT",
+            Command::StreamRetain(..) => "SRETAIN",
+            Command::Limit(..) => "LIMIT",
+            Command::Memory => "MEMORY",
+            Command::Info => "INFO",
+            Command::SlowLog(..) | Command::SlowLogReset => "SLOWLOG",
+            Command::Monitor => "MONITOR",
+            Command::ClientList | Command::ClientKill(_) | Command::ClientSetName(_) => "CLIENT",
+            Command::TransactionList => "TXLIST",
+            Command::TransactionAbort(_) => "TXABORT",
+            Command::Sync => "SYNC",
+            Command::Ack(_) => "ACK",
+            Command::Promote => "PROMOTE",
+            Command::Raft(_) => "RAFT",
+            Command::ClusterSlots | Command::ClusterKeySlot(_) => "CLUSTER",
+            Command::WriteConcern(_) | Command::QueueWriteConcern(..) => "WRITECONCERN",
+            Command::MaxMemory(..) => "MAXMEMORY",
+            Command::DeadLetter(..) => "DEADLETTER"
+        }
+    }
+
+    // Whether a follower accepts the command, everything else changes data
+    pub fn is_read_only(&self) -> bool {
+        matches!(*self,
+            Command::Quit | Command::Auth(..) | Command::StreamRead(..) | Command::Memory | Command::Info |
+            Command::SlowLog(_) | Command::SlowLogReset | Command::Monitor | Command::ClientList |
+            Command::ClientKill(_) | Command::ClientSetName(_) | Command::TransactionList |
+            Command::TransactionAbort(_) | Command::MaxMemory(_) | Command::Promote |
+            Command::WriteConcern(_) | Command::Raft(_) | Command::ClusterSlots | Command::ClusterKeySlot(_))
+    }
+
+    // The queues and streams the command uses, for finding the shard that holds them
+    pub fn names(&self) -> Vec<&str> {
+        match *self {
+            Command::Push(_, ref name, _, _) | Command::Pop(ref name) | Command::BlockingPop(ref name, _, _) |
+            Command::StreamPush(_, ref name) | Command::StreamRead(ref name, _, _) | Command::StreamCommit(ref name, _, _) |
+            Command::StreamRetain(ref name, _) | Command::Limit(ref name, _) | Command::QueueWriteConcern(ref name, _) => vec![name],
+            Command::DeadLetter(ref name, ref dead_letter_name) => vec![name, dead_letter_name],
+            _ => Vec::new()
+        }
+    }
+
+    // Every permission the command needs, none for commands anyone may run, e.g. MEMORY or transaction control
+    pub fn required_permissions(&self) -> Vec<(Permission, &str)> {
+        match *self {
+            Command::Push(_, ref queue_name, _, _) => vec![(Permission::Push, queue_name)],
+            Command::Pop(ref queue_name) => vec![(Permission::Pop, queue_name)],
+            Command::BlockingPop(ref queue_name, _, _) => vec![(Permission::Pop, queue_name)],
+            Command::StreamPush(_, ref stream_name) => vec![(Permission::Push, stream_name)],
+            Command::StreamRead(ref stream_name, _, _) => vec![(Permission::Pop, stream_name)],
+            Command::StreamCommit(ref stream_name, _, _) => vec![(Permission::Pop, stream_name)],
+            Command::StreamRetain(ref stream_name, _) => vec![(Permission::Admin, stream_name)],
+            Command::Limit(ref queue_name, _) => vec![(Permission::Admin, queue_name)],
+            // Expired items end up in the dead letter queue as if pushed there
+            Command::DeadLetter(ref queue_name, ref dead_letter_name) => {
+                vec![(Permission::Admin, queue_name), (Permission::Push, dead_letter_name)]
+            }
+            Command::QueueWriteConcern(ref queue_name, _) => vec![(Permission::Admin, queue_name)],
+            Command::MaxMemory(_) => vec![(Permission::Server, SERVER)],
+            // Both show commands from every client, including their data
+            Command::SlowLog(_) | Command::SlowLogReset | Command::Monitor => vec![(Permission::Server, SERVER)],
+            Command::ClientList | Command::ClientKill(_) => vec![(Permission::Server, SERVER)],
+            Command::TransactionList | Command::TransactionAbort(_) => vec![(Permission::Server, SERVER)],
+            // A follower gets a copy of every queue
+            Command::Sync | Command::Promote => vec![(Permission::Server, SERVER)],
+            Command::Raft(_) => vec![(Permission::Server, SERVER)],
+            _ => Vec::new()
+        }
+    }
 }
@@ -0,0 +1,480 @@
+use std::fs::File;
+use std::io::Read;
+use std::net::{SocketAddr,ToSocketAddrs};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use log::{LevelFilter};
+
+use acl::{AclRule,Permission,SERVER};
+use auth::{Users,UserName,PasswordHash};
+use logging;
+use logging::{LogFormat};
+use persistence;
+use persistence::{FsyncPolicy};
+use raft::{NodeId};
+use shards;
+use shards::{ShardRange,Slot};
+use slowlog;
+use queue_table::{QueueLimits,OverflowPolicy};
+
+pub const USAGE: &str = "Usage: queue_experiments [--config path] [--setting value ...]
+       queue_experiments --hash-password password
+
+Settings can be given in the config file as `setting value` lines or as --setting value flags,
+flags override the config file.
+
+  listen address           address to listen on, can be repeated (default 127.0.0.1:5248)
+                           `none` disables TCP
+  tls-listen address       address to accept TLS connections on, can be repeated
+  tls-cert path            PEM certificate chain for TLS
+  tls-key path             PEM private key for TLS
+  tls-ca-cert path         require TLS clients to present a certificate signed by this CA
+  unixsocket path          also listen on a Unix domain socket
+  unixsocketperm mode      octal file permissions of the Unix socket (default 700)
+  metrics-listen address   serve Prometheus metrics over HTTP at /metrics on this address
+  shutdown-timeout secs    how long open transactions get to finish on SIGTERM (default 10)
+  log-level level          error, warn, info, debug, trace or off (default info)
+  log-format format        text or json (default text)
+  trace-commands yes|no    log every command with its latency (default no)
+  slowlog-slower-than us   keep commands that take at least this long for SLOWLOG (default 10000),
+                           negative disables the slow log
+  slowlog-max-len n        how many slow commands to keep (default 128)
+  appendonly path          log every change to this file and load it on start, nothing is kept
+                           across restarts without it
+  appendfsync policy       when the log is synced to disk: always, everysec or no (default everysec)
+  maxmemory bytes          refuse writes once this much data is held, 0 is unlimited
+  max-queue-length n       default max length of new queues, 0 is unlimited
+  max-queue-bytes n        default max bytes of new queues, 0 is unlimited
+  overflow policy          default overflow policy of new queues: reject, drop or block
+  user name hash           require clients to AUTH, can be repeated, hashes come from --hash-password
+  acl user perms pattern   grant a user push, pop, admin or all (comma separated) on matching
+                           queues, e.g. `acl web push billing.*`, or server with the pattern *
+                           for server-wide commands, can be repeated
+  follow address           start as a read only follower of the leader at address
+  follow-auth user pass    user and password to AUTH with on the leader
+  cluster-node id address  a node of the Raft cluster and the address clients reach it on,
+                           given once per node including this one
+  cluster-id id            which of the cluster nodes this is, enables cluster mode
+  cluster-auth user pass   user and password to AUTH with on the other nodes, required in cluster mode
+  cluster-election-timeout ms
+                           how long without a leader before an election (default 1000)
+  shard start-end address  slots start to end (inclusive, 0-16383) are served at address,
+                           given once per range, every slot must be covered
+  shard-self address       which of the shard addresses this server is, enables sharding";
+
+const DEFAULT_LISTEN: &str = "127.0.0.1:5248";
+const DEFAULT_SHUTDOWN_TIMEOUT: u64 = 10;
+const DEFAULT_ELECTION_TIMEOUT: u64 = 1000;
+
+#[derive(PartialEq)]
+#[derive(Debug)]
+pub struct Config {
+    pub listen: Vec<SocketAddr>,
+    pub tls_listen: Vec<SocketAddr>,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub tls_ca_cert: Option<PathBuf>,
+    pub unixsocket: Option<PathBuf>,
+    pub unixsocketperm: u32,
+    pub metrics_listen: Option<SocketAddr>,
+    pub shutdown_timeout: Duration,
+    pub log_level: LevelFilter,
+    pub log_format: LogFormat,
+    pub trace_commands: bool,
+    pub slowlog_slower_than: i64,
+    pub slowlog_max_len: usize,
+    pub appendonly: Option<PathBuf>,
+    pub appendfsync: FsyncPolicy,
+    pub maxmemory: usize,
+    pub queue_limits: QueueLimits,
+    pub users: Users,
+    pub follow: Option<SocketAddr>,
+    pub follow_auth: Option<(UserName, String)>,
+    pub cluster_nodes: Vec<(NodeId, SocketAddr)>,
+    pub cluster_id: Option<NodeId>,
+    pub cluster_auth: Option<(UserName, String)>,
+    pub cluster_election_timeout: Duration,
+    pub shards: Vec<ShardRange>,
+    pub shard_self: Option<SocketAddr>
+}
+
+// (setting, value, where it came from for error messages)
+type Setting = (String, String, String);
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            listen: vec![DEFAULT_LISTEN.parse().unwrap()],
+            tls_listen: Vec::new(),
+            tls_cert: None,
+            tls_key: None,
+            tls_ca_cert: None,
+            unixsocket: None,
+            unixsocketperm: 0o700,
+            metrics_listen: None,
+            shutdown_timeout: Duration::from_secs(DEFAULT_SHUTDOWN_TIMEOUT),
+            log_level: LevelFilter::Info,
+            log_format: LogFormat::Text,
+            trace_commands: false,
+            slowlog_slower_than: slowlog::DEFAULT_SLOWER_THAN,
+            slowlog_max_len: slowlog::DEFAULT_MAX_LEN,
+            appendonly: None,
+            appendfsync: FsyncPolicy::EverySec,
+            maxmemory: 0,
+            queue_limits: QueueLimits::unlimited(),
+            users: Users::new(),
+            follow: None,
+            follow_auth: None,
+            cluster_nodes: Vec::new(),
+            cluster_id: None,
+            cluster_auth: None,
+            cluster_election_timeout: Duration::from_millis(DEFAULT_ELECTION_TIMEOUT),
+            shards: Vec::new(),
+            shard_self: None
+        }
+    }
+}
+
+impl Config {
+    // args excludes the program name
+    pub fn from_args(args: Vec<String>) -> Result<Config, String> {
+        let flags = parse_flags(args)?;
+        let mut config = Config::default();
+        for (setting, value, _) in flags.iter() {
+            if setting == "config" {
+                let contents = read_file(value)?;
+                config.apply(parse_file(&contents, value)?)?;
+            }
+        }
+        let overrides = flags.into_iter().filter(|(setting, _, _)| setting != "config").collect();
+        config.apply(overrides)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file_contents(contents: &str) -> Result<Config, String> {
+        let mut config = Config::default();
+        config.apply(parse_file(contents, "config")?)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.listen.is_empty() && self.tls_listen.is_empty() && self.unixsocket.is_none() {
+            return Err("nothing to listen on, set listen, tls-listen or unixsocket".to_string());
+        }
+        if !self.tls_listen.is_empty() && (self.tls_cert.is_none() || self.tls_key.is_none()) {
+            return Err("tls-listen requires tls-cert and tls-key".to_string());
+        }
+        // A follower loads its data from the leader and cluster nodes from the other nodes
+        if self.appendonly.is_some() && (self.follow.is_some() || self.cluster_id.is_some()) {
+            return Err("appendonly can't be used with follow or in cluster mode".to_string());
+        }
+        if let Some(id) = self.cluster_id {
+            if !self.cluster_nodes.iter().any(|&(node, _)| node == id) {
+                return Err(format!("cluster-id {} is not one of the cluster-node settings", id));
+            }
+            if self.follow.is_some() {
+                return Err("follow can't be used in cluster mode".to_string());
+            }
+            for (i, &(node, _)) in self.cluster_nodes.iter().enumerate() {
+                if self.cluster_nodes[..i].iter().any(|&(other, _)| other == node) {
+                    return Err(format!("cluster-node {} is set more than once", node));
+                }
+            }
+            // RAFT commands rewrite the log, so only users with the server permission may send them
+            match self.cluster_auth {
+                Some((ref user, ref password)) => {
+                    if !self.users.authenticate(user, password) {
+                        return Err("cluster-auth must be the name and password of a user setting".to_string());
+                    }
+                    if !self.users.allows(user, Permission::Server, SERVER) {
+                        return Err(format!("cluster-auth user {} needs the server permission", user));
+                    }
+                }
+                None => {
+                    return Err("cluster mode requires cluster-auth and user settings".to_string());
+                }
+            }
+        } else if !self.cluster_nodes.is_empty() {
+            return Err("cluster-node requires cluster-id".to_string());
+        }
+        if let Some(local) = self.shard_self {
+            shards::validate(&self.shards, local)?;
+        } else if !self.shards.is_empty() {
+            return Err("shard requires shard-self".to_string());
+        }
+        Ok(())
+    }
+
+    // Listen addresses from one source replace those from earlier sources
+    fn apply(&mut self, settings: Vec<Setting>) -> Result<(), String> {
+        let mut listen = Vec::new();
+        let mut listen_given = false;
+        let mut tls_listen = Vec::new();
+        let mut tls_listen_given = false;
+        for (setting, value, source) in settings {
+            let result = match &setting as &str {
+                "listen" => {
+                    listen_given = true;
+                    if value == "none" {
+                        Ok(())
+                    } else {
+                        parse_address(&value).map(|address| listen.push(address))
+                    }
+                }
+                "tls-listen" => {
+                    tls_listen_given = true;
+                    parse_address(&value).map(|address| tls_listen.push(address))
+                }
+                "tls-cert" => {
+                    self.tls_cert = Some(PathBuf::from(value));
+                    Ok(())
+                }
+                "tls-key" => {
+                    self.tls_key = Some(PathBuf::from(value));
+                    Ok(())
+                }
+                "tls-ca-cert" => {
+                    self.tls_ca_cert = Some(PathBuf::from(value));
+                    Ok(())
+                }
+                "unixsocket" => {
+                    self.unixsocket = Some(PathBuf::from(value));
+                    Ok(())
+                }
+                "unixsocketperm" => {
+                    parse_mode(&value).map(|mode| self.unixsocketperm = mode)
+                }
+                "metrics-listen" => {
+                    parse_address(&value).map(|address| self.metrics_listen = Some(address))
+                }
+                "shutdown-timeout" => {
+                    parse_size(&value).map(|secs| self.shutdown_timeout = Duration::from_secs(secs as u64))
+                }
+                "log-level" => {
+                    logging::parse_level(&value).map(|level| self.log_level = level)
+                }
+                "log-format" => {
+                    logging::parse_format(&value).map(|format| self.log_format = format)
+                }
+                "trace-commands" => {
+                    parse_bool(&value).map(|trace| self.trace_commands = trace)
+                }
+                "slowlog-slower-than" => {
+                    value.parse::<i64>().map(|micros| self.slowlog_slower_than = micros).map_err(|_| format!("invalid number {}", value))
+                }
+                "slowlog-max-len" => {
+                    parse_size(&value).map(|max_len| self.slowlog_max_len = max_len)
+                }
+                "appendonly" => {
+                    self.appendonly = Some(PathBuf::from(value));
+                    Ok(())
+                }
+                "appendfsync" => {
+                    persistence::parse_policy(&value).map(|policy| self.appendfsync = policy)
+                }
+                "maxmemory" => {
+                    parse_size(&value).map(|max| self.maxmemory = max)
+                }
+                "max-queue-length" => {
+                    parse_size(&value).map(|max| self.queue_limits.max_len = unlimited_if_zero(max))
+                }
+                "max-queue-bytes" => {
+                    parse_size(&value).map(|max| self.queue_limits.max_bytes = unlimited_if_zero(max))
+                }
+                "overflow" => {
+                    parse_overflow(&value).map(|overflow| self.queue_limits.overflow = overflow)
+                }
+                "user" => {
+                    parse_user(&value).map(|(name, hash)| self.users.add(name, hash))
+                }
+                "acl" => {
+                    AclRule::parse(&value).map(|rule| self.users.add_rule(rule))
+                }
+                "follow" => {
+                    parse_address(&value).map(|address| self.follow = Some(address))
+                }
+                "follow-auth" => {
+                    parse_auth(&value, "follow-auth").map(|auth| self.follow_auth = Some(auth))
+                }
+                "cluster-node" => {
+                    parse_cluster_node(&value).map(|node| self.cluster_nodes.push(node))
+                }
+                "cluster-id" => {
+                    value.parse::<NodeId>().map(|id| self.cluster_id = Some(id)).map_err(|_| format!("invalid node id {}", value))
+                }
+                "cluster-auth" => {
+                    parse_auth(&value, "cluster-auth").map(|auth| self.cluster_auth = Some(auth))
+                }
+                "cluster-election-timeout" => {
+                    parse_size(&value).map(|millis| self.cluster_election_timeout = Duration::from_millis(millis as u64))
+                }
+                "shard" => {
+                    parse_shard(&value).map(|range| self.shards.push(range))
+                }
+                "shard-self" => {
+                    parse_address(&value).map(|address| self.shard_self = Some(address))
+                }
+                _ => {
+                    Err(format!("unknown setting {}", setting))
+                }
+            };
+            if let Err(message) = result {
+                return Err(format!("{}: {}", source, message));
+            }
+        }
+        if listen_given {
+            self.listen = listen;
+        }
+        if tls_listen_given {
+            self.tls_listen = tls_listen;
+        }
+        Ok(())
+    }
+}
+
+fn parse_flags(args: Vec<String>) -> Result<Vec<Setting>, String> {
+    let mut settings = Vec::new();
+    let mut args = args.into_iter();
+    loop {
+        match args.next() {
+            Some(flag) => {
+                if !flag.starts_with("--") {
+                    return Err(format!("unexpected argument {}", flag));
+                }
+                let setting = flag[2..].to_string();
+                match args.next() {
+                    Some(value) => {
+                        settings.push((setting, value, flag));
+                    }
+                    None => {
+                        return Err(format!("{} requires a value", flag));
+                    }
+                }
+            }
+            None => {
+                return Ok(settings);
+            }
+        }
+    }
+}
+
+// Blank lines and lines starting with # are ignored
+fn parse_file(contents: &str, path: &str) -> Result<Vec<Setting>, String> {
+    let mut settings = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut parts = line.splitn(2, char::is_whitespace);
+        let setting = parts.next().unwrap().to_string();
+        match parts.next() {
+            Some(value) => {
+                settings.push((setting, value.trim().to_string(), format!("{} line {}", path, number + 1)));
+            }
+            None => {
+                return Err(format!("{} line {}: {} requires a value", path, number + 1, setting));
+            }
+        }
+    }
+    Ok(settings)
+}
+
+fn read_file(path: &str) -> Result<String, String> {
+    let mut contents = String::new();
+    match File::open(path).and_then(|mut file| file.read_to_string(&mut contents)) {
+        Ok(_) => {
+            Ok(contents)
+        }
+        Err(error) => {
+            Err(format!("could not read config file {}: {}", path, error))
+        }
+    }
+}
+
+// Accepts anything that resolves, e.g. localhost:5248 or [::1]:5248
+fn parse_address(value: &str) -> Result<SocketAddr, String> {
+    match value.to_socket_addrs().map(|mut addresses| addresses.next()) {
+        Ok(Some(address)) => {
+            Ok(address)
+        }
+        _ => {
+            Err(format!("invalid address {}", value))
+        }
+    }
+}
+
+fn parse_mode(value: &str) -> Result<u32, String> {
+    match u32::from_str_radix(value, 8) {
+        Ok(mode) if mode <= 0o777 => Ok(mode),
+        _ => Err(format!("invalid file mode {}", value))
+    }
+}
+
+fn parse_bool(value: &str) -> Result<bool, String> {
+    match &value.to_lowercase() as &str {
+        "yes" | "true" => Ok(true),
+        "no" | "false" => Ok(false),
+        _              => Err(format!("expected yes or no, got {}", value))
+    }
+}
+
+fn parse_size(value: &str) -> Result<usize, String> {
+    value.parse::<usize>().map_err(|_| format!("invalid number {}", value))
+}
+
+fn parse_overflow(value: &str) -> Result<OverflowPolicy, String> {
+    match &value.to_lowercase() as &str {
+        "reject" => Ok(OverflowPolicy::Reject),
+        "drop"   => Ok(OverflowPolicy::DropOldest),
+        "block"  => Ok(OverflowPolicy::Block),
+        _        => Err(format!("unknown overflow policy {}", value))
+    }
+}
+
+fn parse_user(value: &str) -> Result<(String, PasswordHash), String> {
+    let parts: Vec<&str> = value.split_whitespace().collect();
+    if parts.len() != 2 {
+        return Err("user requires a name and a password hash".to_string());
+    }
+    PasswordHash::parse(parts[1]).map(|hash| (parts[0].to_string(), hash))
+}
+
+fn parse_auth(value: &str, setting: &str) -> Result<(UserName, String), String> {
+    let parts: Vec<&str> = value.split_whitespace().collect();
+    if parts.len() != 2 {
+        return Err(format!("{} requires a user and a password", setting));
+    }
+    Ok((parts[0].to_string(), parts[1].to_string()))
+}
+
+fn parse_cluster_node(value: &str) -> Result<(NodeId, SocketAddr), String> {
+    let parts: Vec<&str> = value.split_whitespace().collect();
+    if parts.len() != 2 {
+        return Err("cluster-node requires an id and an address".to_string());
+    }
+    let id = parts[0].parse::<NodeId>().map_err(|_| format!("invalid node id {}", parts[0]))?;
+    parse_address(parts[1]).map(|address| (id, address))
+}
+
+fn parse_shard(value: &str) -> Result<ShardRange, String> {
+    let parts: Vec<&str> = value.split_whitespace().collect();
+    if parts.len() != 2 {
+        return Err("shard requires a slot range and an address".to_string());
+    }
+    let bounds: Vec<Option<Slot>> = parts[0].split('-').map(|bound| bound.parse::<Slot>().ok()).collect();
+    let (start, end) = match bounds.as_slice() {
+        [Some(start), Some(end)] if start <= end && (*end as usize) < shards::SLOTS => (*start, *end),
+        _ => {
+            return Err(format!("invalid slot range {}", parts[0]));
+        }
+    };
+    parse_address(parts[1]).map(|address| ShardRange { start, end, address })
+}
+
+fn unlimited_if_zero(value: usize) -> Option<usize> {
+    if value == 0 { None } else { Some(value) }
+}
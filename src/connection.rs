@@ -1,101 +1,754 @@
-use std::io::{Write, BufRead};
-use std::time::Duration;
-use std::thread;
+use std::mem;
+use std::collections::VecDeque;
+use std::sync::mpsc::{Receiver,TryRecvError};
+use std::time::{Duration,Instant,SystemTime,UNIX_EPOCH};
 
-use queue_table::{QueueName,QueueTable};
+use acl::{Permission};
+use auth::{Users,UserName,Verifier};
+use clients::{ClientEntry,TransactionInfo};
+use cluster;
+use logging;
+use monitor::{MonitorFeed};
+use queue_table::{Queue,QueueName,QueueTable,MessageGroup,Item,PushError};
+use raft::{Index,Term,Role,EntryState};
+use replication;
+use replication::{Operation,ReplicaFeed,Sequence,WriteConcern};
+use shards;
+use shards::{Slot};
+use stats::{ClientId};
+use stream::{GroupName,Offset};
 
 use commands::{Command,UncommittedCommand};
 
-const BLOCKING_POP_POLLING_FREQ:u64 = 100;
+const MAX_DESCRIBED_LEN: usize = 128;
+// Unprocessed input a client may send before it is disconnected. It bounds a single
+// message as well as the commands pipelined behind a blocked one.
+const MAX_INPUT_SIZE: usize = 64 << 20;
+// Wrong passwords a connection may send before it is closed
+const MAX_FAILED_AUTH: u32 = 3;
 
-pub struct Connection<'a> {
-    queue_table: &'a QueueTable,
-    reader: &'a mut BufRead,
-    writer: &'a mut Write,
-    uncommitted_cmds: Vec<UncommittedCommand>
+#[derive(PartialEq)]
+#[derive(Debug)]
+pub enum Status {
+    Ready,
+    // Waiting on other clients, call retry_blocked once they may have made progress
+    Blocked,
+    Closed
 }
 
-impl <'a>Connection<'a> {
-    pub fn new(reader: &'a mut BufRead, writer: &'a mut Write, queue_table: &'a QueueTable) -> Connection<'a> {
+// What a blocked connection waits for, it only needs retrying once that may have happened
+#[derive(PartialEq,Eq,Hash)]
+#[derive(Debug)]
+#[derive(Clone)]
+pub enum Wait {
+    // An item to pop from the queue
+    Items(QueueName),
+    // Room to push to the queue
+    Room(QueueName),
+    // Followers or the cluster to apply a write, or its deadline to pass
+    Replication,
+    // A verifier thread to check a password
+    Auth
+}
+
+// A command that can't finish until another client pushes or pops, until followers ack
+// or until a verifier thread has checked a password
+enum Blocked {
+    // When the BPOP started, for the wait time histogram, when it gives up and whether
+    // the reply has the item's attempt
+    Pop(QueueName, Instant, Option<Instant>, bool),
+    Push(Item, QueueName),
+    // With the queue that has no room yet
+    Commit(VecDeque<UncommittedCommand>, WriteConcern, QueueName),
+    // The reply is held until the write is replicated
+    Replication(ReplicationWait, Vec<u8>),
+    Auth(UserName, Receiver<bool>)
+}
+
+struct ReplicationWait {
+    // Followers that must have applied everything up to sequence
+    sequence: Sequence,
+    replicas: usize,
+    // The cluster log entry that must be committed
+    entry: Option<(Index, Term)>,
+    deadline: Instant
+}
+
+// Connection doesn't do any IO itself.
+// Bytes read from the client are passed to feed and replies are collected with take_output.
+pub struct Connection {
+    id: ClientId,
+    peer: String,
+    queue_table: QueueTable,
+    users: Users,
+    // Without one, e.g. in tests, passwords are checked inline
+    verifier: Option<Verifier>,
+    user: Option<UserName>,
+    failed_auth: u32,
+    uncommitted_cmds: Vec<UncommittedCommand>,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    blocked: Option<Blocked>,
+    shutting_down: bool,
+    closed: bool,
+    // Set once the client runs MONITOR
+    monitor: Option<MonitorFeed>,
+    // Set once a follower runs SYNC
+    replica: Option<ReplicaFeed>,
+    // What this connection currently counts towards in the server stats
+    counted_in_transaction: bool,
+    counted_blocked: bool,
+    // This connection's entry in CLIENT LIST
+    info: ClientEntry,
+    // Transaction size last published to info, None outside a transaction
+    counted_transaction: Option<usize>,
+    // Set when TXABORT rolled back the transaction before the client finished it
+    aborted: bool,
+    // Set with WRITECONCERN, combined with the concern of each queue written to
+    write_concern: WriteConcern,
+    // The last cluster log entry this connection proposed, commits and pushes wait for it
+    proposed: Option<(Index, Term)>,
+    // When sharded every queue in a transaction must be in the slot of the first one
+    transaction_slot: Option<Slot>
+}
+
+impl Connection {
+    pub fn new(queue_table: &QueueTable) -> Connection {
+        Connection::with_users(queue_table, &Users::new())
+    }
+
+    // Only AUTH and QUIT are accepted until the client authenticates as one of users
+    pub fn with_users(queue_table: &QueueTable, users: &Users) -> Connection {
+        let id = queue_table.stats().client_connected();
         Connection {
-            queue_table: &queue_table,
-            reader: reader,
-            writer: writer,
-            uncommitted_cmds: Vec::new()
+            id,
+            peer: "unknown".to_string(),
+            queue_table: queue_table.clone(),
+            users: users.clone(),
+            verifier: None,
+            user: None,
+            failed_auth: 0,
+            uncommitted_cmds: Vec::new(),
+            input: Vec::new(),
+            output: Vec::new(),
+            blocked: None,
+            shutting_down: false,
+            closed: false,
+            monitor: None,
+            replica: None,
+            counted_in_transaction: false,
+            counted_blocked: false,
+            info: queue_table.clients().register(id),
+            counted_transaction: None,
+            aborted: false,
+            write_concern: WriteConcern::none(),
+            proposed: None,
+            transaction_slot: None
+        }
+    }
+
+    pub fn id(&self) -> ClientId {
+        self.id
+    }
+
+    // Where the client connected from, for logging and CLIENT LIST
+    pub fn set_peer(&mut self, peer: String) {
+        self.info.lock().unwrap().peer = peer.clone();
+        self.peer = peer;
+    }
+
+    pub fn peer(&self) -> &str {
+        &self.peer
+    }
+
+    // AUTH then blocks until the verifier has checked the password
+    pub fn set_verifier(&mut self, verifier: &Verifier) {
+        self.verifier = Some(verifier.clone());
+    }
+
+    // Messages are only processed while the connection isn't blocked,
+    // anything else is buffered until retry_blocked succeeds
+    pub fn feed(&mut self, buf: &[u8]) -> Status {
+        self.input.extend_from_slice(buf);
+        let status = self.process_input();
+        if self.input.len() > MAX_INPUT_SIZE && !self.closed {
+            warn!(client = self.id, peer:% = self.peer, bytes = self.input.len(); "too much unprocessed input");
+            self.close();
+            return Status::Closed;
+        }
+        status
+    }
+
+    pub fn retry_blocked(&mut self) -> Status {
+        match self.blocked.take() {
+            Some(Blocked::Pop(queue_name, since, deadline, attempts)) => {
+                self.exec_blocking_pop(queue_name, since, deadline, attempts);
+            }
+            Some(Blocked::Push(item, queue_name)) => {
+                self.exec_blocking_push(item, queue_name);
+            }
+            Some(Blocked::Commit(cmds, concern, _)) => {
+                self.continue_commit(cmds, concern);
+            }
+            Some(Blocked::Replication(wait, reply)) => {
+                self.wait_for_replicas(wait, reply);
+            }
+            Some(Blocked::Auth(user, result)) => {
+                match result.try_recv() {
+                    Ok(valid) => {
+                        self.auth_checked(user, valid);
+                    }
+                    Err(TryRecvError::Empty) => {
+                        self.blocked = Some(Blocked::Auth(user, result));
+                    }
+                    Err(TryRecvError::Disconnected) => {
+                        error!(client = self.id; "password check lost");
+                        self.auth_checked(user, false);
+                    }
+                }
+            }
+            None => {
+            }
         }
+        self.process_input()
+    }
+
+    pub fn has_output(&self) -> bool {
+        !self.output.is_empty() || self.monitor.as_ref().is_some_and(|feed| !feed.lock().unwrap().is_empty()) ||
+            self.replica.as_ref().is_some_and(|feed| !feed.lock().unwrap().data.is_empty())
     }
 
-    pub fn listen(&mut self) {
+    // Includes anything published to a MONITOR or SYNC feed.
+    // A follower that fell too far behind is closed, it syncs again when it reconnects.
+    pub fn take_output(&mut self) -> Vec<u8> {
+        let mut output = mem::take(&mut self.output);
+        if let Some(ref feed) = self.monitor {
+            output.append(&mut feed.lock().unwrap());
+        }
+        let overflowed = match self.replica {
+            Some(ref feed) => {
+                let mut feed = feed.lock().unwrap();
+                output.append(&mut feed.data);
+                feed.overflowed
+            }
+            None => false
+        };
+        if overflowed && !self.closed {
+            warn!(client = self.id, peer:% = self.peer; "follower fell too far behind");
+            self.close();
+        }
+        output
+    }
+
+    pub fn is_monitoring(&self) -> bool {
+        self.monitor.is_some()
+    }
+
+    pub fn is_replica(&self) -> bool {
+        self.replica.is_some()
+    }
+
+    // Called when the client goes away, open transactions are rolled back
+    pub fn close(&mut self) {
+        if let Some(Blocked::Commit(cmds, _, _)) = self.blocked.take() {
+            self.uncommitted_cmds.extend(cmds);
+        }
+        self.rollback(None);
+        self.closed = true;
+        if self.monitor.take().is_some() {
+            self.queue_table.monitors().unsubscribe(self.id);
+        }
+        if self.replica.take().is_some() {
+            self.queue_table.replication().unsubscribe(self.id);
+        }
+        self.update_stats();
+    }
+
+    // Blocked pops and pushes are answered with SHUTTING DOWN.
+    // An open transaction can still COMMIT or ABORT, after which the connection closes.
+    pub fn shutdown(&mut self) -> Status {
+        self.shutting_down = true;
+        match self.blocked.take() {
+            Some(Blocked::Pop(..)) | Some(Blocked::Push(..)) => {
+                self.write(b"SHUTTING DOWN\r\n");
+            }
+            blocked => {
+                self.blocked = blocked;
+            }
+        }
+        self.process_input()
+    }
+
+    // Rolls back the transaction for TXABORT. A COMMIT waiting on a full queue is answered straight away,
+    // otherwise the client gets TRANSACTION ABORTED until it sends COMMIT or ABORT.
+    pub fn abort_transaction(&mut self) -> Status {
+        match self.blocked.take() {
+            Some(Blocked::Commit(cmds, _, _)) => {
+                self.uncommitted_cmds.extend(cmds);
+                self.write(b"TRANSACTION ABORTED\r\n");
+            }
+            Some(Blocked::Pop(..)) if self.is_in_transaction() => {
+                self.write(b"TRANSACTION ABORTED\r\n");
+                self.aborted = true;
+            }
+            blocked => {
+                self.blocked = blocked;
+                self.aborted = self.is_in_transaction();
+            }
+        }
+        if self.is_in_transaction() {
+            warn!(client = self.id; "transaction aborted by admin");
+        }
+        self.rollback(None);
+        self.process_input()
+    }
+
+    // Called before a node that lost cluster leadership rebuilds its queues from the log.
+    // Blocked pops and pushes get NO LEADER and open transactions are aborted as with TXABORT.
+    pub fn leadership_lost(&mut self) -> Status {
+        match self.blocked.take() {
+            Some(Blocked::Pop(..)) | Some(Blocked::Push(..)) if !self.is_in_transaction() => {
+                self.write(b"NO LEADER\r\n");
+            }
+            blocked => {
+                self.blocked = blocked;
+            }
+        }
+        let committing = matches!(self.blocked, Some(Blocked::Commit(..)));
+        if self.is_in_transaction() || committing {
+            self.abort_transaction()
+        } else {
+            self.process_input()
+        }
+    }
+
+    pub fn is_in_transaction(&self) -> bool {
+        !self.uncommitted_cmds.is_empty()
+    }
+
+    pub fn waiting_for(&self) -> Option<Wait> {
+        if self.closed {
+            return None;
+        }
+        match self.blocked {
+            Some(Blocked::Pop(ref queue_name, ..)) => Some(Wait::Items(queue_name.clone())),
+            Some(Blocked::Push(_, ref queue_name)) | Some(Blocked::Commit(_, _, ref queue_name)) => Some(Wait::Room(queue_name.clone())),
+            Some(Blocked::Replication(..)) => Some(Wait::Replication),
+            Some(Blocked::Auth(..)) => Some(Wait::Auth),
+            None => None
+        }
+    }
+
+    // When a BPOP or a write waiting on followers gives up
+    pub fn deadline(&self) -> Option<Instant> {
+        match self.blocked {
+            Some(Blocked::Pop(_, _, deadline, _)) => deadline,
+            Some(Blocked::Replication(ref wait, _)) => Some(wait.deadline),
+            _ => None
+        }
+    }
+
+    pub fn status(&self) -> Status {
+        if self.closed {
+            Status::Closed
+        } else if self.blocked.is_some() {
+            Status::Blocked
+        } else {
+            Status::Ready
+        }
+    }
+
+    fn process_input(&mut self) -> Status {
         loop {
-            let quit = self.process_message();
-            self.flush();
-            if quit {
-                self.rollback();
+            if self.shutting_down && self.blocked.is_none() && !self.is_in_transaction() {
+                self.closed = true;
+            }
+            if self.status() != Status::Ready {
                 break;
             }
+            match self.input.iter().position(|c| *c == b';') {
+                Some(end) => {
+                    let mut message: Vec<u8> = self.input.drain(..end + 1).collect();
+                    message.pop();
+                    self.process_message(message);
+                }
+                None => {
+                    break;
+                }
+            }
         }
+        self.update_stats();
+        self.status()
     }
 
-    pub fn process_message(&mut self) -> bool {
-        match self.read_message() {
-            Ok(result) => {
-                let cmd = Command::parse(result);
-                match cmd {
-                    Ok(cmd) => {
-                        if self.is_in_transaction() {
-                            self.exec_cmd_in_transaction(cmd)
-                        } else {
-                            self.exec_cmd(cmd)
-                        }
-                    }
-                    Err(message) => {
-                        self.write(message.as_bytes());
-                        self.write(b"\r\n");
-                        false
+    fn update_stats(&mut self) {
+        let in_transaction = self.is_in_transaction();
+        if in_transaction != self.counted_in_transaction {
+            self.queue_table.stats().set_in_transaction(in_transaction);
+            self.counted_in_transaction = in_transaction;
+        }
+        let blocked = self.blocked.is_some();
+        if blocked != self.counted_blocked {
+            self.queue_table.stats().set_blocked(blocked);
+            self.counted_blocked = blocked;
+        }
+        let transaction = if self.is_in_transaction() {
+            Some(self.uncommitted_cmds.len() - 1)
+        } else {
+            None
+        };
+        if transaction != self.counted_transaction {
+            let mut info = self.info.lock().unwrap();
+            info.transaction_size = transaction.unwrap_or(0);
+            info.transaction = transaction.map(|_| self.transaction_info(info.transaction.take()));
+            self.counted_transaction = transaction;
+        }
+    }
+
+    // Keeps when the transaction began from the previous summary
+    fn transaction_info(&self, previous: Option<TransactionInfo>) -> TransactionInfo {
+        let (began, began_at) = match previous {
+            Some(previous) => (previous.began, previous.began_at),
+            None => (Instant::now(), SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_secs()).unwrap_or(0))
+        };
+        let mut pops: Vec<(QueueName, usize)> = Vec::new();
+        for cmd in self.uncommitted_cmds.iter() {
+            if let UncommittedCommand::Pop(_, ref queue_name) = *cmd {
+                match pops.iter().position(|(name, _)| name == queue_name) {
+                    Some(i) => pops[i].1 += 1,
+                    None => pops.push((queue_name.clone(), 1))
+                }
+            }
+        }
+        pops.sort();
+        TransactionInfo {
+            began,
+            began_at,
+            pushes: count_cmds(&self.uncommitted_cmds).0,
+            pops
+        }
+    }
+
+    fn exec_info(&mut self) {
+        let stats = self.queue_table.stats().clone();
+        let memory = self.queue_table.memory().clone();
+        let replication = self.queue_table.replication().clone();
+        let cluster = self.queue_table.cluster().info();
+        let role = match cluster {
+            Some(ref cluster) if cluster.role == Role::Candidate => "candidate",
+            Some(ref cluster) if cluster.role == Role::Follower => "follower",
+            Some(_) => "leader",
+            None if replication.is_follower() => "follower",
+            None => "leader"
+        };
+        let mut lines = vec![
+            format!("role:{}", role),
+            format!("connected_replicas:{}", replication.replicas()),
+            format!("uptime_seconds:{}", stats.uptime().as_secs()),
+            format!("connected_clients:{}", stats.connected_clients()),
+            format!("open_transactions:{}", stats.open_transactions()),
+            format!("blocked_clients:{}", stats.blocked_clients()),
+            format!("used_memory:{}", memory.used()),
+            format!("maxmemory:{}", memory.max())
+        ];
+        let leader = match cluster {
+            Some(ref cluster) => cluster.leader.map(|leader| leader.to_string()),
+            None => replication.leader()
+        };
+        if let Some(leader) = leader {
+            lines.insert(1, format!("leader:{}", leader));
+        }
+        if let Some(cluster) = cluster {
+            lines.insert(1, format!("cluster_node:{} cluster_term:{} cluster_commit_index:{} cluster_applied:{}",
+                                    cluster.node, cluster.term, cluster.commit_index, cluster.applied));
+        }
+        for (queue_name, queue) in self.queue_table.queue_stats() {
+            let oldest_age = queue.oldest_age.map(|age| age.as_millis()).unwrap_or(0);
+            lines.push(format!("queue:{} length:{} bytes:{} pushed:{} popped:{} committed:{} rolled_back:{} oldest_age_ms:{}",
+                               queue_name, queue.len, queue.bytes, queue.pushed, queue.popped, queue.committed, queue.rolled_back, oldest_age));
+        }
+        self.write(format!("{}\r\n", lines.len()).as_bytes());
+        for line in lines {
+            self.write(format!("{}\r\n", line).as_bytes());
+        }
+    }
+
+    fn exec_cluster_slots(&mut self) {
+        let shards = self.queue_table.shards().clone();
+        if !shards.is_enabled() {
+            self.write(b"NOT SHARDED\r\n");
+            return;
+        }
+        let ranges = shards.ranges();
+        self.write(format!("{}\r\n", ranges.len()).as_bytes());
+        for range in ranges {
+            self.write(format!("{} {} {}\r\n", range.start, range.end, range.address).as_bytes());
+        }
+    }
+
+    fn exec_client_list(&mut self) {
+        let clients = self.queue_table.clients().list();
+        self.write(format!("{}\r\n", clients.len()).as_bytes());
+        for client in clients {
+            self.write(format!("id={} peer={} name={} cmd={} tx={} idle={}\r\n", client.id, client.peer,
+                               client.name.as_ref().map_or("-", |name| name as &str), client.command.unwrap_or("-"),
+                               client.transaction_size, client.idle().as_secs()).as_bytes());
+        }
+    }
+
+    fn exec_transaction_list(&mut self) {
+        let clients: Vec<_> = self.queue_table.clients().list().into_iter().filter(|client| client.transaction.is_some()).collect();
+        self.write(format!("{}\r\n", clients.len()).as_bytes());
+        for client in clients {
+            let transaction = client.transaction.unwrap();
+            let pops: Vec<String> = transaction.pops.iter().map(|&(ref queue_name, count)| format!("{}:{}", queue_name, count)).collect();
+            self.write(format!("client={} peer={} name={} began={} age={} pushes={} pops={}\r\n", client.id, client.peer,
+                               client.name.as_ref().map_or("-", |name| name as &str), transaction.began_at,
+                               transaction.began.elapsed().as_secs(), transaction.pushes,
+                               if pops.is_empty() { "-".to_string() } else { pops.join(",") }).as_bytes());
+        }
+    }
+
+    fn process_message(&mut self, message: Vec<u8>) {
+        let started = Instant::now();
+        let monitors = self.queue_table.monitors().clone();
+        let slowlog = self.queue_table.slowlog().clone();
+        let text = if monitors.is_active() || slowlog.is_enabled() {
+            Some(describe_message(&message))
+        } else {
+            None
+        };
+        match Command::parse(message) {
+            Ok(cmd) => {
+                let name = cmd.name();
+                {
+                    let mut info = self.info.lock().unwrap();
+                    info.command = Some(name);
+                    info.last_active = started;
+                }
+                let text = match cmd {
+                    Command::Auth(..) => text.map(|_| "AUTH (redacted)".to_string()),
+                    _ => text
+                };
+                if monitors.is_active() {
+                    if let Some(ref text) = text {
+                        monitors.publish(&format!("{} [{} {}] {}", unix_time(), self.id, self.peer, text));
                     }
                 }
+                self.dispatch(cmd);
+                let elapsed = started.elapsed();
+                if let Some(text) = text {
+                    slowlog.record(elapsed, self.id, &self.peer, text);
+                }
+                if logging::trace_commands() {
+                    let latency_us = elapsed.as_micros() as u64;
+                    info!(client = self.id, command = name, latency_us = latency_us; "command");
+                }
             }
-            Err(_) => {
-                true
+            Err(message) => {
+                warn!(client = self.id, peer:% = self.peer, error:% = message; "could not parse command");
+                self.write(message.as_bytes());
+                self.write(b"\r\n");
+            }
+        }
+    }
+
+    fn dispatch(&mut self, cmd: Command) {
+        match cmd {
+            Command::Auth(user, password) => {
+                self.exec_auth(user, password);
+            }
+            Command::Quit => {
+                self.exec_cmd(Command::Quit);
+            }
+            _ if !self.is_authenticated() => {
+                self.write(b"NOT AUTHENTICATED\r\n");
+            }
+            Command::Commit | Command::Abort(_) if self.aborted => {
+                self.aborted = false;
+                self.write(b"TRANSACTION ABORTED\r\n");
+            }
+            _ if self.aborted => {
+                self.write(b"TRANSACTION ABORTED\r\n");
+            }
+            ref cmd if !self.is_in_local_slot(cmd) => {
+            }
+            ref cmd if !cmd.is_read_only() && self.queue_table.replication().is_follower() => {
+                self.write(b"READONLY\r\n");
+            }
+            ref cmd if !cmd.is_read_only() && !self.queue_table.cluster().accepts_writes() => {
+                let redirect = self.queue_table.cluster().redirect();
+                self.write(format!("{}\r\n", redirect).as_bytes());
+            }
+            cmd => {
+                if self.is_in_transaction() {
+                    self.exec_cmd_in_transaction(cmd)
+                } else {
+                    self.exec_cmd(cmd)
+                }
             }
         }
     }
 
-    fn is_in_transaction(&self) -> bool {
-        self.uncommitted_cmds.len() > 0
+    // Answers MOVED for names held by another shard and CROSSSLOT for names
+    // outside the slot of the command or the transaction
+    fn is_in_local_slot(&mut self, cmd: &Command) -> bool {
+        let shards = self.queue_table.shards().clone();
+        if !shards.is_enabled() {
+            return true;
+        }
+        let mut slot = if self.is_in_transaction() { self.transaction_slot } else { None };
+        for name in cmd.names() {
+            let name_slot = shards::slot(name);
+            if slot.is_some_and(|slot| slot != name_slot) {
+                self.write(b"CROSSSLOT\r\n");
+                return false;
+            }
+            slot = Some(name_slot);
+        }
+        if let Some(slot) = slot {
+            if let Some(owner) = shards.owner(slot) {
+                self.write(format!("MOVED {} {}\r\n", slot, owner).as_bytes());
+                return false;
+            }
+            if self.is_in_transaction() {
+                self.transaction_slot = Some(slot);
+            }
+        }
+        true
+    }
+
+    fn is_authenticated(&self) -> bool {
+        !self.users.is_enabled() || self.user.is_some()
+    }
+
+    fn is_allowed(&self, permissions: &[(Permission, &str)]) -> bool {
+        match self.user {
+            Some(ref user) => permissions.iter().all(|&(permission, name)| self.users.allows(user, permission, name)),
+            // Without authentication there is no one to check
+            None => true
+        }
+    }
+
+    fn exec_auth(&mut self, user: UserName, password: String) {
+        if !self.users.is_enabled() {
+            self.write(b"NO USERS CONFIGURED\r\n");
+            return;
+        }
+        match self.verifier {
+            Some(ref verifier) => {
+                let result = verifier.verify(&self.users, &user, &password);
+                self.blocked = Some(Blocked::Auth(user, result));
+            }
+            None => {
+                let valid = self.users.authenticate(&user, &password);
+                self.auth_checked(user, valid);
+            }
+        }
+    }
+
+    fn auth_checked(&mut self, user: UserName, valid: bool) {
+        if valid {
+            self.user = Some(user);
+            self.write(b"SUCCESS\r\n");
+        } else {
+            self.write(b"INVALID USER OR PASSWORD\r\n");
+            self.failed_auth += 1;
+            if self.failed_auth >= MAX_FAILED_AUTH {
+                warn!(client = self.id, peer:% = self.peer, user = user.as_str(); "too many failed AUTH attempts");
+                self.close();
+            }
+        }
     }
 
     fn write(&mut self, buf: &[u8]) {
-        let _ = self.writer.write(buf);
+        self.output.extend_from_slice(buf);
     }
 
-    fn flush(&mut self) {
-        let _ = self.writer.flush();
+    // Built before the data is moved into a queue, None when there are no followers, log or cluster
+    fn replicated<F: FnOnce() -> Operation>(&self, operation: F) -> Option<Operation> {
+        if self.queue_table.replication().is_active() || self.queue_table.persistence().is_enabled() || self.queue_table.cluster().is_enabled() {
+            Some(operation())
+        } else {
+            None
+        }
     }
 
-    fn read_message(&mut self) -> Result<Vec<u8>,()> {
-        let mut buffer = Vec::new();
-        let result = self.reader.read_until(b';', &mut buffer);
+    fn publish(&mut self, operation: Option<Operation>) {
+        if let Some(operation) = operation {
+            self.queue_table.replication().publish(&operation);
+            self.queue_table.persistence().append(&operation);
+            if let Some(entry) = self.queue_table.cluster().propose(&operation) {
+                self.proposed = Some(entry);
+            }
+        }
+    }
 
-        if result.is_err() {
-            return Err(());
+    // Replies once concern.replicas followers applied everything published so far and,
+    // in a cluster, once a majority has the last entry this connection proposed.
+    // Otherwise the client gets REPLICATION TIMEOUT, the write itself stays.
+    fn reply_when_replicated(&mut self, concern: WriteConcern, reply: Vec<u8>) {
+        let entry = self.proposed.take();
+        if concern.replicas == 0 && entry.is_none() {
+            self.write(&reply);
+            return;
         }
+        let timeout = if entry.is_some() {
+            concern.timeout.max(Duration::from_millis(cluster::COMMIT_TIMEOUT))
+        } else {
+            concern.timeout
+        };
+        let wait = ReplicationWait {
+            sequence: self.queue_table.replication().last_sequence(),
+            replicas: concern.replicas,
+            entry,
+            deadline: Instant::now() + timeout
+        };
+        self.wait_for_replicas(wait, reply);
+    }
 
-        // EOF
-        if buffer.pop() != Some(b';') {
-            return Err(());
+    fn wait_for_replicas(&mut self, wait: ReplicationWait, reply: Vec<u8>) {
+        let committed = match wait.entry {
+            Some((index, term)) => self.queue_table.cluster().entry_state(index, term),
+            None => EntryState::Committed
+        };
+        if committed == EntryState::Lost {
+            warn!(client = self.id; "write lost when the cluster leader changed");
+            self.write(b"NOT COMMITTED\r\n");
+        } else if committed == EntryState::Committed && self.queue_table.replication().acked(wait.sequence) >= wait.replicas {
+            self.write(&reply);
+        } else if Instant::now() >= wait.deadline {
+            warn!(client = self.id, sequence = wait.sequence, replicas = wait.replicas; "write not acknowledged by followers in time");
+            self.write(b"REPLICATION TIMEOUT\r\n");
+        } else {
+            self.blocked = Some(Blocked::Replication(wait, reply));
         }
+    }
 
-        return Ok(buffer);
+    // The snapshot and the feed are taken on the event loop thread so no operation falls between them
+    fn exec_sync(&mut self) {
+        if self.replica.is_some() {
+            self.write(b"ALREADY SYNCING\r\n");
+            return;
+        }
+        info!(client = self.id, peer:% = self.peer; "follower syncing");
+        self.write(b"SUCCESS\r\n");
+        for operation in replication::snapshot(&self.queue_table) {
+            self.write(operation.encode().as_bytes());
+        }
+        self.replica = Some(self.queue_table.replication().subscribe(self.id));
     }
 
-    fn exec_pop(&mut self, queue_name: QueueName) -> Result<(String),()> {
+    // Items popped inside a transaction hold their message group until commit or rollback
+    fn exec_pop(&mut self, queue_name: QueueName, hold: bool) -> Result<Item,()> {
         match self.queue_table.get_queue(&queue_name) {
             Some(queue)  => {
-                match queue.pop_front() {
-                    Some(data) => {
-                        self.write(format!("{}\r\n", data).as_bytes());
-                        Ok(data)
+                let item = take_item(&queue, hold);
+                self.queue_table.publish_removed(&queue_name, &queue);
+                match item {
+                    Some(item) => {
+                        self.write(format!("{}\r\n", item.data).as_bytes());
+                        Ok(item)
                     }
                     None => {
                         self.write(b"NO DATA\r\n");
@@ -110,88 +763,337 @@ impl <'a>Connection<'a> {
         }
     }
 
-    fn exec_blocking_pop(&mut self, queue_name: QueueName) -> String {
-        let queue = self.queue_table.get_or_create_queue(queue_name);
-        loop {
-            match queue.pop_front() {
-                Some(data) => {
-                    return data;
+    fn exec_blocking_pop(&mut self, queue_name: QueueName, since: Instant, deadline: Option<Instant>, attempts: bool) {
+        let queue = self.queue_table.get_or_create_queue(queue_name.clone());
+        let hold = self.is_in_transaction();
+        let item = take_item(&queue, hold);
+        self.queue_table.publish_removed(&queue_name, &queue);
+        match item {
+            Some(item) => {
+                self.queue_table.stats().record_bpop_wait(since.elapsed());
+                if attempts {
+                    self.write(format!("{} {}\r\n", item.rollbacks + 1, item.data).as_bytes());
+                } else {
+                    self.write(format!("{}\r\n", item.data).as_bytes());
                 }
-                None => {
+                if hold {
+                    self.buffer_cmd(UncommittedCommand::Pop(item, queue_name));
+                } else {
+                    self.publish(self.replicated(|| Operation::remove(&queue_name, &item)));
+                }
+            }
+            None if deadline.is_some_and(|deadline| Instant::now() >= deadline) => {
+                self.write(b"NO DATA\r\n");
+            }
+            None => {
+                self.blocked = Some(Blocked::Pop(queue_name, since, deadline, attempts));
+            }
+        }
+    }
+
+    fn exec_blocking_push(&mut self, item: Item, queue_name: QueueName) {
+        let queue = self.queue_table.get_or_create_queue(queue_name.clone());
+        let result = queue.push_item(item.clone());
+        self.queue_table.publish_removed(&queue_name, &queue);
+        match result {
+            Ok(()) => {
+                self.publish(self.replicated(|| Operation::push(&queue_name, &item)));
+                let concern = self.write_concern.combine(&queue.write_concern());
+                self.reply_when_replicated(concern, b"SUCCESS\r\n".to_vec());
+            }
+            Err(PushError::WouldBlock) => {
+                self.blocked = Some(Blocked::Push(item, queue_name));
+            }
+            Err(error) => {
+                self.write(format!("{}\r\n", push_error_message(&error)).as_bytes());
+            }
+        }
+    }
+
+    fn exec_stream_read(&mut self, stream_name: QueueName, group: GroupName, count: usize) {
+        match self.queue_table.get_stream(&stream_name) {
+            Some(stream) => {
+                let entries = stream.read(&group, count);
+                if entries.is_empty() {
+                    self.write(b"NO DATA\r\n");
+                    return;
                 }
+                self.write(format!("{}\r\n", entries.len()).as_bytes());
+                for (offset, data) in entries {
+                    self.write(format!("{} {}\r\n", offset, data).as_bytes());
+                }
+            }
+            None => {
+                self.write(b"NO SUCH STREAM\r\n");
             }
-            thread::sleep(Duration::from_millis(BLOCKING_POP_POLLING_FREQ));
         }
     }
 
-    fn exec_cmd(&mut self, cmd: Command) -> bool {
+    fn exec_cmd(&mut self, cmd: Command) {
+        if !self.is_allowed(&cmd.required_permissions()) {
+            self.write(b"NOT ALLOWED\r\n");
+            return;
+        }
         match cmd {
-            Command::Push(value, queue_name) => {
-                exec_push(value, &self.queue_table, queue_name);
-                self.write(b"SUCCESS\r\n");
+            Command::Push(value, queue_name, group, ttl) => {
+                self.exec_blocking_push(new_item(value, group, ttl), queue_name);
             }
             Command::Pop(queue_name) => {
-                let _ = self.exec_pop(queue_name);
+                if let Ok(item) = self.exec_pop(queue_name.clone(), false) {
+                    self.publish(self.replicated(|| Operation::remove(&queue_name, &item)));
+                }
+            }
+            Command::BlockingPop(queue_name, timeout, attempts) => {
+                let now = Instant::now();
+                self.exec_blocking_pop(queue_name, now, timeout.map(|timeout| now + timeout), attempts);
             }
-            Command::BlockingPop(queue_name) => {
-                let data = self.exec_blocking_pop(queue_name.clone());
-                self.write(format!("{}\r\n", data).as_bytes());
+            Command::Auth(user, password) => {
+                self.exec_auth(user, password);
             }
             Command::Quit => {
                 self.write(b"Bye bye");
-                return true;
+                self.close();
             }
             Command::Begin => {
+                debug!(client = self.id; "transaction begun");
                 self.uncommitted_cmds.push(UncommittedCommand::Begin);
             }
-            Command::Abort => {
+            Command::Abort(_) => {
                 self.write(b"Not in transaction\r\n");
             }
             Command::Commit => {
                 self.write(b"Not in transaction\r\n");
             }
+            Command::StreamPush(value, stream_name) => {
+                let operation = self.replicated(|| Operation::StreamAppend(stream_name.clone(), value.clone()));
+                match self.queue_table.get_or_create_stream(stream_name).append(value) {
+                    Ok(offset) => {
+                        self.publish(operation);
+                        let concern = self.write_concern;
+                        self.reply_when_replicated(concern, format!("{}\r\n", offset).into_bytes());
+                    }
+                    Err(_) => {
+                        self.write(b"OUT OF MEMORY\r\n");
+                    }
+                }
+            }
+            Command::StreamRead(stream_name, group, count) => {
+                self.exec_stream_read(stream_name, group, count);
+            }
+            Command::StreamCommit(stream_name, group, offset) => {
+                let operation = self.replicated(|| Operation::StreamCommit(stream_name.clone(), group.clone(), offset));
+                match exec_stream_commit(&self.queue_table, stream_name, group, offset) {
+                    Ok(()) => {
+                        self.publish(operation);
+                        self.write(b"SUCCESS\r\n");
+                    }
+                    Err(message) => {
+                        self.write(format!("{}\r\n", message).as_bytes());
+                    }
+                }
+            }
+            Command::StreamRetain(stream_name, retention) => {
+                let operation = self.replicated(|| Operation::StreamRetain(stream_name.clone(), retention.clone()));
+                self.queue_table.get_or_create_stream(stream_name).set_retention(retention);
+                self.publish(operation);
+                self.write(b"SUCCESS\r\n");
+            }
+            Command::Limit(queue_name, limits) => {
+                let operation = self.replicated(|| Operation::Limit(queue_name.clone(), limits.clone()));
+                self.queue_table.get_or_create_queue(queue_name).set_limits(limits);
+                self.publish(operation);
+                self.write(b"SUCCESS\r\n");
+            }
+            Command::Info => {
+                self.exec_info();
+            }
+            Command::SlowLog(count) => {
+                let entries = self.queue_table.slowlog().entries(count);
+                self.write(format!("{}\r\n", entries.len()).as_bytes());
+                for entry in entries {
+                    self.write(format!("{} {} {} {} {} {}\r\n", entry.id, entry.timestamp, entry.duration.as_micros(),
+                                       entry.client, entry.peer, entry.command).as_bytes());
+                }
+            }
+            Command::SlowLogReset => {
+                self.queue_table.slowlog().reset();
+                self.write(b"SUCCESS\r\n");
+            }
+            Command::Monitor => {
+                if self.monitor.is_none() {
+                    self.monitor = Some(self.queue_table.monitors().subscribe(self.id));
+                }
+                self.write(b"SUCCESS\r\n");
+            }
+            Command::ClientList => {
+                self.exec_client_list();
+            }
+            Command::ClientKill(id) => {
+                if self.queue_table.clients().kill(id) {
+                    self.write(b"SUCCESS\r\n");
+                } else {
+                    self.write(b"NO SUCH CLIENT\r\n");
+                }
+            }
+            Command::TransactionList => {
+                self.exec_transaction_list();
+            }
+            Command::TransactionAbort(id) => {
+                if self.queue_table.clients().abort_transaction(id) {
+                    self.write(b"SUCCESS\r\n");
+                } else {
+                    self.write(b"NO SUCH TRANSACTION\r\n");
+                }
+            }
+            Command::ClientSetName(name) => {
+                self.info.lock().unwrap().name = Some(name);
+                self.write(b"SUCCESS\r\n");
+            }
+            Command::Memory => {
+                let memory = self.queue_table.memory().clone();
+                self.write(format!("used_memory:{} maxmemory:{}\r\n", memory.used(), memory.max()).as_bytes());
+            }
+            Command::MaxMemory(max) => {
+                self.queue_table.memory().set_max(max);
+                self.write(b"SUCCESS\r\n");
+            }
+            Command::DeadLetter(queue_name, dead_letter_name) => {
+                let operation = self.replicated(|| Operation::DeadLetter(queue_name.clone(), dead_letter_name.clone()));
+                self.queue_table.set_dead_letter(queue_name, dead_letter_name);
+                self.publish(operation);
+                self.write(b"SUCCESS\r\n");
+            }
+            Command::Sync => {
+                self.exec_sync();
+            }
+            // Not answered, the connection only carries operations to the follower
+            Command::Ack(sequence) => {
+                if self.replica.is_some() {
+                    self.queue_table.replication().ack(self.id, sequence);
+                } else {
+                    self.write(b"NOT SYNCING\r\n");
+                }
+            }
+            Command::ClusterSlots => {
+                self.exec_cluster_slots();
+            }
+            Command::ClusterKeySlot(name) => {
+                self.write(format!("{}\r\n", shards::slot(&name)).as_bytes());
+            }
+            // Not answered, replies go back as messages of their own
+            Command::Raft(message) => {
+                self.queue_table.cluster().receive(message);
+            }
+            Command::WriteConcern(concern) => {
+                self.write_concern = concern;
+                self.write(b"SUCCESS\r\n");
+            }
+            Command::QueueWriteConcern(queue_name, concern) => {
+                let operation = self.replicated(|| Operation::WriteConcern(queue_name.clone(), concern));
+                self.queue_table.get_or_create_queue(queue_name).set_write_concern(concern);
+                self.publish(operation);
+                self.write(b"SUCCESS\r\n");
+            }
+            Command::Promote => {
+                if self.queue_table.replication().promote() {
+                    warn!(client = self.id; "promoted to leader");
+                    self.write(b"SUCCESS\r\n");
+                } else {
+                    self.write(b"NOT A FOLLOWER\r\n");
+                }
+            }
         };
-        false
     }
 
-    fn exec_cmd_in_transaction(&mut self, cmd: Command) -> bool {
+    // Data held by a transaction counts towards memory until commit or rollback.
+    // Popped items were already accepted so they are never refused.
+    fn buffer_cmd(&mut self, cmd: UncommittedCommand) {
+        let reserved = match cmd {
+            UncommittedCommand::Pop(..) => {
+                self.queue_table.memory().force_reserve(cmd.bytes());
+                Ok(())
+            }
+            _ => {
+                self.queue_table.memory().reserve(cmd.bytes())
+            }
+        };
+        match reserved {
+            Ok(()) => {
+                self.uncommitted_cmds.push(cmd);
+            }
+            Err(_) => {
+                self.write(b"OUT OF MEMORY\r\n");
+            }
+        }
+    }
+
+    fn exec_cmd_in_transaction(&mut self, cmd: Command) {
+        if !self.is_allowed(&cmd.required_permissions()) {
+            self.write(b"NOT ALLOWED\r\n");
+            return;
+        }
         match cmd {
-            Command::Push(value, queue_name) => {
-                self.uncommitted_cmds.push(UncommittedCommand::Push(value, queue_name));
+            Command::Push(value, queue_name, group, ttl) => {
+                self.buffer_cmd(UncommittedCommand::Push(value, queue_name, group, ttl));
             }
             Command::Pop(queue_name) => {
-                let data = self.exec_pop(queue_name.clone());
-                if data.is_ok() {
-                    self.uncommitted_cmds.push(UncommittedCommand::Pop(data.unwrap(), queue_name));
+                if let Ok(item) = self.exec_pop(queue_name.clone(), true) {
+                    self.buffer_cmd(UncommittedCommand::Pop(item, queue_name));
                 }
             }
-            Command::BlockingPop(queue_name) => {
-                let data = self.exec_blocking_pop(queue_name.clone());
-                self.write(format!("{}\r\n", data).as_bytes());
-                self.uncommitted_cmds.push(UncommittedCommand::Pop(data, queue_name));
-            }
-            Command::Quit => {
-                self.write(b"Bye bye");
-                return true;
-            }
             Command::Begin => {
                 self.write(b"Already in transaction\r\n");
             }
-            Command::Abort => {
-                self.rollback();
+            Command::Abort(delay) => {
+                self.rollback(delay);
             }
             Command::Commit => {
                 self.commit();
             }
+            Command::StreamPush(value, stream_name) => {
+                self.buffer_cmd(UncommittedCommand::StreamPush(value, stream_name));
+            }
+            // Refused when sent if the stream doesn't have the offset, COMMIT checks it again
+            Command::StreamCommit(stream_name, group, offset) => {
+                match check_stream_commit(&self.queue_table, &stream_name, offset) {
+                    Ok(()) => {
+                        self.uncommitted_cmds.push(UncommittedCommand::StreamCommit(stream_name, group, offset));
+                    }
+                    Err(message) => {
+                        self.write(format!("{}\r\n", message).as_bytes());
+                    }
+                }
+            }
+            Command::StreamRetain(stream_name, retention) => {
+                self.uncommitted_cmds.push(UncommittedCommand::StreamRetain(stream_name, retention));
+            }
+            // Everything else behaves the same inside a transaction
+            cmd => {
+                self.exec_cmd(cmd);
+            }
         };
-        false
     }
 
-    fn rollback(&mut self) {
+    // Popped items are handed out again after the delay, if any
+    fn rollback(&mut self, delay: Option<Duration>) {
+        self.transaction_slot = None;
+        if self.is_in_transaction() {
+            let (pushes, pops) = count_cmds(&self.uncommitted_cmds);
+            info!(client = self.id, pushes = pushes, pops = pops; "transaction rolled back");
+            self.queue_table.stats().record_abort();
+        }
         for cmd in self.uncommitted_cmds.drain(..) {
+            self.queue_table.memory().release(cmd.bytes());
             match cmd {
-                UncommittedCommand::Pop(value, queue_name) => {
-                    exec_push(value, &self.queue_table, queue_name);
+                UncommittedCommand::Pop(item, queue_name) => {
+                    match delay {
+                        Some(delay) if !delay.is_zero() => {
+                            self.queue_table.requeue_after(queue_name, item, delay);
+                        }
+                        _ => {
+                            exec_unpop(item, &self.queue_table, queue_name);
+                        }
+                    }
                 },
                 _ => {
                 }
@@ -200,21 +1102,248 @@ impl <'a>Connection<'a> {
     }
 
     fn commit(&mut self) {
-        for cmd in self.uncommitted_cmds.drain(..) {
+        self.transaction_slot = None;
+        let (pushes, pops) = count_cmds(&self.uncommitted_cmds);
+        debug!(client = self.id, pushes = pushes, pops = pops; "transaction committing");
+        let mut concern = self.write_concern;
+        for cmd in self.uncommitted_cmds.iter() {
+            match *cmd {
+                UncommittedCommand::Push(_, ref queue_name, _, _) | UncommittedCommand::Pop(_, ref queue_name) => {
+                    if let Some(queue) = self.queue_table.get_queue(queue_name) {
+                        concern = concern.combine(&queue.write_concern());
+                    }
+                }
+                _ => {
+                }
+            }
+        }
+        let cmds = self.uncommitted_cmds.drain(..).collect();
+        self.continue_commit(cmds, concern);
+    }
+
+    // All or nothing: if any command can't be applied the whole transaction is rolled back
+    // and the reason is the reply. A push to a full queue with the block policy parks the
+    // commit until every push fits.
+    fn continue_commit(&mut self, cmds: VecDeque<UncommittedCommand>, concern: WriteConcern) {
+        // Checked again in case the client authenticated as someone else mid transaction
+        let denied = cmds.iter().flat_map(|cmd| cmd.required_permissions())
+            .find(|&permission| !self.is_allowed(&[permission]))
+            .map(|(_, name)| name.to_string());
+        let refused = match denied {
+            Some(name) => Some(("NOT ALLOWED".to_string(), name)),
+            None => {
+                match self.check_room(&cmds) {
+                    Ok(()) => self.check_stream_commits(&cmds).err(),
+                    Err((PushError::WouldBlock, name)) => {
+                        self.blocked = Some(Blocked::Commit(cmds, concern, name));
+                        return;
+                    }
+                    Err((error, name)) => Some((push_error_message(&error).to_string(), name))
+                }
+            }
+        };
+        if let Some((message, name)) = refused {
+            info!(client = self.id, reason = message.as_str(), name = name.as_str(); "transaction refused on commit");
+            self.uncommitted_cmds.extend(cmds);
+            self.rollback(None);
+            self.write(format!("{}: {}\r\n", message, name).as_bytes());
+            return;
+        }
+        for cmd in cmds {
+            self.queue_table.memory().release(cmd.bytes());
             match cmd {
-                UncommittedCommand::Push(value, queue_name) => {
-                    exec_push(value, &self.queue_table, queue_name);
+                UncommittedCommand::Push(value, queue_name, group, ttl) => {
+                    let item = new_item(value, group, ttl);
+                    let operation = self.replicated(|| Operation::push(&queue_name, &item));
+                    let queue = self.queue_table.get_or_create_queue(queue_name.clone());
+                    let result = queue.push_item(item);
+                    self.queue_table.publish_removed(&queue_name, &queue);
+                    match result {
+                        Ok(()) => {
+                            self.publish(operation);
+                        }
+                        Err(error) => {
+                            error!(client = self.id, queue = queue_name.as_str(), error:? = error; "push refused after the commit checked it");
+                        }
+                    }
+                }
+                UncommittedCommand::Pop(item, queue_name) => {
+                    self.queue_table.get_or_create_queue(queue_name.clone()).commit_held(&item);
+                    self.publish(self.replicated(|| Operation::remove(&queue_name, &item)));
+                }
+                UncommittedCommand::StreamPush(value, stream_name) => {
+                    let operation = self.replicated(|| Operation::StreamAppend(stream_name.clone(), value.clone()));
+                    if self.queue_table.get_or_create_stream(stream_name.clone()).append(value).is_err() {
+                        error!(client = self.id, stream = stream_name.as_str(); "append refused after the commit checked it");
+                    } else {
+                        self.publish(operation);
+                    }
+                }
+                UncommittedCommand::StreamCommit(stream_name, group, offset) => {
+                    let operation = self.replicated(|| Operation::StreamCommit(stream_name.clone(), group.clone(), offset));
+                    match exec_stream_commit(&self.queue_table, stream_name.clone(), group, offset) {
+                        Ok(()) => {
+                            self.publish(operation);
+                        }
+                        Err(message) => {
+                            error!(client = self.id, stream = stream_name.as_str(), error = message.as_str(); "stream commit refused after the commit checked it");
+                        }
+                    }
+                }
+                UncommittedCommand::StreamRetain(stream_name, retention) => {
+                    let operation = self.replicated(|| Operation::StreamRetain(stream_name.clone(), retention.clone()));
+                    self.queue_table.get_or_create_stream(stream_name).set_retention(retention);
+                    self.publish(operation);
+                }
+                _ => {
+                }
+            }
+        }
+        self.queue_table.stats().record_commit();
+        debug!(client = self.id; "transaction committed");
+        self.reply_when_replicated(concern, Vec::new());
+    }
+
+    // Whether every stream the transaction commits an offset of still has it,
+    // otherwise why not and the stream
+    fn check_stream_commits(&self, cmds: &VecDeque<UncommittedCommand>) -> Result<(), (String, QueueName)> {
+        for cmd in cmds.iter() {
+            if let UncommittedCommand::StreamCommit(ref stream_name, _, offset) = *cmd {
+                if let Err(message) = check_stream_commit(&self.queue_table, stream_name, offset) {
+                    return Err((message, stream_name.clone()));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Whether every push fits once the transaction's buffered data is released,
+    // otherwise why not and the queue or stream it is about
+    fn check_room(&self, cmds: &VecDeque<UncommittedCommand>) -> Result<(), (PushError, QueueName)> {
+        let mut pushes: Vec<(&QueueName, Vec<usize>)> = Vec::new();
+        let mut first_push = None;
+        let mut buffered = 0;
+        let mut pushed = 0;
+        for cmd in cmds.iter() {
+            buffered += cmd.bytes();
+            match *cmd {
+                UncommittedCommand::Push(ref value, ref queue_name, _, _) => {
+                    pushed += value.len();
+                    first_push = first_push.or(Some(queue_name));
+                    match pushes.iter_mut().find(|&&mut (name, _)| name == queue_name) {
+                        Some(&mut (_, ref mut sizes)) => sizes.push(value.len()),
+                        None => pushes.push((queue_name, vec![value.len()]))
+                    }
+                }
+                UncommittedCommand::StreamPush(ref value, ref stream_name) => {
+                    pushed += value.len();
+                    first_push = first_push.or(Some(stream_name));
                 }
                 _ => {
                 }
             }
         }
+        for (queue_name, sizes) in pushes {
+            self.queue_table.get_or_create_queue(queue_name.clone()).check_room(&sizes).map_err(|error| (error, queue_name.clone()))?;
+        }
+        let memory = self.queue_table.memory();
+        match first_push {
+            Some(name) if memory.max() > 0 && memory.used().saturating_sub(buffered) + pushed > memory.max() => {
+                Err((PushError::OutOfMemory, name.clone()))
+            }
+            _ => Ok(())
+        }
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        if self.monitor.is_some() {
+            self.queue_table.monitors().unsubscribe(self.id);
+        }
+        if self.replica.is_some() {
+            self.queue_table.replication().unsubscribe(self.id);
+        }
+        self.queue_table.clients().unregister(self.id);
+        let stats = self.queue_table.stats();
+        stats.client_disconnected();
+        if self.counted_in_transaction {
+            stats.set_in_transaction(false);
+        }
+        if self.counted_blocked {
+            stats.set_blocked(false);
+        }
+    }
+}
+
+// The ttl counts from when the push is applied, i.e. from COMMIT inside a transaction
+fn new_item(value: String, group: Option<MessageGroup>, ttl: Option<Duration>) -> Item {
+    let expires_at = ttl.map(|ttl| Instant::now() + ttl);
+    Item::new(value, group, expires_at)
+}
+
+// Long messages are cut short, it's only for SLOWLOG and MONITOR
+fn describe_message(message: &[u8]) -> String {
+    if message.len() > MAX_DESCRIBED_LEN {
+        format!("{}... ({} bytes)", String::from_utf8_lossy(&message[..MAX_DESCRIBED_LEN]), message.len())
+    } else {
+        String::from_utf8_lossy(message).into_owned()
     }
 }
 
-// exec_push can't be moved onto Connection
-// commit and rollback borrow self as mutable once already
-fn exec_push(value: String, queue_table: &QueueTable, queue_name: QueueName) {
-    let queue = queue_table.get_or_create_queue(queue_name);
-    queue.push_back(value.to_string());
+fn unix_time() -> String {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    format!("{}.{:06}", now.as_secs(), now.subsec_micros())
+}
+
+// (pushes, pops) buffered by a transaction
+fn count_cmds(cmds: &[UncommittedCommand]) -> (usize, usize) {
+    cmds.iter().fold((0, 0), |(pushes, pops), cmd| {
+        match *cmd {
+            UncommittedCommand::Push(..) | UncommittedCommand::StreamPush(..) => (pushes + 1, pops),
+            UncommittedCommand::Pop(..) => (pushes, pops + 1),
+            _ => (pushes, pops)
+        }
+    })
+}
+
+fn push_error_message(error: &PushError) -> &'static str {
+    match *error {
+        PushError::OutOfMemory => "OUT OF MEMORY",
+        _ => "QUEUE FULL"
+    }
+}
+
+fn exec_unpop(item: Item, queue_table: &QueueTable, queue_name: QueueName) {
+    queue_table.get_or_create_queue(queue_name).requeue(item);
+}
+
+fn take_item(queue: &Queue, hold: bool) -> Option<Item> {
+    if hold {
+        queue.pop_front_held()
+    } else {
+        queue.pop_front_item()
+    }
+}
+
+fn check_stream_commit(queue_table: &QueueTable, stream_name: &QueueName, offset: Offset) -> Result<(),String> {
+    match queue_table.get_stream(stream_name) {
+        Some(stream) => {
+            stream.check_offset(offset)
+        }
+        None => {
+            Err("NO SUCH STREAM".to_string())
+        }
+    }
+}
+
+fn exec_stream_commit(queue_table: &QueueTable, stream_name: QueueName, group: GroupName, offset: Offset) -> Result<(),String> {
+    match queue_table.get_stream(&stream_name) {
+        Some(stream) => {
+            stream.commit(group, offset)
+        }
+        None => {
+            Err("NO SUCH STREAM".to_string())
+        }
+    }
 }
@@ -0,0 +1,594 @@
+use std::io;
+use std::io::{Read,Write};
+use std::mem;
+use std::collections::{BTreeSet,HashMap,VecDeque};
+use std::sync::Arc;
+use std::time::{Duration,Instant};
+
+use mio::{Events,Interest,Poll,Token,Waker};
+use signal_hook::consts::{SIGINT,SIGTERM};
+use signal_hook_mio::v1_0::Signals;
+
+use auth::{Users,Verifier};
+use clients::{ClientAction};
+use connection::{Connection,Status,Wait};
+use net::{Listener,Stream};
+use queue_table::{QueueTable};
+
+const READ_BUFFER_SIZE: usize = 4096;
+// A cluster node wakes up this often to apply entries committed by elections its ticker held
+const CLUSTER_WAKE_FREQ: u64 = 100;
+// After accept fails, e.g. out of file descriptors, connections wait in the backlog this long
+const ACCEPT_BACKOFF: u64 = 100;
+// Expired items are swept on the loop so their removal is published in order with client writes
+const EXPIRY_SWEEP_FREQ: u64 = 1000;
+
+struct Client {
+    stream: Stream,
+    connection: Connection,
+    output: Vec<u8>
+}
+
+// Clients parked on the same thing in the order they blocked. For a queue, its version
+// when they were last retried tells whether a push, pop or rollback happened since.
+struct Waiters {
+    version: Option<u64>,
+    tokens: VecDeque<Token>
+}
+
+// What a parked client waits for, and when it gives up if it ever does
+type Parked = (Wait, Option<Instant>);
+
+// Drives every connection from a single thread.
+// Clients waiting on a BPOP, a blocking PUSH, follower acks or a password check are parked
+// instead of holding a thread, and are only retried once what they wait for may have happened.
+pub struct EventLoop {
+    poll: Poll,
+    // Listener i is registered with Token(i), then the signal token, the waker's token, then clients
+    listeners: Vec<Listener>,
+    signal_token: Token,
+    // Woken by the verifier threads once a password has been checked
+    verifier: Verifier,
+    waker_token: Token,
+    password_checked: bool,
+    signals: Option<Signals>,
+    shutdown_timeout: Duration,
+    shutdown_deadline: Option<Instant>,
+    accept_retry: Option<Instant>,
+    next_sweep: Instant,
+    queue_table: QueueTable,
+    users: Users,
+    clients: HashMap<Token, Client>,
+    // What each parked client waits for and until when, and who waits on each thing
+    parked: HashMap<Token, Parked>,
+    waiters: HashMap<Wait, Waiters>,
+    // Parked clients that give up at some point, e.g. a BPOP with a timeout, soonest first
+    deadlines: BTreeSet<(Instant, Token)>,
+    next_token: usize
+}
+
+impl Client {
+    // Returns false once the client has gone away
+    fn read(&mut self) -> bool {
+        let mut buffer = [0; READ_BUFFER_SIZE];
+        loop {
+            match self.stream.read(&mut buffer) {
+                // The client is done sending but still gets the replies to what it sent
+                Ok(0) => {
+                    self.connection.close();
+                    return true;
+                }
+                Ok(n) => {
+                    if self.connection.feed(&buffer[..n]) == Status::Closed {
+                        return true;
+                    }
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    return true;
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {
+                }
+                Err(_) => {
+                    return false;
+                }
+            }
+        }
+    }
+
+    // Returns false once the client has gone away
+    fn write(&mut self) -> bool {
+        let output = self.connection.take_output();
+        self.output.extend(output);
+        while !self.output.is_empty() {
+            match self.stream.write(&self.output) {
+                Ok(0) => {
+                    return false;
+                }
+                Ok(n) => {
+                    self.output.drain(..n);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    return true;
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {
+                }
+                Err(_) => {
+                    return false;
+                }
+            }
+        }
+        match self.stream.flush() {
+            Ok(()) => true,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => true,
+            Err(_) => false
+        }
+    }
+
+    fn waiting(&self) -> Option<Parked> {
+        self.connection.waiting_for().map(|wait| (wait, self.connection.deadline()))
+    }
+
+    fn interest(&self) -> Interest {
+        if self.output.is_empty() && !self.stream.wants_write() {
+            Interest::READABLE
+        } else {
+            Interest::READABLE | Interest::WRITABLE
+        }
+    }
+}
+
+impl EventLoop {
+    pub fn new(mut listeners: Vec<Listener>, queue_table: &QueueTable, users: &Users) -> io::Result<EventLoop> {
+        let poll = Poll::new()?;
+        for (i, listener) in listeners.iter_mut().enumerate() {
+            poll.registry().register(listener, Token(i), Interest::READABLE)?;
+        }
+        let signal_token = Token(listeners.len());
+        let waker = Arc::new(Waker::new(poll.registry(), Token(signal_token.0 + 1))?);
+        Ok(EventLoop {
+            poll,
+            signal_token,
+            verifier: Verifier::new(move|| {
+                let _ = waker.wake();
+            }),
+            waker_token: Token(signal_token.0 + 1),
+            password_checked: false,
+            next_token: signal_token.0 + 2,
+            listeners,
+            signals: None,
+            shutdown_timeout: Duration::from_secs(0),
+            shutdown_deadline: None,
+            accept_retry: None,
+            next_sweep: Instant::now() + Duration::from_millis(EXPIRY_SWEEP_FREQ),
+            queue_table: queue_table.clone(),
+            users: users.clone(),
+            clients: HashMap::new(),
+            parked: HashMap::new(),
+            waiters: HashMap::new(),
+            deadlines: BTreeSet::new()
+        })
+    }
+
+    // On SIGTERM or SIGINT stop accepting, release blocked clients and give open
+    // transactions timeout to finish before rolling them back. A second signal skips the wait.
+    pub fn shutdown_on_signals(&mut self, timeout: Duration) -> io::Result<()> {
+        let mut signals = Signals::new([SIGTERM, SIGINT])?;
+        self.poll.registry().register(&mut signals, self.signal_token, Interest::READABLE)?;
+        self.signals = Some(signals);
+        self.shutdown_timeout = timeout;
+        Ok(())
+    }
+
+    // Returns once shutdown has finished
+    pub fn run(&mut self) -> io::Result<()> {
+        let mut events = Events::with_capacity(1024);
+        loop {
+            let mut timeout = if self.queue_table.cluster().is_enabled() {
+                Some(Duration::from_millis(CLUSTER_WAKE_FREQ))
+            } else {
+                None
+            };
+            let deadlines = self.shutdown_deadline.into_iter().chain(self.accept_retry).chain(Some(self.next_sweep))
+                .chain(self.deadlines.first().map(|&(deadline, _)| deadline)).chain(self.queue_table.next_requeue());
+            for deadline in deadlines {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                timeout = Some(timeout.map_or(remaining, |timeout| timeout.min(remaining)));
+            }
+            match self.poll.poll(&mut events, timeout) {
+                Ok(()) => {
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {
+                    continue;
+                }
+                Err(e) => {
+                    return Err(e);
+                }
+            }
+            for event in events.iter() {
+                let token = event.token();
+                if token == self.signal_token {
+                    self.signal_received();
+                } else if token.0 < self.signal_token.0 {
+                    // The listeners are gone once shutdown starts, even if this poll still reported them
+                    if self.accept_retry.is_none() && self.shutdown_deadline.is_none() {
+                        self.accept(token.0);
+                    }
+                } else if token == self.waker_token {
+                    self.password_checked = true;
+                } else {
+                    self.client_ready(token);
+                }
+            }
+            if self.accept_retry.is_some_and(|retry| Instant::now() >= retry) {
+                self.accept_retry = None;
+                for listener in 0..self.listeners.len() {
+                    self.accept(listener);
+                }
+            }
+            if Instant::now() >= self.next_sweep {
+                self.queue_table.remove_expired();
+                self.next_sweep = Instant::now() + Duration::from_millis(EXPIRY_SWEEP_FREQ);
+            }
+            self.queue_table.persistence().rewrite_if_needed(&self.queue_table);
+            self.client_actions();
+            let cluster_changed = self.queue_table.cluster().take_dirty();
+            if cluster_changed {
+                self.cluster_work();
+            }
+            self.queue_table.requeue_delayed();
+            self.retry_parked(cluster_changed);
+            // Both flags are taken so neither stays set
+            if self.queue_table.monitors().take_dirty() | self.queue_table.replication().take_dirty() {
+                self.flush_feeds();
+            }
+            if let Some(deadline) = self.shutdown_deadline {
+                if Instant::now() >= deadline {
+                    let tokens: Vec<Token> = self.clients.keys().cloned().collect();
+                    for token in tokens {
+                        self.remove_client(token);
+                    }
+                }
+                if self.clients.is_empty() {
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    fn signal_received(&mut self) {
+        let received = match self.signals {
+            Some(ref mut signals) => signals.pending().count() > 0,
+            None => false
+        };
+        if !received {
+            return;
+        }
+        if self.shutdown_deadline.is_some() {
+            warn!("signal received again, rolling back open transactions now");
+            self.shutdown_deadline = Some(Instant::now());
+            return;
+        }
+        info!(clients = self.clients.len(), timeout_secs = self.shutdown_timeout.as_secs(); "shutting down");
+        self.shutdown_deadline = Some(Instant::now() + self.shutdown_timeout);
+        // Dropping the listeners closes them so new clients are refused straight away
+        for mut listener in self.listeners.drain(..) {
+            let _ = self.poll.registry().deregister(&mut listener);
+        }
+        let tokens: Vec<Token> = self.clients.keys().cloned().collect();
+        for token in tokens {
+            let open = match self.clients.get_mut(&token) {
+                Some(client) => {
+                    client.connection.shutdown();
+                    client.write()
+                }
+                None => {
+                    continue;
+                }
+            };
+            self.update_client(token, open);
+        }
+    }
+
+    // A connection that can't be set up is dropped. Other errors, such as running out of
+    // file descriptors, pause accepting for a while rather than spinning on the same error.
+    fn accept(&mut self, listener: usize) {
+        loop {
+            match self.listeners[listener].accept() {
+                Ok((mut stream, peer)) => {
+                    let token = Token(self.next_token);
+                    self.next_token += 1;
+                    if let Err(error) = self.poll.registry().register(&mut stream, token, Interest::READABLE) {
+                        warn!(peer:% = peer, error:% = error; "could not register connection");
+                        continue;
+                    }
+                    let mut connection = Connection::with_users(&self.queue_table, &self.users);
+                    connection.set_verifier(&self.verifier);
+                    info!(client = connection.id(), peer:% = peer; "connection opened");
+                    connection.set_peer(peer);
+                    self.clients.insert(token, Client {
+                        stream,
+                        connection,
+                        output: Vec::new()
+                    });
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    return;
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::ConnectionAborted || e.kind() == io::ErrorKind::Interrupted => {
+                }
+                Err(e) => {
+                    warn!(error:% = e, backoff_ms = ACCEPT_BACKOFF; "could not accept connection");
+                    self.accept_retry = Some(Instant::now() + Duration::from_millis(ACCEPT_BACKOFF));
+                    return;
+                }
+            }
+        }
+    }
+
+    fn client_ready(&mut self, token: Token) {
+        let open = match self.clients.get_mut(&token) {
+            Some(client) => client.read() && client.write(),
+            None => {
+                return;
+            }
+        };
+        self.update_client(token, open);
+    }
+
+    // Carries out CLIENT KILL and TXABORT for other clients
+    fn client_actions(&mut self) {
+        for (id, action) in self.queue_table.clients().take_actions() {
+            let token = self.clients.iter()
+                .find(|&(_, client)| client.connection.id() == id)
+                .map(|(token, _)| *token);
+            let token = match token {
+                Some(token) => token,
+                None => {
+                    continue;
+                }
+            };
+            match action {
+                ClientAction::Kill => {
+                    info!(client = id; "client killed");
+                    self.remove_client(token);
+                }
+                ClientAction::AbortTransaction => {
+                    let open = match self.clients.get_mut(&token) {
+                        Some(client) => {
+                            client.connection.abort_transaction();
+                            client.write()
+                        }
+                        None => {
+                            continue;
+                        }
+                    };
+                    self.update_client(token, open);
+                }
+            }
+        }
+    }
+
+    // Applies committed cluster log entries. A node that lost leadership first releases
+    // its clients as the queues are rebuilt from the log without its uncommitted writes.
+    fn cluster_work(&mut self) {
+        let cluster = self.queue_table.cluster().clone();
+        if cluster.take_reset() {
+            let tokens: Vec<Token> = self.clients.keys().cloned().collect();
+            for token in tokens {
+                let open = match self.clients.get_mut(&token) {
+                    Some(client) => {
+                        client.connection.leadership_lost();
+                        client.write()
+                    }
+                    None => {
+                        continue;
+                    }
+                };
+                self.update_client(token, open);
+            }
+        }
+        cluster.apply(&self.queue_table);
+    }
+
+    // Retries the clients whose wait may be over. Keeps going until nothing else changed
+    // as a client that continues may unblock others, e.g. by pushing to another queue.
+    fn retry_parked(&mut self, cluster_changed: bool) {
+        self.retry_expired();
+        // Acks are taken even when nobody waits on them so they don't stay set
+        let mut replication = self.queue_table.replication().take_acks() | cluster_changed;
+        let mut auth = mem::replace(&mut self.password_checked, false);
+        loop {
+            let mut retried = false;
+            let waits: Vec<Wait> = self.waiters.keys().cloned().collect();
+            for wait in waits {
+                let ready = match wait {
+                    Wait::Items(ref queue_name) | Wait::Room(ref queue_name) => {
+                        let version = self.queue_table.get_queue(queue_name).map(|queue| queue.version());
+                        match self.waiters.get_mut(&wait) {
+                            Some(waiters) if waiters.version != version => {
+                                waiters.version = version;
+                                true
+                            }
+                            _ => false
+                        }
+                    }
+                    Wait::Replication => replication,
+                    Wait::Auth => auth
+                };
+                if ready {
+                    self.retry_waiters(&wait);
+                    retried = true;
+                }
+            }
+            replication = false;
+            auth = false;
+            if !retried {
+                break;
+            }
+        }
+    }
+
+    // Clients blocked on a queue are retried in the order they blocked, stopping at the first
+    // that still has to wait, so an item goes to the longest waiting BPOP without retrying
+    // every other one. Other waits are independent so all of them are retried.
+    fn retry_waiters(&mut self, wait: &Wait) {
+        let in_order = match *wait {
+            Wait::Items(_) | Wait::Room(_) => true,
+            Wait::Replication | Wait::Auth => false
+        };
+        let mut tokens = match self.waiters.get_mut(wait) {
+            Some(waiters) => mem::take(&mut waiters.tokens),
+            None => {
+                return;
+            }
+        };
+        let mut still_waiting = VecDeque::new();
+        while let Some(token) = tokens.pop_front() {
+            let (open, waiting) = match self.clients.get_mut(&token) {
+                Some(client) => {
+                    client.connection.retry_blocked();
+                    (client.write(), client.waiting())
+                }
+                None => {
+                    self.forget(token);
+                    continue;
+                }
+            };
+            let same = open && waiting.is_some() && waiting.as_ref() == self.parked.get(&token);
+            if !same {
+                self.forget(token);
+            }
+            self.update_client(token, open);
+            if same && self.clients.contains_key(&token) {
+                still_waiting.push_back(token);
+                if in_order {
+                    break;
+                }
+            }
+        }
+        // Those not retried keep their place ahead of anyone who blocked in the meantime
+        still_waiting.extend(tokens);
+        let empty = {
+            let waiters = self.waiters.entry(wait.clone()).or_insert_with(|| Waiters { version: None, tokens: VecDeque::new() });
+            still_waiting.extend(waiters.tokens.drain(..));
+            waiters.tokens = still_waiting;
+            waiters.tokens.is_empty()
+        };
+        if empty {
+            self.waiters.remove(wait);
+        }
+    }
+
+    // Clients whose deadline passed are retried on their own, so they give up
+    // even though what they wait for hasn't happened
+    fn retry_expired(&mut self) {
+        let now = Instant::now();
+        let expired: Vec<Token> = self.deadlines.iter()
+            .take_while(|&&(deadline, _)| deadline <= now)
+            .map(|&(_, token)| token)
+            .collect();
+        for token in expired {
+            let open = match self.clients.get_mut(&token) {
+                Some(client) => {
+                    client.connection.retry_blocked();
+                    client.write()
+                }
+                None => {
+                    self.unpark(token);
+                    continue;
+                }
+            };
+            self.update_client(token, open);
+        }
+    }
+
+    // Moves the client to the list of what it now waits for, if that changed
+    fn park(&mut self, token: Token, waiting: Option<Parked>) {
+        if self.parked.get(&token) == waiting.as_ref() {
+            return;
+        }
+        self.unpark(token);
+        if let Some((wait, deadline)) = waiting {
+            let version = match wait {
+                Wait::Items(ref queue_name) | Wait::Room(ref queue_name) => self.queue_table.get_queue(queue_name).map(|queue| queue.version()),
+                Wait::Replication | Wait::Auth => None
+            };
+            self.waiters.entry(wait.clone()).or_insert_with(|| Waiters { version, tokens: VecDeque::new() }).tokens.push_back(token);
+            if let Some(deadline) = deadline {
+                self.deadlines.insert((deadline, token));
+            }
+            self.parked.insert(token, (wait, deadline));
+        }
+    }
+
+    // Drops what the client waited for, for one already taken off its list of waiters
+    fn forget(&mut self, token: Token) -> Option<Wait> {
+        let (wait, deadline) = self.parked.remove(&token)?;
+        if let Some(deadline) = deadline {
+            self.deadlines.remove(&(deadline, token));
+        }
+        Some(wait)
+    }
+
+    fn unpark(&mut self, token: Token) {
+        if let Some(wait) = self.forget(token) {
+            let empty = match self.waiters.get_mut(&wait) {
+                Some(waiters) => {
+                    waiters.tokens.retain(|&parked| parked != token);
+                    waiters.tokens.is_empty()
+                }
+                None => false
+            };
+            if empty {
+                self.waiters.remove(&wait);
+            }
+        }
+    }
+
+    // Monitoring clients and followers are sent what other clients did
+    fn flush_feeds(&mut self) {
+        let tokens: Vec<Token> = self.clients.iter()
+            .filter(|&(_, client)| client.connection.is_monitoring() || client.connection.is_replica())
+            .map(|(token, _)| *token)
+            .collect();
+        for token in tokens {
+            let open = match self.clients.get_mut(&token) {
+                Some(client) => client.write(),
+                None => {
+                    continue;
+                }
+            };
+            self.update_client(token, open);
+        }
+    }
+
+    fn update_client(&mut self, token: Token, open: bool) {
+        let (waiting, finished) = match self.clients.get(&token) {
+            Some(client) => {
+                let finished = client.connection.status() == Status::Closed && client.output.is_empty() && !client.stream.wants_write();
+                (client.waiting(), finished)
+            }
+            None => {
+                return;
+            }
+        };
+        if !open || finished {
+            self.remove_client(token);
+            return;
+        }
+        self.park(token, waiting);
+        if let Some(client) = self.clients.get_mut(&token) {
+            let interest = client.interest();
+            let _ = self.poll.registry().reregister(&mut client.stream, token, interest);
+        }
+    }
+
+    fn remove_client(&mut self, token: Token) {
+        if let Some(mut client) = self.clients.remove(&token) {
+            client.connection.close();
+            info!(client = client.connection.id(), peer:% = client.connection.peer(); "connection closed");
+            let _ = self.poll.registry().deregister(&mut client.stream);
+        }
+        self.unpark(token);
+    }
+}
@@ -1,4 +1,32 @@
+#[macro_use]
+extern crate log;
+extern crate mio;
+extern crate ring;
+extern crate rustls;
+extern crate rustls_pemfile;
+extern crate signal_hook;
+extern crate signal_hook_mio;
+
+pub mod acl;
+pub mod auth;
+pub mod clients;
+pub mod cluster;
 pub mod connection;
 pub mod commands;
+pub mod config;
+pub mod event_loop;
+pub mod logging;
+pub mod memory;
+pub mod metrics;
+pub mod monitor;
+pub mod net;
 pub mod parse_commands;
+pub mod persistence;
 pub mod queue_table;
+pub mod raft;
+pub mod replication;
+pub mod shards;
+pub mod slowlog;
+pub mod stats;
+pub mod stream;
+pub mod tls;
@@ -0,0 +1,180 @@
+use std::io;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool,Ordering};
+use std::time::{SystemTime,UNIX_EPOCH};
+
+use log;
+use log::{Level,LevelFilter,Log,Metadata,Record};
+use log::kv::{Key,Value,VisitSource};
+
+#[derive(PartialEq)]
+#[derive(Debug)]
+#[derive(Clone,Copy)]
+pub enum LogFormat {
+    // 2026-01-02T03:04:05.678Z INFO connection opened client=1 peer=127.0.0.1:4000
+    Text,
+    // {"ts":"2026-01-02T03:04:05.678Z","level":"INFO","msg":"connection opened","client":1,...}
+    Json
+}
+
+static TRACE_COMMANDS: AtomicBool = AtomicBool::new(false);
+
+// Writes every record to stderr with its key-values as fields
+struct Logger {
+    format: LogFormat
+}
+
+pub fn init(level: LevelFilter, format: LogFormat, trace_commands: bool) -> Result<(), String> {
+    TRACE_COMMANDS.store(trace_commands, Ordering::Relaxed);
+    match log::set_boxed_logger(Box::new(Logger { format })) {
+        Ok(()) => {
+            log::set_max_level(level);
+            Ok(())
+        }
+        Err(_) => {
+            Err("logger already initialised".to_string())
+        }
+    }
+}
+
+// Whether every command should be logged with its latency
+pub fn trace_commands() -> bool {
+    TRACE_COMMANDS.load(Ordering::Relaxed)
+}
+
+pub fn parse_level(value: &str) -> Result<LevelFilter, String> {
+    value.parse::<LevelFilter>().map_err(|_| format!("unknown log level {}", value))
+}
+
+pub fn parse_format(value: &str) -> Result<LogFormat, String> {
+    match &value.to_lowercase() as &str {
+        "text" => Ok(LogFormat::Text),
+        "json" => Ok(LogFormat::Json),
+        _      => Err(format!("unknown log format {}", value))
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = format_record(self.format, record, &timestamp());
+        let stderr = io::stderr();
+        let _ = stderr.lock().write_all(line.as_bytes());
+    }
+
+    fn flush(&self) {
+        let _ = io::stderr().flush();
+    }
+}
+
+pub fn format_record(format: LogFormat, record: &Record, timestamp: &str) -> String {
+    let mut fields = Fields(Vec::new());
+    let _ = record.key_values().visit(&mut fields);
+    // Messages from dependencies are told apart by their target
+    if !record.target().starts_with("queue_experiments") {
+        fields.0.insert(0, ("target".to_string(), Field::Text(record.target().to_string())));
+    }
+    match format {
+        LogFormat::Text => {
+            let mut line = format!("{} {} {}", timestamp, level_name(record.level()), record.args());
+            for (key, value) in fields.0 {
+                match value {
+                    Field::Text(ref text) if text.is_empty() || text.contains(char::is_whitespace) || text.contains('"') => {
+                        line.push_str(&format!(" {}={:?}", key, text));
+                    }
+                    Field::Text(text) | Field::Number(text) => {
+                        line.push_str(&format!(" {}={}", key, text));
+                    }
+                }
+            }
+            line.push('\n');
+            line
+        }
+        LogFormat::Json => {
+            let mut line = format!("{{\"ts\":\"{}\",\"level\":\"{}\",\"msg\":{}", timestamp, level_name(record.level()), json_string(&record.args().to_string()));
+            for (key, value) in fields.0 {
+                match value {
+                    Field::Text(text) => line.push_str(&format!(",{}:{}", json_string(&key), json_string(&text))),
+                    Field::Number(number) => line.push_str(&format!(",{}:{}", json_string(&key), number))
+                }
+            }
+            line.push_str("}\n");
+            line
+        }
+    }
+}
+
+enum Field {
+    Text(String),
+    Number(String)
+}
+
+struct Fields(Vec<(String, Field)>);
+
+impl<'kvs> VisitSource<'kvs> for Fields {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), log::kv::Error> {
+        let field = if value.to_u64().is_some() || value.to_i64().is_some() || value.to_f64().is_some() {
+            Field::Number(value.to_string())
+        } else {
+            Field::Text(value.to_string())
+        };
+        self.0.push((key.to_string(), field));
+        Ok(())
+    }
+}
+
+fn level_name(level: Level) -> &'static str {
+    match level {
+        Level::Error => "ERROR",
+        Level::Warn  => "WARN",
+        Level::Info  => "INFO",
+        Level::Debug => "DEBUG",
+        Level::Trace => "TRACE"
+    }
+}
+
+fn json_string(value: &str) -> String {
+    let mut out = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c)
+        }
+    }
+    out.push('"');
+    out
+}
+
+// RFC 3339 in UTC with milliseconds
+fn timestamp() -> String {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = now.as_secs();
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let time = secs % 86400;
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z", year, month, day, time / 3600, time % 3600 / 60, time % 60, now.subsec_millis())
+}
+
+// Days since 1970-01-01 to a date, from http://howardhinnant.github.io/date_algorithms.html
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = if z >= 0 { z } else { z - 146096 } / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
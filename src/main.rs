@@ -1,28 +1,217 @@
+use std::env;
+use std::fs;
+use std::os::unix::fs::{DirBuilderExt,FileTypeExt,MetadataExt,PermissionsExt};
+use std::os::unix::net::{UnixStream};
+use std::path::Path;
+use std::process;
 use std::thread;
-use std::net::{TcpListener};
-use std::io::{BufWriter, BufReader};
+
+#[macro_use]
+extern crate log;
+extern crate mio;
+use mio::net::{TcpListener,UnixListener};
 
 extern crate queue_experiments;
+use queue_experiments::auth::{PasswordHash};
+use queue_experiments::cluster;
+use queue_experiments::config::{Config,USAGE};
 use queue_experiments::queue_table::{QueueTable};
-use queue_experiments::connection::{Connection};
+use queue_experiments::event_loop::{EventLoop};
+use queue_experiments::logging;
+use queue_experiments::metrics;
+use queue_experiments::net::{Listener};
+use queue_experiments::replication;
+use queue_experiments::tls;
+
+fn exit_with_error(message: String) -> ! {
+    eprintln!("Error: {}", message);
+    process::exit(1);
+}
+
+// Identifies the socket file we bound, so it is only removed on exit if no one replaced it
+type SocketFile = (u64, u64);
+
+// A socket file left behind by a previous run would make the bind fail, so it is removed once
+// connecting to it fails. A socket another server still listens on, or anything else at path, is left alone.
+// The socket is bound in a directory only we can enter and moved into place once it has its permissions,
+// so no one can connect while it still has the umask's.
+fn bind_unix_socket(path: &Path, mode: u32) -> Result<(UnixListener, SocketFile), String> {
+    match fs::symlink_metadata(path) {
+        Ok(ref metadata) if metadata.file_type().is_socket() => {
+            if UnixStream::connect(path).is_ok() {
+                return Err(format!("could not listen on {}: another server is listening on it", path.display()));
+            }
+            if let Err(error) = fs::remove_file(path) {
+                return Err(format!("could not remove old socket {}: {}", path.display(), error));
+            }
+        }
+        Ok(_) => {
+            return Err(format!("could not listen on {}: it exists and is not a socket", path.display()));
+        }
+        Err(_) => {
+        }
+    }
+    let file_name = match path.file_name() {
+        Some(file_name) => file_name.to_string_lossy().into_owned(),
+        None => {
+            return Err(format!("could not listen on {}: not a file path", path.display()));
+        }
+    };
+    let private_dir = path.with_file_name(format!(".{}.{}", file_name, process::id()));
+    if let Err(error) = fs::DirBuilder::new().mode(0o700).create(&private_dir) {
+        return Err(format!("could not create {}: {}", private_dir.display(), error));
+    }
+    let result = bind_in(&private_dir.join(&file_name), path, mode);
+    let _ = fs::remove_dir_all(&private_dir);
+    result
+}
+
+fn bind_in(bind_path: &Path, path: &Path, mode: u32) -> Result<(UnixListener, SocketFile), String> {
+    let listener = match UnixListener::bind(bind_path) {
+        Ok(listener) => listener,
+        Err(error) => {
+            return Err(format!("could not listen on {}: {}", path.display(), error));
+        }
+    };
+    if let Err(error) = fs::set_permissions(bind_path, fs::Permissions::from_mode(mode)) {
+        return Err(format!("could not set permissions of {}: {}", path.display(), error));
+    }
+    let file = match fs::symlink_metadata(bind_path) {
+        Ok(metadata) => (metadata.dev(), metadata.ino()),
+        Err(error) => {
+            return Err(format!("could not stat {}: {}", path.display(), error));
+        }
+    };
+    match fs::rename(bind_path, path) {
+        Ok(()) => Ok((listener, file)),
+        Err(error) => Err(format!("could not move socket to {}: {}", path.display(), error))
+    }
+}
 
 fn main() {
-    let listener = TcpListener::bind("127.0.0.1:5248").unwrap();
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "--help") {
+        println!("{}", USAGE);
+        return;
+    }
+    if args.len() == 2 && args[0] == "--hash-password" {
+        println!("{}", PasswordHash::new(&args[1]));
+        return;
+    }
+    let config = match Config::from_args(args) {
+        Ok(config) => config,
+        Err(message) => exit_with_error(message)
+    };
+
+    if let Err(message) = logging::init(config.log_level, config.log_format, config.trace_commands) {
+        exit_with_error(message);
+    }
+
+    let mut listeners = Vec::new();
+    for address in config.listen.iter() {
+        match TcpListener::bind(*address) {
+            Ok(listener) => {
+                listeners.push(Listener::Tcp(listener));
+            }
+            Err(error) => {
+                exit_with_error(format!("could not listen on {}: {}", address, error));
+            }
+        }
+    }
+    if !config.tls_listen.is_empty() {
+        // validate makes sure the certificate and key are set
+        let cert = config.tls_cert.as_ref().unwrap();
+        let key = config.tls_key.as_ref().unwrap();
+        let tls_config = match tls::server_config(cert, key, config.tls_ca_cert.as_deref()) {
+            Ok(tls_config) => tls_config,
+            Err(message) => exit_with_error(message)
+        };
+        for address in config.tls_listen.iter() {
+            match TcpListener::bind(*address) {
+                Ok(listener) => {
+                    listeners.push(Listener::Tls(listener, tls_config.clone()));
+                }
+                Err(error) => {
+                    exit_with_error(format!("could not listen on {}: {}", address, error));
+                }
+            }
+        }
+    }
+    let mut socket_file = None;
+    if let Some(ref path) = config.unixsocket {
+        match bind_unix_socket(path, config.unixsocketperm) {
+            Ok((listener, file)) => {
+                listeners.push(Listener::Unix(listener, path.clone()));
+                socket_file = Some(file);
+            }
+            Err(message) => {
+                exit_with_error(message);
+            }
+        }
+    }
 
     let queue_table = QueueTable::new();
+    queue_table.memory().set_max(config.maxmemory);
+    queue_table.set_default_limits(config.queue_limits.clone());
+    queue_table.slowlog().set_slower_than(config.slowlog_slower_than);
+    queue_table.slowlog().set_max_len(config.slowlog_max_len);
 
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => {
-                let queue_table = queue_table.clone();
-                thread::spawn(move|| {
-                    let mut reader = BufReader::new(&stream);
-                    let mut writer = BufWriter::new(&stream);
-                    Connection::new(&mut reader, &mut writer, &queue_table).listen();
-                });
+    if let Some(ref path) = config.appendonly {
+        match queue_table.persistence().open(path, config.appendfsync, &queue_table) {
+            Ok(operations) => {
+                info!(path:% = path.display(), operations = operations; "loaded the log");
             }
-            Err(_) => {
+            Err(message) => {
+                exit_with_error(message);
             }
         }
     }
+
+    if let Some(address) = config.metrics_listen {
+        let listener = match std::net::TcpListener::bind(address) {
+            Ok(listener) => listener,
+            Err(error) => exit_with_error(format!("could not listen on {}: {}", address, error))
+        };
+        info!(address:% = address; "serving metrics");
+        let metrics_queue_table = queue_table.clone();
+        thread::spawn(move|| metrics::serve(listener, metrics_queue_table));
+    }
+
+    if let Some(leader) = config.follow {
+        info!(leader:% = leader; "following");
+        queue_table.replication().set_leader(leader.to_string());
+        let follower_queue_table = queue_table.clone();
+        let auth = config.follow_auth.clone();
+        thread::spawn(move|| replication::follow(leader, auth, follower_queue_table));
+    }
+
+    if let Some(id) = config.cluster_id {
+        info!(node = id, nodes = config.cluster_nodes.len(); "starting in cluster mode");
+        queue_table.cluster().enable(id, config.cluster_nodes.clone(), config.cluster_election_timeout);
+        cluster::run(queue_table.cluster().clone(), config.cluster_auth.clone());
+    }
+
+    if let Some(local) = config.shard_self {
+        info!(address:% = local, shards = config.shards.len(); "serving a shard");
+        queue_table.shards().configure(config.shards.clone(), local);
+    }
+
+    for listener in listeners.iter() {
+        info!(address:% = listener.local_address(); "listening");
+    }
+    let result = EventLoop::new(listeners, &queue_table, &config.users).and_then(|mut event_loop| {
+        event_loop.shutdown_on_signals(config.shutdown_timeout)?;
+        event_loop.run()
+    });
+    if let Err(error) = result {
+        exit_with_error(format!("{}", error));
+    }
+    info!("stopped");
+    // Every change was appended to the log as it was made, without a log nothing is kept
+    // Another server may have taken over the path after ours was removed
+    if let (Some(path), Some(file)) = (config.unixsocket.as_ref(), socket_file) {
+        if fs::symlink_metadata(path).ok().map(|metadata| (metadata.dev(), metadata.ino())) == Some(file) {
+            let _ = fs::remove_file(path);
+        }
+    }
 }
@@ -0,0 +1,82 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize,Ordering};
+
+#[derive(PartialEq)]
+#[derive(Debug)]
+pub struct OutOfMemory;
+
+struct MemoryInner {
+    used: AtomicUsize,
+    // 0 means unlimited
+    max: AtomicUsize
+}
+
+// Bytes of data held by the server across queues, streams and open transactions.
+// Shared by everything created from the same QueueTable.
+pub struct Memory {
+    inner: Arc<MemoryInner>
+}
+
+impl Default for Memory {
+    fn default() -> Memory {
+        Memory::new()
+    }
+}
+
+impl Memory {
+    pub fn new() -> Memory {
+        Memory {
+            inner: Arc::new(MemoryInner {
+                used: AtomicUsize::new(0),
+                max: AtomicUsize::new(0)
+            })
+        }
+    }
+
+    pub fn reserve(&self, bytes: usize) -> Result<(),OutOfMemory> {
+        let max = self.inner.max.load(Ordering::SeqCst);
+        let mut used = self.inner.used.load(Ordering::SeqCst);
+        loop {
+            if max > 0 && used + bytes > max {
+                return Err(OutOfMemory);
+            }
+            match self.inner.used.compare_exchange(used, used + bytes, Ordering::SeqCst, Ordering::SeqCst) {
+                Ok(_) => {
+                    return Ok(());
+                }
+                Err(current) => {
+                    used = current;
+                }
+            }
+        }
+    }
+
+    // For data that was already accepted once, e.g. items returned by a rollback
+    pub fn force_reserve(&self, bytes: usize) {
+        self.inner.used.fetch_add(bytes, Ordering::SeqCst);
+    }
+
+    pub fn release(&self, bytes: usize) {
+        self.inner.used.fetch_sub(bytes, Ordering::SeqCst);
+    }
+
+    pub fn used(&self) -> usize {
+        self.inner.used.load(Ordering::SeqCst)
+    }
+
+    pub fn max(&self) -> usize {
+        self.inner.max.load(Ordering::SeqCst)
+    }
+
+    pub fn set_max(&self, max: usize) {
+        self.inner.max.store(max, Ordering::SeqCst);
+    }
+}
+
+impl Clone for Memory {
+    fn clone(&self) -> Memory {
+        Memory {
+            inner: self.inner.clone()
+        }
+    }
+}
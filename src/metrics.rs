@@ -0,0 +1,107 @@
+use std::io;
+use std::io::{Read,Write};
+use std::net::{TcpListener,TcpStream};
+use std::time::Duration;
+
+use queue_table::{QueueTable,QueueStats};
+use stats::{BPOP_WAIT_BUCKETS};
+
+const MAX_REQUEST_SIZE: usize = 8192;
+const REQUEST_TIMEOUT: u64 = 5;
+
+// A per-queue metric: name, type, help text and how to read it from the stats
+type Metric = (&'static str, &'static str, &'static str, fn(&QueueStats) -> String);
+
+// Renders everything in the Prometheus text exposition format
+pub fn render(queue_table: &QueueTable) -> String {
+    let stats = queue_table.stats();
+    let memory = queue_table.memory();
+    let mut out = String::new();
+
+    metric(&mut out, "queue_uptime_seconds", "gauge", "Seconds since the server started", stats.uptime().as_secs());
+    metric(&mut out, "queue_connected_clients", "gauge", "Clients currently connected", stats.connected_clients());
+    metric(&mut out, "queue_connections_total", "counter", "Clients connected since start up", stats.connections());
+    metric(&mut out, "queue_open_transactions", "gauge", "Clients with an open transaction", stats.open_transactions());
+    metric(&mut out, "queue_blocked_clients", "gauge", "Clients waiting on a BPOP, blocking PUSH or COMMIT", stats.blocked_clients());
+    metric(&mut out, "queue_commits_total", "counter", "Transactions committed", stats.commits());
+    metric(&mut out, "queue_aborts_total", "counter", "Transactions aborted or rolled back on disconnect", stats.aborts());
+    metric(&mut out, "queue_used_memory_bytes", "gauge", "Bytes of data held", memory.used());
+    metric(&mut out, "queue_maxmemory_bytes", "gauge", "Configured memory limit, 0 is unlimited", memory.max());
+
+    let queues = queue_table.queue_stats();
+    let per_queue: [Metric; 7] = [
+        ("queue_length", "gauge", "Items waiting in the queue", |queue| queue.len.to_string()),
+        ("queue_bytes", "gauge", "Bytes of data waiting in the queue", |queue| queue.bytes.to_string()),
+        ("queue_pushed_total", "counter", "Items pushed to the queue", |queue| queue.pushed.to_string()),
+        ("queue_popped_total", "counter", "Items popped from the queue", |queue| queue.popped.to_string()),
+        ("queue_committed_total", "counter", "Items popped in a transaction that committed", |queue| queue.committed.to_string()),
+        ("queue_rolled_back_total", "counter", "Items popped in a transaction that was rolled back", |queue| queue.rolled_back.to_string()),
+        ("queue_oldest_item_age_seconds", "gauge", "Age of the oldest item in the queue", |queue| queue.oldest_age.map(seconds).unwrap_or(0.0).to_string())
+    ];
+    for &(name, kind, help, value) in per_queue.iter() {
+        header(&mut out, name, kind, help);
+        for (queue_name, queue) in queues.iter() {
+            out.push_str(&format!("{}{{queue=\"{}\"}} {}\n", name, escape_label(queue_name), value(queue)));
+        }
+    }
+
+    let bpop_wait = stats.bpop_wait();
+    header(&mut out, "queue_bpop_wait_seconds", "histogram", "Time BPOP waited for an item");
+    for (bound, count) in BPOP_WAIT_BUCKETS.iter().zip(bpop_wait.buckets.iter()) {
+        out.push_str(&format!("queue_bpop_wait_seconds_bucket{{le=\"{}\"}} {}\n", bound, count));
+    }
+    out.push_str(&format!("queue_bpop_wait_seconds_bucket{{le=\"+Inf\"}} {}\n", bpop_wait.count));
+    out.push_str(&format!("queue_bpop_wait_seconds_sum {}\n", seconds(bpop_wait.sum)));
+    out.push_str(&format!("queue_bpop_wait_seconds_count {}\n", bpop_wait.count));
+    out
+}
+
+// Answers one request at a time, scrapes are infrequent and cheap
+pub fn serve(listener: TcpListener, queue_table: QueueTable) {
+    for stream in listener.incoming().flatten() {
+        let _ = handle_request(stream, &queue_table);
+    }
+}
+
+fn handle_request(mut stream: TcpStream, queue_table: &QueueTable) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(REQUEST_TIMEOUT)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(REQUEST_TIMEOUT)))?;
+    let mut request = Vec::new();
+    let mut buffer = [0; 1024];
+    while !request.windows(4).any(|end| end == b"\r\n\r\n") && request.len() < MAX_REQUEST_SIZE {
+        let n = stream.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        request.extend_from_slice(&buffer[..n]);
+    }
+    let request_line = String::from_utf8_lossy(&request).lines().next().unwrap_or("").to_string();
+    let parts: Vec<&str> = request_line.split_whitespace().collect();
+    let response = match (parts.first(), parts.get(1)) {
+        (Some(&"GET"), Some(&"/metrics")) => {
+            let body = render(queue_table);
+            format!("HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body)
+        }
+        _ => {
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+        }
+    };
+    stream.write_all(response.as_bytes())
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    out.push_str(&format!("# HELP {} {}\n# TYPE {} {}\n", name, help, name, kind));
+}
+
+fn metric<T: ToString>(out: &mut String, name: &str, kind: &str, help: &str, value: T) {
+    header(out, name, kind, help);
+    out.push_str(&format!("{} {}\n", name, value.to_string()));
+}
+
+fn seconds(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1e9
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
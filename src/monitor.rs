@@ -0,0 +1,76 @@
+use std::sync::{Arc,Mutex};
+use std::sync::atomic::{AtomicBool,AtomicUsize,Ordering};
+
+use stats::{ClientId};
+
+// A monitoring client that falls this far behind misses lines until it catches up
+const MAX_FEED_SIZE: usize = 1 << 20;
+
+pub type MonitorFeed = Arc<Mutex<Vec<u8>>>;
+
+struct MonitorsInner {
+    feeds: Mutex<Vec<(ClientId, MonitorFeed)>>,
+    // Checked before formatting anything so there is no cost without subscribers
+    count: AtomicUsize,
+    // Set when a line is published, the event loop then flushes monitoring clients
+    dirty: AtomicBool
+}
+
+// Clients that ran MONITOR, each gets a copy of every command processed
+#[derive(Clone)]
+pub struct Monitors {
+    inner: Arc<MonitorsInner>
+}
+
+impl Default for Monitors {
+    fn default() -> Monitors {
+        Monitors::new()
+    }
+}
+
+impl Monitors {
+    pub fn new() -> Monitors {
+        Monitors {
+            inner: Arc::new(MonitorsInner {
+                feeds: Mutex::new(Vec::new()),
+                count: AtomicUsize::new(0),
+                dirty: AtomicBool::new(false)
+            })
+        }
+    }
+
+    pub fn subscribe(&self, client: ClientId) -> MonitorFeed {
+        let feed = Arc::new(Mutex::new(Vec::new()));
+        let mut feeds = self.inner.feeds.lock().unwrap();
+        feeds.push((client, feed.clone()));
+        self.inner.count.store(feeds.len(), Ordering::Relaxed);
+        feed
+    }
+
+    pub fn unsubscribe(&self, client: ClientId) {
+        let mut feeds = self.inner.feeds.lock().unwrap();
+        feeds.retain(|&(id, _)| id != client);
+        self.inner.count.store(feeds.len(), Ordering::Relaxed);
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.inner.count.load(Ordering::Relaxed) > 0
+    }
+
+    pub fn publish(&self, line: &str) {
+        let feeds = self.inner.feeds.lock().unwrap();
+        for (_, feed) in feeds.iter() {
+            let mut feed = feed.lock().unwrap();
+            if feed.len() + line.len() + 2 <= MAX_FEED_SIZE {
+                feed.extend_from_slice(line.as_bytes());
+                feed.extend_from_slice(b"\r\n");
+            }
+        }
+        self.inner.dirty.store(true, Ordering::Relaxed);
+    }
+
+    // Whether anything was published since the last call
+    pub fn take_dirty(&self) -> bool {
+        self.inner.dirty.swap(false, Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,149 @@
+use std::io;
+use std::io::{Read,Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use mio::{Interest,Registry,Token};
+use mio::event::Source;
+use mio::net::{TcpListener,TcpStream,UnixListener,UnixStream};
+use rustls::ServerConfig;
+
+use tls::TlsStream;
+
+// The event loop treats TCP, TLS and Unix socket clients the same.
+// A Unix socket may be moved after binding, so its listener keeps the path it ended up at.
+pub enum Listener {
+    Tcp(TcpListener),
+    Tls(TcpListener, Arc<ServerConfig>),
+    Unix(UnixListener, PathBuf)
+}
+
+pub enum Stream {
+    Tcp(TcpStream),
+    Tls(Box<TlsStream>),
+    Unix(UnixStream)
+}
+
+impl Listener {
+    // Also returns the peer address for logging, Unix socket clients are usually unnamed
+    pub fn accept(&self) -> io::Result<(Stream, String)> {
+        match *self {
+            Listener::Tcp(ref listener) => {
+                listener.accept().map(|(stream, address)| (Stream::Tcp(stream), address.to_string()))
+            }
+            Listener::Tls(ref listener, ref config) => {
+                let (stream, address) = listener.accept()?;
+                let stream = TlsStream::new(stream, config.clone())?;
+                Ok((Stream::Tls(Box::new(stream)), address.to_string()))
+            }
+            Listener::Unix(ref listener, _) => {
+                listener.accept().map(|(stream, address)| {
+                    let peer = match address.as_pathname() {
+                        Some(path) => path.display().to_string(),
+                        None => "unix".to_string()
+                    };
+                    (Stream::Unix(stream), peer)
+                })
+            }
+        }
+    }
+
+    pub fn local_address(&self) -> String {
+        match *self {
+            Listener::Tcp(ref listener) | Listener::Tls(ref listener, _) => {
+                listener.local_addr().map(|address| address.to_string()).unwrap_or_default()
+            }
+            Listener::Unix(_, ref path) => path.display().to_string()
+        }
+    }
+}
+
+impl Source for Listener {
+    fn register(&mut self, registry: &Registry, token: Token, interests: Interest) -> io::Result<()> {
+        match *self {
+            Listener::Tcp(ref mut listener) => listener.register(registry, token, interests),
+            Listener::Tls(ref mut listener, _) => listener.register(registry, token, interests),
+            Listener::Unix(ref mut listener, _) => listener.register(registry, token, interests)
+        }
+    }
+
+    fn reregister(&mut self, registry: &Registry, token: Token, interests: Interest) -> io::Result<()> {
+        match *self {
+            Listener::Tcp(ref mut listener) => listener.reregister(registry, token, interests),
+            Listener::Tls(ref mut listener, _) => listener.reregister(registry, token, interests),
+            Listener::Unix(ref mut listener, _) => listener.reregister(registry, token, interests)
+        }
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        match *self {
+            Listener::Tcp(ref mut listener) => listener.deregister(registry),
+            Listener::Tls(ref mut listener, _) => listener.deregister(registry),
+            Listener::Unix(ref mut listener, _) => listener.deregister(registry)
+        }
+    }
+}
+
+impl Stream {
+    // TLS may have encrypted data left to send after all plaintext has been written
+    pub fn wants_write(&self) -> bool {
+        match *self {
+            Stream::Tls(ref stream) => stream.wants_write(),
+            _ => false
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Tcp(ref mut stream) => stream.read(buf),
+            Stream::Tls(ref mut stream) => stream.read(buf),
+            Stream::Unix(ref mut stream) => stream.read(buf)
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Tcp(ref mut stream) => stream.write(buf),
+            Stream::Tls(ref mut stream) => stream.write(buf),
+            Stream::Unix(ref mut stream) => stream.write(buf)
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Stream::Tcp(ref mut stream) => stream.flush(),
+            Stream::Tls(ref mut stream) => stream.flush(),
+            Stream::Unix(ref mut stream) => stream.flush()
+        }
+    }
+}
+
+impl Source for Stream {
+    fn register(&mut self, registry: &Registry, token: Token, interests: Interest) -> io::Result<()> {
+        match *self {
+            Stream::Tcp(ref mut stream) => stream.register(registry, token, interests),
+            Stream::Tls(ref mut stream) => stream.socket.register(registry, token, interests),
+            Stream::Unix(ref mut stream) => stream.register(registry, token, interests)
+        }
+    }
+
+    fn reregister(&mut self, registry: &Registry, token: Token, interests: Interest) -> io::Result<()> {
+        match *self {
+            Stream::Tcp(ref mut stream) => stream.reregister(registry, token, interests),
+            Stream::Tls(ref mut stream) => stream.socket.reregister(registry, token, interests),
+            Stream::Unix(ref mut stream) => stream.reregister(registry, token, interests)
+        }
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        match *self {
+            Stream::Tcp(ref mut stream) => stream.deregister(registry),
+            Stream::Tls(ref mut stream) => stream.socket.deregister(registry),
+            Stream::Unix(ref mut stream) => stream.deregister(registry)
+        }
+    }
+}
@@ -1,14 +1,30 @@
 use commands::{Command};
+use stream::{Retention};
+use queue_table::{QueueLimits,OverflowPolicy};
+use replication::{WriteConcern};
+use raft;
 use std::str::{Chars};
+use std::time::{Duration};
 
 pub type ParseResult = Result<Command,String>;
 
 pub fn parse_command(buffer: Vec<u8>) -> ParseResult {
+    let (command_name, arguments) = parse_parts(buffer)?;
+    build_command(command_name, arguments)
+}
+
+// The upper cased name and the unquoted arguments of a message
+pub fn parse_parts(buffer: Vec<u8>) -> Result<(String, Vec<String>), String> {
     let buffer = try!(string_from_utf8(buffer));
     let mut chars = buffer.chars();
     let command_name = try!(parse_command_name(&mut chars));
     let arguments = try!(parse_arguments(&mut chars));
-    build_command(command_name, arguments)
+    Ok((command_name, arguments))
+}
+
+// The inverse of parse_quoted_string, for writing arguments
+pub fn quote(value: &str) -> String {
+    format!("'{}'", value.replace('\\', "\\\\").replace('\'', "\\'"))
 }
 
 fn string_from_utf8(buffer: Vec<u8>) -> Result<String,String> {
@@ -135,16 +151,46 @@ fn build_command(command_name: String, arguments: Vec<String>) -> ParseResult {
         "QUIT"   => { build_with_no_args(arguments, "QUIT", Command::Quit) },
         "BEGIN"  => { build_with_no_args(arguments, "BEGIN", Command::Begin) },
         "COMMIT" => { build_with_no_args(arguments, "COMMIT", Command::Commit) },
-        "ABORT"  => { build_with_no_args(arguments, "ABORT", Command::Abort) },
+        "ABORT"  => { build_abort(arguments) }
+        "SPUSH"  => { build_spush(arguments) }
+        "SREAD"  => { build_sread(arguments) }
+        "SCOMMIT" => { build_scommit(arguments) }
+        "SRETAIN" => { build_sretain(arguments) }
+        "LIMIT"  => { build_limit(arguments) }
+        "MEMORY" => { build_with_no_args(arguments, "MEMORY", Command::Memory) },
+        "INFO"   => { build_with_no_args(arguments, "INFO", Command::Info) },
+        "SLOWLOG" => { build_slowlog(arguments) }
+        "MONITOR" => { build_with_no_args(arguments, "MONITOR", Command::Monitor) },
+        "CLIENT" => { build_client(arguments) }
+        "TXLIST" => { build_with_no_args(arguments, "TXLIST", Command::TransactionList) },
+        "TXABORT" => { build_txabort(arguments) }
+        "SYNC"   => { build_with_no_args(arguments, "SYNC", Command::Sync) },
+        "ACK"    => { build_ack(arguments) }
+        "PROMOTE" => { build_with_no_args(arguments, "PROMOTE", Command::Promote) },
+        "WRITECONCERN" => { build_writeconcern(arguments) }
+        "RAFT"   => { raft::Message::parse(&arguments).map(Command::Raft) }
+        "CLUSTER" => { build_cluster(arguments) }
+        "MAXMEMORY" => { build_maxmemory(arguments) }
+        "DEADLETTER" => { build_deadletter(arguments) }
+        "AUTH"   => { build_auth(arguments) }
         cmd      => Err(format!("Unknown Command: {}", cmd))
     }
 }
 
+// An empty message group means none, a ttl of 0 means the item never expires
 fn build_push(arguments: Vec<String>) -> Result<Command, String> {
-    if arguments.len() == 2 {
+    if arguments.len() >= 2 && arguments.len() <= 4 {
         let queue_name = arguments[0].clone();
         let value = arguments[1].clone();
-        Ok(Command::Push(value, queue_name))
+        let group = arguments.get(2).cloned().and_then(|group| {
+            if group.is_empty() { None } else { Some(group) }
+        });
+        let ttl = match arguments.get(3) {
+            Some(ttl) => parse_number(ttl, "ttl")?,
+            None => 0
+        };
+        let ttl = if ttl == 0 { None } else { Some(Duration::from_secs(ttl)) };
+        Ok(Command::Push(value, queue_name, group, ttl))
     } else {
         Err("Incorrect number of arguments for PUSH".to_string())
     }
@@ -159,15 +205,44 @@ fn build_pop(arguments: Vec<String>) -> Result<Command, String> {
     }
 }
 
+// BPOP 'queue' ['timeout ms' ['withattempts']], a timeout of 0 waits for as long as it takes
 fn build_bpop(arguments: Vec<String>) -> Result<Command, String> {
-    if arguments.len() == 1 {
+    if arguments.len() >= 1 && arguments.len() <= 3 {
         let queue_name = arguments[0].clone();
-        Ok(Command::BlockingPop(queue_name))
+        let timeout = match arguments.get(1) {
+            Some(timeout) => parse_number(timeout, "timeout")?,
+            None => 0
+        };
+        let timeout = if timeout == 0 { None } else { Some(Duration::from_millis(timeout)) };
+        let attempts = match arguments.get(2) {
+            Some(flag) if flag.to_lowercase() == "withattempts" => true,
+            Some(flag) => {
+                return Err(format!("Unknown BPOP option: {}", flag));
+            }
+            None => false
+        };
+        Ok(Command::BlockingPop(queue_name, timeout, attempts))
     } else {
         Err("Incorrect number of arguments for BPOP".to_string())
     }
 }
 
+// ABORT ['delay ms']
+fn build_abort(arguments: Vec<String>) -> Result<Command, String> {
+    match arguments.len() {
+        0 => {
+            Ok(Command::Abort(None))
+        }
+        1 => {
+            let delay = parse_number(&arguments[0], "delay")?;
+            Ok(Command::Abort(Some(Duration::from_millis(delay))))
+        }
+        _ => {
+            Err("Incorrect number of arguments for ABORT".to_string())
+        }
+    }
+}
+
 fn build_with_no_args(arguments: Vec<String>, command_name: &'static str, command: Command) -> Result<Command, String> {
     if arguments.len() == 0 {
         Ok(command)
@@ -175,3 +250,214 @@ fn build_with_no_args(arguments: Vec<String>, command_name: &'static str, comman
         Err(format!("No arguments expect for command: {}", command_name))
     }
 }
+
+fn build_spush(arguments: Vec<String>) -> Result<Command, String> {
+    if arguments.len() == 2 {
+        let stream_name = arguments[0].clone();
+        let value = arguments[1].clone();
+        Ok(Command::StreamPush(value, stream_name))
+    } else {
+        Err("Incorrect number of arguments for SPUSH".to_string())
+    }
+}
+
+fn build_sread(arguments: Vec<String>) -> Result<Command, String> {
+    match arguments.len() {
+        2 => {
+            Ok(Command::StreamRead(arguments[0].clone(), arguments[1].clone(), 1))
+        }
+        3 => {
+            let count = parse_number(&arguments[2], "count")?;
+            Ok(Command::StreamRead(arguments[0].clone(), arguments[1].clone(), count as usize))
+        }
+        _ => {
+            Err("Incorrect number of arguments for SREAD".to_string())
+        }
+    }
+}
+
+fn build_scommit(arguments: Vec<String>) -> Result<Command, String> {
+    if arguments.len() == 3 {
+        let offset = parse_number(&arguments[2], "offset")?;
+        Ok(Command::StreamCommit(arguments[0].clone(), arguments[1].clone(), offset))
+    } else {
+        Err("Incorrect number of arguments for SCOMMIT".to_string())
+    }
+}
+
+// A limit of 0 means unlimited
+fn build_sretain(arguments: Vec<String>) -> Result<Command, String> {
+    if arguments.len() == 3 {
+        let max_len = parse_number(&arguments[1], "max length")?;
+        let max_age = parse_number(&arguments[2], "max age")?;
+        let retention = Retention {
+            max_len: if max_len == 0 { None } else { Some(max_len as usize) },
+            max_age: if max_age == 0 { None } else { Some(Duration::from_secs(max_age)) }
+        };
+        Ok(Command::StreamRetain(arguments[0].clone(), retention))
+    } else {
+        Err("Incorrect number of arguments for SRETAIN".to_string())
+    }
+}
+
+// A limit of 0 means unlimited
+fn build_limit(arguments: Vec<String>) -> Result<Command, String> {
+    if arguments.len() == 4 {
+        let max_len = parse_number(&arguments[1], "max length")?;
+        let max_bytes = parse_number(&arguments[2], "max bytes")?;
+        let overflow = match &arguments[3].to_uppercase() as &str {
+            "REJECT" => OverflowPolicy::Reject,
+            "DROP"   => OverflowPolicy::DropOldest,
+            "BLOCK"  => OverflowPolicy::Block,
+            policy   => {
+                return Err(format!("Unknown overflow policy: {}", policy));
+            }
+        };
+        let limits = QueueLimits {
+            max_len: if max_len == 0 { None } else { Some(max_len as usize) },
+            max_bytes: if max_bytes == 0 { None } else { Some(max_bytes as usize) },
+            overflow
+        };
+        Ok(Command::Limit(arguments[0].clone(), limits))
+    } else {
+        Err("Incorrect number of arguments for LIMIT".to_string())
+    }
+}
+
+// 0 means unlimited
+fn build_maxmemory(arguments: Vec<String>) -> Result<Command, String> {
+    if arguments.len() == 1 {
+        let max = parse_number(&arguments[0], "max memory")?;
+        Ok(Command::MaxMemory(max as usize))
+    } else {
+        Err("Incorrect number of arguments for MAXMEMORY".to_string())
+    }
+}
+
+fn build_deadletter(arguments: Vec<String>) -> Result<Command, String> {
+    if arguments.len() == 2 {
+        Ok(Command::DeadLetter(arguments[0].clone(), arguments[1].clone()))
+    } else {
+        Err("Incorrect number of arguments for DEADLETTER".to_string())
+    }
+}
+
+// SLOWLOG, SLOWLOG 'count' or SLOWLOG 'reset'
+fn build_slowlog(arguments: Vec<String>) -> Result<Command, String> {
+    match arguments.len() {
+        0 => {
+            Ok(Command::SlowLog(None))
+        }
+        1 if arguments[0].to_uppercase() == "RESET" => {
+            Ok(Command::SlowLogReset)
+        }
+        1 => {
+            let count = parse_number(&arguments[0], "count")?;
+            Ok(Command::SlowLog(Some(count as usize)))
+        }
+        _ => {
+            Err("Incorrect number of arguments for SLOWLOG".to_string())
+        }
+    }
+}
+
+// CLIENT 'list', CLIENT 'kill' 'id' or CLIENT 'setname' 'name'
+fn build_client(arguments: Vec<String>) -> Result<Command, String> {
+    let subcommand = arguments.first().map(|subcommand| subcommand.to_uppercase()).unwrap_or_default();
+    match (&subcommand as &str, arguments.len()) {
+        ("LIST", 1) => {
+            Ok(Command::ClientList)
+        }
+        ("KILL", 2) => {
+            let id = parse_number(&arguments[1], "client id")?;
+            Ok(Command::ClientKill(id))
+        }
+        // Names are shown space separated in CLIENT LIST
+        ("SETNAME", 2) if arguments[1].is_empty() || arguments[1].contains(char::is_whitespace) => {
+            Err(format!("Invalid client name: {}", arguments[1]))
+        }
+        ("SETNAME", 2) => {
+            Ok(Command::ClientSetName(arguments[1].clone()))
+        }
+        ("LIST", _) | ("KILL", _) | ("SETNAME", _) => {
+            Err(format!("Incorrect number of arguments for CLIENT {}", subcommand))
+        }
+        _ => {
+            Err("CLIENT requires 'list', 'kill' or 'setname'".to_string())
+        }
+    }
+}
+
+// CLUSTER 'slots' or CLUSTER 'keyslot' 'name'
+fn build_cluster(arguments: Vec<String>) -> Result<Command, String> {
+    let subcommand = arguments.first().map(|subcommand| subcommand.to_uppercase()).unwrap_or_default();
+    match (&subcommand as &str, arguments.len()) {
+        ("SLOTS", 1) => {
+            Ok(Command::ClusterSlots)
+        }
+        ("KEYSLOT", 2) => {
+            Ok(Command::ClusterKeySlot(arguments[1].clone()))
+        }
+        ("SLOTS", _) | ("KEYSLOT", _) => {
+            Err(format!("Incorrect number of arguments for CLUSTER {}", subcommand))
+        }
+        _ => {
+            Err("CLUSTER requires 'slots' or 'keyslot'".to_string())
+        }
+    }
+}
+
+fn build_txabort(arguments: Vec<String>) -> Result<Command, String> {
+    if arguments.len() == 1 {
+        let id = parse_number(&arguments[0], "client id")?;
+        Ok(Command::TransactionAbort(id))
+    } else {
+        Err("Incorrect number of arguments for TXABORT".to_string())
+    }
+}
+
+fn build_ack(arguments: Vec<String>) -> Result<Command, String> {
+    if arguments.len() == 1 {
+        let sequence = parse_number(&arguments[0], "sequence")?;
+        Ok(Command::Ack(sequence))
+    } else {
+        Err("Incorrect number of arguments for ACK".to_string())
+    }
+}
+
+// WRITECONCERN 'replicas' 'timeout' for the connection or WRITECONCERN 'queue' 'replicas' 'timeout',
+// the timeout is in milliseconds and 0 replicas turns it off
+fn build_writeconcern(arguments: Vec<String>) -> Result<Command, String> {
+    if arguments.len() == 2 || arguments.len() == 3 {
+        let start = arguments.len() - 2;
+        let replicas = parse_number(&arguments[start], "replicas")?;
+        let timeout = parse_number(&arguments[start + 1], "timeout")?;
+        let concern = WriteConcern { replicas: replicas as usize, timeout: Duration::from_millis(timeout) };
+        if start == 0 {
+            Ok(Command::WriteConcern(concern))
+        } else {
+            Ok(Command::QueueWriteConcern(arguments[0].clone(), concern))
+        }
+    } else {
+        Err("Incorrect number of arguments for WRITECONCERN".to_string())
+    }
+}
+
+fn build_auth(arguments: Vec<String>) -> Result<Command, String> {
+    if arguments.len() == 2 {
+        Ok(Command::Auth(arguments[0].clone(), arguments[1].clone()))
+    } else {
+        Err("Incorrect number of arguments for AUTH".to_string())
+    }
+}
+
+fn parse_number(argument: &String, argument_name: &'static str) -> Result<u64, String> {
+    match argument.parse::<u64>() {
+        Ok(number) => {
+            Ok(number)
+        }
+        Err(_) => {
+            Err(format!("Invalid {}: {}", argument_name, argument))
+        }
+    }
+}
@@ -0,0 +1,216 @@
+use std::fs;
+use std::fs::{File,OpenOptions};
+use std::io;
+use std::io::{Read,Write};
+use std::path::{Path,PathBuf};
+use std::sync::{Arc,Mutex};
+use std::sync::atomic::{AtomicBool,Ordering};
+use std::thread;
+use std::time::Duration;
+
+use queue_table::{QueueTable};
+use replication;
+use replication::{Operation};
+
+// How often the everysec policy syncs the log to disk
+const SYNC_FREQ: u64 = 1000;
+// The log is rewritten as a snapshot once it is this big and twice its size after the last rewrite
+const MIN_REWRITE_SIZE: u64 = 64 << 20;
+
+// When appended operations are synced to disk
+#[derive(PartialEq)]
+#[derive(Debug)]
+#[derive(Clone,Copy)]
+pub enum FsyncPolicy {
+    // Before the client is answered
+    Always,
+    // Once a second, a crash loses at most the last second of writes
+    EverySec,
+    // Whenever the OS writes them out
+    No
+}
+
+struct AppendLog {
+    file: File,
+    path: PathBuf,
+    policy: FsyncPolicy,
+    size: u64,
+    rewritten_size: u64,
+    // Set after a failed write so the error is only logged once until writes work again
+    failed: bool
+}
+
+struct PersistenceInner {
+    log: Mutex<Option<AppendLog>>,
+    // Checked before encoding anything so there is no cost without a log
+    enabled: AtomicBool
+}
+
+// Every committed change, written to an append only file in the same format as the
+// operations sent to followers and replayed when the server starts
+#[derive(Clone)]
+pub struct Persistence {
+    inner: Arc<PersistenceInner>
+}
+
+impl Default for Persistence {
+    fn default() -> Persistence {
+        Persistence::new()
+    }
+}
+
+impl Persistence {
+    pub fn new() -> Persistence {
+        Persistence {
+            inner: Arc::new(PersistenceInner {
+                log: Mutex::new(None),
+                enabled: AtomicBool::new(false)
+            })
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.inner.enabled.load(Ordering::Relaxed)
+    }
+
+    // Replays the log at path into queue_table, then rewrites it as a snapshot and appends from there.
+    // Returns how many operations were replayed.
+    pub fn open(&self, path: &Path, policy: FsyncPolicy, queue_table: &QueueTable) -> Result<usize, String> {
+        let replayed = load(path, queue_table)?;
+        let log = rewrite(path, policy, queue_table).map_err(|error| format!("could not write {}: {}", path.display(), error))?;
+        *self.inner.log.lock().unwrap() = Some(log);
+        self.inner.enabled.store(true, Ordering::Relaxed);
+        if policy == FsyncPolicy::EverySec {
+            let persistence = self.clone();
+            thread::spawn(move|| persistence.sync_every_second());
+        }
+        Ok(replayed)
+    }
+
+    pub fn append(&self, operation: &Operation) {
+        let mut log = self.inner.log.lock().unwrap();
+        let log = match *log {
+            Some(ref mut log) => log,
+            None => {
+                return;
+            }
+        };
+        let encoded = operation.encode();
+        let mut result = log.file.write_all(encoded.as_bytes());
+        if result.is_ok() && log.policy == FsyncPolicy::Always {
+            result = log.file.sync_data();
+        }
+        match result {
+            Ok(()) => {
+                log.size += encoded.len() as u64;
+                log.failed = false;
+            }
+            Err(error) => {
+                if !log.failed {
+                    error!(path:% = log.path.display(), error:% = error; "could not append to the log, writes are not persisted");
+                }
+                log.failed = true;
+            }
+        }
+    }
+
+    // Called by the event loop, clients wait while the snapshot is written
+    pub fn rewrite_if_needed(&self, queue_table: &QueueTable) {
+        let mut log = self.inner.log.lock().unwrap();
+        let (path, policy) = match *log {
+            Some(ref log) if log.size >= MIN_REWRITE_SIZE.max(log.rewritten_size * 2) => (log.path.clone(), log.policy),
+            _ => {
+                return;
+            }
+        };
+        match rewrite(&path, policy, queue_table) {
+            Ok(rewritten) => {
+                info!(path:% = path.display(), bytes = rewritten.size; "log rewritten");
+                *log = Some(rewritten);
+            }
+            // Appending to the old log goes on, it is retried once it has grown again
+            Err(error) => {
+                error!(path:% = path.display(), error:% = error; "could not rewrite the log");
+                if let Some(ref mut log) = *log {
+                    log.rewritten_size = log.size;
+                }
+            }
+        }
+    }
+
+    fn sync_every_second(&self) {
+        loop {
+            thread::sleep(Duration::from_millis(SYNC_FREQ));
+            // Synced without the lock so appends don't wait for the disk
+            let file = match *self.inner.log.lock().unwrap() {
+                Some(ref log) => log.file.try_clone(),
+                None => {
+                    return;
+                }
+            };
+            if let Err(error) = file.and_then(|file| file.sync_data()) {
+                error!(error:% = error; "could not sync the log");
+            }
+        }
+    }
+}
+
+// A missing log is an empty one. A log cut off by a crash mid write loses the partial operation.
+fn load(path: &Path, queue_table: &QueueTable) -> Result<usize, String> {
+    let mut contents = Vec::new();
+    match File::open(path) {
+        Ok(mut file) => {
+            if let Err(error) = file.read_to_end(&mut contents) {
+                return Err(format!("could not read {}: {}", path.display(), error));
+            }
+        }
+        Err(ref error) if error.kind() == io::ErrorKind::NotFound => {
+            return Ok(0);
+        }
+        Err(error) => {
+            return Err(format!("could not open {}: {}", path.display(), error));
+        }
+    }
+    let mut replayed = 0;
+    let mut rest = &contents[..];
+    while let Some(end) = replication::message_end(rest) {
+        let operation = Operation::parse(rest[..end].to_vec()).map_err(|error| format!("{} operation {}: {}", path.display(), replayed + 1, error))?;
+        replication::apply(queue_table, operation).map_err(|error| format!("{} operation {}: {}", path.display(), replayed + 1, error))?;
+        replayed += 1;
+        rest = &rest[end + 1..];
+    }
+    if !rest.is_empty() {
+        warn!(path:% = path.display(), bytes = rest.len(); "ignoring an incomplete operation at the end of the log");
+    }
+    Ok(replayed)
+}
+
+// Written next to path and renamed over it, so a crash leaves either the old or the new log
+fn rewrite(path: &Path, policy: FsyncPolicy, queue_table: &QueueTable) -> io::Result<AppendLog> {
+    let mut encoded = Vec::new();
+    for operation in replication::snapshot(queue_table) {
+        encoded.extend_from_slice(operation.encode().as_bytes());
+    }
+    let mut temp_name = path.as_os_str().to_owned();
+    temp_name.push(".rewrite");
+    let temp_path = PathBuf::from(temp_name);
+    let mut file = OpenOptions::new().write(true).create(true).truncate(true).open(&temp_path)?;
+    file.write_all(&encoded)?;
+    file.sync_all()?;
+    fs::rename(&temp_path, path)?;
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        File::open(dir)?.sync_all()?;
+    }
+    let file = OpenOptions::new().append(true).open(path)?;
+    let size = encoded.len() as u64;
+    Ok(AppendLog { file, path: path.to_path_buf(), policy, size, rewritten_size: size, failed: false })
+}
+
+pub fn parse_policy(value: &str) -> Result<FsyncPolicy, String> {
+    match &value.to_lowercase() as &str {
+        "always"   => Ok(FsyncPolicy::Always),
+        "everysec" => Ok(FsyncPolicy::EverySec),
+        "no"       => Ok(FsyncPolicy::No),
+        _          => Err(format!("unknown appendfsync policy {}", value))
+    }
+}
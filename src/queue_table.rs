@@ -1,13 +1,133 @@
+use std::mem;
 use std::sync::{Arc,Mutex,RwLock};
-use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64,Ordering};
+use std::collections::{HashMap,HashSet};
 use std::collections::VecDeque;
+use std::time::{Duration,Instant};
+
+use clients::{Clients};
+use cluster::{Cluster};
+use persistence::{Persistence};
+use memory::{Memory};
+use monitor::{Monitors};
+use replication::{Replication,WriteConcern,Operation};
+use shards::{Shards};
+use slowlog::{SlowLog};
+use stats::{ServerStats};
+use stream::{Stream};
 
 pub type QueueName = String;
+pub type MessageGroup = String;
+
+// Every change to any queue takes the next version, so a version seen earlier can't
+// match a queue that has changed or was recreated under the same name since
+static VERSIONS: AtomicU64 = AtomicU64::new(1);
+// An item dropped to make room or expired, with the dead letter queue it was moved to
+pub type Removed = (Item, Option<QueueName>);
+
+#[derive(Clone)]
+pub struct Item {
+    pub data: String,
+    pub group: Option<MessageGroup>,
+    pub expires_at: Option<Instant>,
+    pub pushed_at: Instant,
+    // Times the item was popped by a transaction that rolled back, not replicated or persisted
+    pub rollbacks: u32
+}
+
+#[derive(PartialEq)]
+#[derive(Debug)]
+#[derive(Clone)]
+pub enum OverflowPolicy {
+    Reject,
+    DropOldest,
+    Block
+}
+
+#[derive(PartialEq)]
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct QueueLimits {
+    pub max_len: Option<usize>,
+    pub max_bytes: Option<usize>,
+    pub overflow: OverflowPolicy
+}
+
+#[derive(PartialEq)]
+#[derive(Debug)]
+pub enum PushError {
+    QueueFull,
+    OutOfMemory,
+    // Only returned for OverflowPolicy::Block, the caller should retry
+    WouldBlock
+}
+
+// Totals since the queue was created.
+// committed and rolled_back only count pops made inside a transaction.
+#[derive(PartialEq)]
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct QueueStats {
+    pub len: usize,
+    pub bytes: usize,
+    pub pushed: u64,
+    pub popped: u64,
+    pub committed: u64,
+    pub rolled_back: u64,
+    pub oldest_age: Option<Duration>
+}
+
+// Everything a follower needs to recreate a queue
+pub struct QueueSnapshot {
+    pub limits: QueueLimits,
+    pub dead_letter: Option<QueueName>,
+    pub write_concern: WriteConcern,
+    // Items held by open transactions come first as they were popped from the front
+    pub items: Vec<Item>
+}
+
+struct QueueInner {
+    items: VecDeque<Item>,
+    bytes: usize,
+    limits: QueueLimits,
+    memory: Memory,
+    // Groups with an item held by an open transaction
+    held_groups: HashSet<MessageGroup>,
+    // Copies of items popped by open transactions, they are still committed data
+    held: Vec<Item>,
+    // Where expired items are moved to, they are discarded if unset
+    dead_letter: Option<(QueueName, Queue)>,
+    // Items that left the queue without being popped, until they are published to followers
+    removed: Vec<Removed>,
+    // Changes when items are added, removed or released, or the limits change
+    version: u64,
+    // Followers that must have a push or commit touching this queue
+    write_concern: WriteConcern,
+    pushed: u64,
+    popped: u64,
+    committed: u64,
+    rolled_back: u64
+}
+
 pub struct Queue {
-    inner: Arc<Mutex<VecDeque<String>>>
+    inner: Arc<Mutex<QueueInner>>
 }
 pub struct QueueTable {
-    inner: Arc<RwLock<HashMap<QueueName, Queue>>>
+    inner: Arc<RwLock<HashMap<QueueName, Queue>>>,
+    streams: Arc<RwLock<HashMap<QueueName, Stream>>>,
+    memory: Memory,
+    stats: ServerStats,
+    slowlog: SlowLog,
+    monitors: Monitors,
+    clients: Clients,
+    replication: Replication,
+    persistence: Persistence,
+    cluster: Cluster,
+    shards: Shards,
+    // Limits given to newly created queues
+    default_limits: Arc<RwLock<QueueLimits>>,
+    // Rolled back items that stay held until their delay is over, by when that is
+    delayed: Arc<Mutex<Vec<(Instant, QueueName, Item)>>>
 }
 
 fn get_queue_with_lock(lock: &HashMap<QueueName, Queue>, queue_name: &QueueName) -> Option<Queue> {
@@ -20,25 +140,408 @@ fn get_queue_with_lock(lock: &HashMap<QueueName, Queue>, queue_name: &QueueName)
     }
 }
 
-fn create_queue(lock: &mut HashMap<QueueName, Queue>, queue_name: QueueName) -> Queue {
-    let queue = Queue::new();
+fn create_queue(lock: &mut HashMap<QueueName, Queue>, queue_name: QueueName, memory: &Memory, limits: QueueLimits) -> Queue {
+    let queue = Queue::with_memory(memory.clone());
+    queue.set_limits(limits);
     lock.insert(queue_name, queue.clone());
     return queue;
 }
 
+impl Item {
+    pub fn new(data: String, group: Option<MessageGroup>, expires_at: Option<Instant>) -> Item {
+        Item { data, group, expires_at, pushed_at: Instant::now(), rollbacks: 0 }
+    }
+
+    fn is_same(&self, other: &Item) -> bool {
+        self.data == other.data && self.group == other.group && self.pushed_at == other.pushed_at
+    }
+
+    fn is_expired(&self, now: Instant) -> bool {
+        match self.expires_at {
+            Some(expires_at) => expires_at <= now,
+            None => false
+        }
+    }
+}
+
+impl QueueLimits {
+    pub fn unlimited() -> QueueLimits {
+        QueueLimits { max_len: None, max_bytes: None, overflow: OverflowPolicy::Reject }
+    }
+}
+
+impl QueueInner {
+    fn has_room_for(&self, bytes: usize) -> bool {
+        let len_ok = match self.limits.max_len {
+            Some(max_len) => self.items.len() < max_len,
+            None => true
+        };
+        let bytes_ok = match self.limits.max_bytes {
+            Some(max_bytes) => self.bytes + bytes <= max_bytes,
+            None => true
+        };
+        len_ok && bytes_ok
+    }
+
+    fn push_item(&mut self, item: Item) -> Result<(),PushError> {
+        let bytes = item.data.len();
+        if let Some(max_bytes) = self.limits.max_bytes {
+            if bytes > max_bytes {
+                return Err(PushError::QueueFull);
+            }
+        }
+        if !self.has_room_for(bytes) {
+            match self.limits.overflow {
+                OverflowPolicy::Reject => {
+                    return Err(PushError::QueueFull);
+                }
+                OverflowPolicy::Block => {
+                    return Err(PushError::WouldBlock);
+                }
+                OverflowPolicy::DropOldest => {
+                    while !self.has_room_for(bytes) {
+                        match self.remove_at(0) {
+                            Some(item) => {
+                                self.removed.push((item, None));
+                            }
+                            None => {
+                                break;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        if self.memory.reserve(bytes).is_err() {
+            return Err(PushError::OutOfMemory);
+        }
+        self.bytes += bytes;
+        self.items.push_back(item);
+        self.pushed += 1;
+        self.changed();
+        Ok(())
+    }
+
+    fn remove_at(&mut self, position: usize) -> Option<Item> {
+        let item = self.items.remove(position);
+        if let Some(ref item) = item {
+            self.bytes -= item.data.len();
+            self.memory.release(item.data.len());
+            self.changed();
+        }
+        item
+    }
+
+    fn changed(&mut self) {
+        self.version = VERSIONS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Skips items whose group already has an item held.
+    // Expired items found on the way are removed into expired.
+    fn take_next(&mut self, expired: &mut Vec<Item>) -> Option<Item> {
+        let now = Instant::now();
+        let mut position = 0;
+        while position < self.items.len() {
+            if self.items[position].is_expired(now) {
+                expired.extend(self.remove_at(position));
+                continue;
+            }
+            let available = match self.items[position].group {
+                Some(ref group) => !self.held_groups.contains(group),
+                None => true
+            };
+            if available {
+                self.popped += 1;
+                return self.remove_at(position);
+            }
+            position += 1;
+        }
+        None
+    }
+
+    fn release_held(&mut self, item: &Item) {
+        if let Some(position) = self.held.iter().position(|held| held.is_same(item)) {
+            self.held.remove(position);
+        }
+    }
+
+    fn take_expired(&mut self, expired: &mut Vec<Item>) {
+        let now = Instant::now();
+        let mut position = 0;
+        while position < self.items.len() {
+            if self.items[position].is_expired(now) {
+                expired.extend(self.remove_at(position));
+            } else {
+                position += 1;
+            }
+        }
+    }
+}
+
 impl Queue {
     pub fn new() -> Queue {
-        Queue { inner: Arc::new(Mutex::new(VecDeque::new())) }
+        Queue::with_memory(Memory::new())
+    }
+
+    pub fn with_memory(memory: Memory) -> Queue {
+        Queue {
+            inner: Arc::new(Mutex::new(QueueInner {
+                items: VecDeque::new(),
+                bytes: 0,
+                limits: QueueLimits::unlimited(),
+                memory,
+                held_groups: HashSet::new(),
+                held: Vec::new(),
+                dead_letter: None,
+                removed: Vec::new(),
+                version: VERSIONS.fetch_add(1, Ordering::Relaxed),
+                write_concern: WriteConcern::none(),
+                pushed: 0,
+                popped: 0,
+                committed: 0,
+                rolled_back: 0
+            }))
+        }
+    }
+
+    pub fn push_back(&self, value: String) -> Result<(),PushError> {
+        self.push_back_with_group(value, None)
+    }
+
+    pub fn push_back_with_group(&self, value: String, group: Option<MessageGroup>) -> Result<(),PushError> {
+        self.push_item(Item::new(value, group, None))
+    }
+
+    pub fn push_item(&self, item: Item) -> Result<(),PushError> {
+        let mut queue = self.inner.lock().unwrap();
+        queue.push_item(item)
+    }
+
+    // Whether pushes of these sizes would all be accepted now, memory aside. Pushes that can
+    // never fit together are QueueFull even under the block policy, waiting wouldn't help.
+    pub fn check_room(&self, sizes: &[usize]) -> Result<(),PushError> {
+        let queue = self.inner.lock().unwrap();
+        let total: usize = sizes.iter().sum();
+        let too_many = queue.limits.max_len.is_some_and(|max_len| sizes.len() > max_len);
+        let too_big = queue.limits.max_bytes.is_some_and(|max_bytes| total > max_bytes);
+        if queue.limits.overflow == OverflowPolicy::DropOldest {
+            let item_too_big = queue.limits.max_bytes.is_some_and(|max_bytes| sizes.iter().any(|&bytes| bytes > max_bytes));
+            return if item_too_big { Err(PushError::QueueFull) } else { Ok(()) };
+        }
+        if too_many || too_big {
+            return Err(PushError::QueueFull);
+        }
+        let len_ok = queue.limits.max_len.is_none_or(|max_len| queue.items.len() + sizes.len() <= max_len);
+        let bytes_ok = queue.limits.max_bytes.is_none_or(|max_bytes| queue.bytes + total <= max_bytes);
+        if len_ok && bytes_ok {
+            Ok(())
+        } else if queue.limits.overflow == OverflowPolicy::Block {
+            Err(PushError::WouldBlock)
+        } else {
+            Err(PushError::QueueFull)
+        }
+    }
+
+    pub fn set_dead_letter(&self, dead_letter: Option<(QueueName, Queue)>) {
+        let mut queue = self.inner.lock().unwrap();
+        queue.dead_letter = dead_letter;
+    }
+
+    pub fn set_limits(&self, limits: QueueLimits) {
+        let mut queue = self.inner.lock().unwrap();
+        queue.limits = limits;
+        queue.changed();
+    }
+
+    // Differs from an earlier version once clients blocked on the queue may be able to continue
+    pub fn version(&self) -> u64 {
+        let queue = self.inner.lock().unwrap();
+        queue.version
     }
 
-    pub fn push_back(&self, value: String) {
+    pub fn set_write_concern(&self, write_concern: WriteConcern) {
         let mut queue = self.inner.lock().unwrap();
-        queue.push_back(value)
+        queue.write_concern = write_concern;
+    }
+
+    pub fn write_concern(&self) -> WriteConcern {
+        let queue = self.inner.lock().unwrap();
+        queue.write_concern
+    }
+
+    pub fn len(&self) -> usize {
+        let queue = self.inner.lock().unwrap();
+        queue.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn bytes(&self) -> usize {
+        let queue = self.inner.lock().unwrap();
+        queue.bytes
     }
 
     pub fn pop_front(&self) -> Option<String> {
+        self.pop_front_item().map(|item| item.data)
+    }
+
+    pub fn pop_front_item(&self) -> Option<Item> {
+        let mut expired = Vec::new();
+        let item = {
+            let mut queue = self.inner.lock().unwrap();
+            queue.take_next(&mut expired)
+        };
+        self.dead_letter(expired);
+        item
+    }
+
+    // Pops an item and holds its group until release_group or requeue
+    pub fn pop_front_held(&self) -> Option<Item> {
+        let mut expired = Vec::new();
+        let item = {
+            let mut queue = self.inner.lock().unwrap();
+            let item = queue.take_next(&mut expired);
+            if let Some(ref item) = item {
+                if let Some(ref group) = item.group {
+                    queue.held_groups.insert(group.clone());
+                }
+                queue.held.push(item.clone());
+            }
+            item
+        };
+        self.dead_letter(expired);
+        item
+    }
+
+    // Returns the number of expired items removed
+    pub fn remove_expired(&self) -> usize {
+        let mut expired = Vec::new();
+        {
+            let mut queue = self.inner.lock().unwrap();
+            queue.take_expired(&mut expired);
+        }
+        let count = expired.len();
+        self.dead_letter(expired);
+        count
+    }
+
+    // Called without holding the lock as the dead letter queue may be this queue.
+    // Limits are ignored, the items were already accepted once and would otherwise be lost.
+    fn dead_letter(&self, expired: Vec<Item>) {
+        if expired.is_empty() {
+            return;
+        }
+        let dead_letter = {
+            let queue = self.inner.lock().unwrap();
+            queue.dead_letter.clone()
+        };
+        if let Some((_, ref dead_letter)) = dead_letter {
+            for item in expired.iter() {
+                dead_letter.restore(Item::new(item.data.clone(), item.group.clone(), None));
+            }
+        }
+        let dead_letter_name = dead_letter.map(|(name, _)| name);
+        let mut queue = self.inner.lock().unwrap();
+        queue.removed.extend(expired.into_iter().map(|item| (item, dead_letter_name.clone())));
+    }
+
+    // The items dropped or expired since the last call, for publishing to followers
+    pub fn take_removed(&self) -> Vec<Removed> {
+        let mut queue = self.inner.lock().unwrap();
+        mem::take(&mut queue.removed)
+    }
+
+    pub fn release_group(&self, group: &MessageGroup) {
+        let mut queue = self.inner.lock().unwrap();
+        queue.held_groups.remove(group);
+        queue.changed();
+    }
+
+    // Called once a transaction holding item commits
+    pub fn commit_held(&self, item: &Item) {
+        let mut queue = self.inner.lock().unwrap();
+        if let Some(ref group) = item.group {
+            queue.held_groups.remove(group);
+        }
+        queue.release_held(item);
+        queue.committed += 1;
+        queue.changed();
+    }
+
+    // Removes the first item with the same data and group, for replicated pops.
+    // Returns false if there is none, e.g. because it already expired.
+    pub fn remove_item(&self, data: &str, group: &Option<MessageGroup>) -> bool {
+        let mut queue = self.inner.lock().unwrap();
+        match queue.items.iter().position(|item| item.data == data && item.group == *group) {
+            Some(position) => {
+                queue.remove_at(position);
+                queue.popped += 1;
+                true
+            }
+            None => false
+        }
+    }
+
+    // Adds an item from a leader's snapshot, ignoring limits as the leader already accepted it
+    pub fn restore(&self, item: Item) {
+        let mut queue = self.inner.lock().unwrap();
+        queue.bytes += item.data.len();
+        queue.memory.force_reserve(item.data.len());
+        queue.items.push_back(item);
+        queue.changed();
+    }
+
+    pub fn snapshot(&self) -> QueueSnapshot {
+        let queue = self.inner.lock().unwrap();
+        QueueSnapshot {
+            limits: queue.limits.clone(),
+            dead_letter: queue.dead_letter.as_ref().map(|(name, _)| name.clone()),
+            write_concern: queue.write_concern,
+            items: queue.held.iter().chain(queue.items.iter()).cloned().collect()
+        }
+    }
+
+    // Drops every item, held items belong to their transactions and are left alone
+    pub fn clear(&self) {
+        let mut queue = self.inner.lock().unwrap();
+        while queue.remove_at(0).is_some() {
+        }
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        let queue = self.inner.lock().unwrap();
+        QueueStats {
+            len: queue.items.len(),
+            bytes: queue.bytes,
+            pushed: queue.pushed,
+            popped: queue.popped,
+            committed: queue.committed,
+            rolled_back: queue.rolled_back,
+            oldest_age: queue.items.iter().map(|item| item.pushed_at).min().map(|pushed_at| pushed_at.elapsed())
+        }
+    }
+
+    // Returns a rolled back item, ignoring limits as it was already accepted once.
+    // Grouped items go back to the front so their group keeps its order.
+    pub fn requeue(&self, mut item: Item) {
+        item.rollbacks += 1;
         let mut queue = self.inner.lock().unwrap();
-        queue.pop_front()
+        queue.bytes += item.data.len();
+        queue.memory.force_reserve(item.data.len());
+        queue.rolled_back += 1;
+        queue.release_held(&item);
+        match item.group.clone() {
+            Some(group) => {
+                queue.held_groups.remove(&group);
+                queue.items.push_front(item);
+            }
+            None => {
+                queue.items.push_back(item);
+            }
+        }
+        queue.changed();
     }
 }
 
@@ -52,7 +555,21 @@ impl Clone for Queue {
 
 impl QueueTable {
     pub fn new() -> QueueTable {
-        QueueTable { inner: Arc::new(RwLock::new(HashMap::new())) }
+        QueueTable {
+            inner: Arc::new(RwLock::new(HashMap::new())),
+            streams: Arc::new(RwLock::new(HashMap::new())),
+            memory: Memory::new(),
+            stats: ServerStats::new(),
+            slowlog: SlowLog::new(),
+            monitors: Monitors::new(),
+            clients: Clients::new(),
+            replication: Replication::new(),
+            persistence: Persistence::new(),
+            cluster: Cluster::new(),
+            shards: Shards::new(),
+            default_limits: Arc::new(RwLock::new(QueueLimits::unlimited())),
+            delayed: Arc::new(Mutex::new(Vec::new()))
+        }
     }
 
     pub fn get_or_create_queue(&self, queue_name: QueueName) -> Queue {
@@ -68,7 +585,8 @@ impl QueueTable {
                 queue
             }
             None => {
-                create_queue(&mut write_lock, queue_name)
+                let limits = self.default_limits.read().unwrap().clone();
+                create_queue(&mut write_lock, queue_name, &self.memory, limits)
             }
         }
     }
@@ -77,12 +595,185 @@ impl QueueTable {
         let read_lock = self.inner.read().unwrap();
         get_queue_with_lock(&read_lock, &queue_name)
     }
+
+    pub fn get_or_create_stream(&self, stream_name: QueueName) -> Stream {
+        if let Some(stream) = self.get_stream(&stream_name) {
+            return stream;
+        }
+        let mut write_lock = self.streams.write().unwrap();
+        let memory = self.memory.clone();
+        write_lock.entry(stream_name).or_insert_with(|| Stream::with_memory(memory)).clone()
+    }
+
+    pub fn get_stream(&self, stream_name: &QueueName) -> Option<Stream> {
+        let read_lock = self.streams.read().unwrap();
+        read_lock.get(stream_name).cloned()
+    }
+
+    pub fn set_default_limits(&self, limits: QueueLimits) {
+        let mut default_limits = self.default_limits.write().unwrap();
+        *default_limits = limits;
+    }
+
+    pub fn set_dead_letter(&self, queue_name: QueueName, dead_letter_name: QueueName) {
+        let dead_letter = self.get_or_create_queue(dead_letter_name.clone());
+        self.get_or_create_queue(queue_name).set_dead_letter(Some((dead_letter_name, dead_letter)));
+    }
+
+    // Run periodically so expired items don't hold memory in idle queues.
+    // Followers leave expiry to their leader, which publishes what it removed.
+    pub fn remove_expired(&self) -> usize {
+        if self.replication.is_follower() || !self.cluster.accepts_writes() {
+            return 0;
+        }
+        let queues: Vec<(QueueName, Queue)> = {
+            let read_lock = self.inner.read().unwrap();
+            read_lock.iter().map(|(name, queue)| (name.clone(), queue.clone())).collect()
+        };
+        let mut count = 0;
+        for (queue_name, queue) in queues {
+            count += queue.remove_expired();
+            self.publish_removed(&queue_name, &queue);
+        }
+        count
+    }
+
+    // Tells followers about the items the queue dropped or expired, as they don't apply
+    // limits or expire items themselves. A moved item is restored to the dead letter queue
+    // as the leader's dead lettering ignores the dead letter queue's limits too.
+    pub fn publish_removed(&self, queue_name: &QueueName, queue: &Queue) {
+        let removed = queue.take_removed();
+        if removed.is_empty() || !(self.replication.is_active() || self.persistence.is_enabled() || self.cluster.is_enabled()) {
+            return;
+        }
+        for (item, dead_letter) in removed {
+            let mut operations = vec![Operation::remove(queue_name, &item)];
+            if let Some(dead_letter) = dead_letter {
+                operations.push(Operation::Restore(dead_letter, item.data, item.group, None));
+            }
+            for operation in operations {
+                self.replication.publish(&operation);
+                self.persistence.append(&operation);
+                self.cluster.propose(&operation);
+            }
+        }
+    }
+
+    pub fn memory(&self) -> &Memory {
+        &self.memory
+    }
+
+    pub fn stats(&self) -> &ServerStats {
+        &self.stats
+    }
+
+    pub fn slowlog(&self) -> &SlowLog {
+        &self.slowlog
+    }
+
+    pub fn monitors(&self) -> &Monitors {
+        &self.monitors
+    }
+
+    pub fn clients(&self) -> &Clients {
+        &self.clients
+    }
+
+    pub fn replication(&self) -> &Replication {
+        &self.replication
+    }
+
+    pub fn persistence(&self) -> &Persistence {
+        &self.persistence
+    }
+
+    pub fn cluster(&self) -> &Cluster {
+        &self.cluster
+    }
+
+    pub fn shards(&self) -> &Shards {
+        &self.shards
+    }
+
+    // Sorted by queue name
+    pub fn queues(&self) -> Vec<(QueueName, Queue)> {
+        let mut queues: Vec<(QueueName, Queue)> = {
+            let read_lock = self.inner.read().unwrap();
+            read_lock.iter().map(|(name, queue)| (name.clone(), queue.clone())).collect()
+        };
+        queues.sort_by(|a, b| a.0.cmp(&b.0));
+        queues
+    }
+
+    // Sorted by stream name
+    pub fn streams(&self) -> Vec<(QueueName, Stream)> {
+        let mut streams: Vec<(QueueName, Stream)> = {
+            let read_lock = self.streams.read().unwrap();
+            read_lock.iter().map(|(name, stream)| (name.clone(), stream.clone())).collect()
+        };
+        streams.sort_by(|a, b| a.0.cmp(&b.0));
+        streams
+    }
+
+    pub fn queue_stats(&self) -> Vec<(QueueName, QueueStats)> {
+        self.queues().into_iter().map(|(name, queue)| (name, queue.stats())).collect()
+    }
+
+    // Rolls back a popped item once the delay is over. Until then it stays held, so it is
+    // still in snapshots and its message group stays blocked.
+    pub fn requeue_after(&self, queue_name: QueueName, item: Item, delay: Duration) {
+        self.delayed.lock().unwrap().push((Instant::now() + delay, queue_name, item));
+    }
+
+    // Called by the event loop
+    pub fn requeue_delayed(&self) {
+        let now = Instant::now();
+        let due: Vec<(Instant, QueueName, Item)> = {
+            let mut delayed = self.delayed.lock().unwrap();
+            let (due, waiting) = mem::take(&mut *delayed).into_iter().partition(|&(at, _, _)| at <= now);
+            *delayed = waiting;
+            due
+        };
+        for (_, queue_name, item) in due {
+            self.get_or_create_queue(queue_name).requeue(item);
+        }
+    }
+
+    // When requeue_delayed has something to do next
+    pub fn next_requeue(&self) -> Option<Instant> {
+        self.delayed.lock().unwrap().iter().map(|&(at, _, _)| at).min()
+    }
+
+    // Removes every queue and stream, before a follower loads a new snapshot
+    pub fn clear(&self) {
+        self.delayed.lock().unwrap().clear();
+        let queues: Vec<Queue> = self.inner.write().unwrap().drain().map(|(_, queue)| queue).collect();
+        for queue in queues {
+            queue.clear();
+        }
+        let streams: Vec<Stream> = self.streams.write().unwrap().drain().map(|(_, stream)| stream).collect();
+        for stream in streams {
+            stream.clear();
+        }
+    }
 }
 
 impl Clone for QueueTable {
     fn clone(&self) -> QueueTable {
         QueueTable {
-            inner: self.inner.clone()
+            inner: self.inner.clone(),
+            streams: self.streams.clone(),
+            memory: self.memory.clone(),
+            stats: self.stats.clone(),
+            slowlog: self.slowlog.clone(),
+            monitors: self.monitors.clone(),
+            clients: self.clients.clone(),
+            replication: self.replication.clone(),
+            persistence: self.persistence.clone(),
+            cluster: self.cluster.clone(),
+            shards: self.shards.clone(),
+            default_limits: self.default_limits.clone(),
+            delayed: self.delayed.clone()
         }
     }
 }
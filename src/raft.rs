@@ -0,0 +1,622 @@
+use std::collections::{HashMap,HashSet};
+use std::time::{Duration,Instant};
+
+use parse_commands::{quote};
+
+// Most entries sent to a follower in one append
+const MAX_APPEND_ENTRIES: usize = 512;
+
+pub type NodeId = u64;
+pub type Term = u64;
+// Position in the log, the first entry is 1
+pub type Index = u64;
+
+#[derive(PartialEq)]
+#[derive(Debug)]
+#[derive(Clone,Copy)]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader
+}
+
+// data is an encoded replication operation, empty for the entry a new leader starts its term with
+#[derive(PartialEq)]
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct Entry {
+    pub term: Term,
+    pub data: String
+}
+
+// Every message is one way, replies are sent back as messages of their own
+#[derive(PartialEq)]
+#[derive(Debug)]
+#[derive(Clone)]
+pub enum Message {
+    RequestVote { term: Term, candidate: NodeId, last_index: Index, last_term: Term },
+    Vote { term: Term, from: NodeId, granted: bool },
+    Append { term: Term, leader: NodeId, prev_index: Index, prev_term: Term, commit: Index, entries: Vec<Entry> },
+    // On failure last_index is where the leader should try from next
+    Appended { term: Term, from: NodeId, success: bool, last_index: Index },
+    // Sent instead of Append once the entries a follower needs were compacted
+    Snapshot { term: Term, leader: NodeId, index: Index, snapshot_term: Term, data: Vec<String> }
+}
+
+// Entries up to base_index were compacted into base, the operations that recreate them
+struct Log {
+    base_index: Index,
+    base_term: Term,
+    base: Vec<String>,
+    entries: Vec<Entry>
+}
+
+// What a commit wait is told about the entry it proposed
+#[derive(PartialEq)]
+#[derive(Debug)]
+pub enum EntryState {
+    Pending,
+    Committed,
+    // Replaced by a new leader's entry, the write is gone
+    Lost
+}
+
+// The consensus state of one node. It does no IO: messages are passed to step,
+// time to tick, and whatever needs sending is collected with take_messages.
+// Nothing is persisted, so a restarted node waits an election timeout before voting in case
+// it voted in a term it forgot. Its log is gone too, which may have held entries counted
+// towards a commit, so until it has caught up to a leader's commit index it only votes for
+// candidates with an empty log, as when a whole cluster starts.
+pub struct RaftNode {
+    id: NodeId,
+    peers: Vec<NodeId>,
+    term: Term,
+    voted_for: Option<NodeId>,
+    role: Role,
+    leader: Option<NodeId>,
+    log: Log,
+    commit: Index,
+    next_index: HashMap<NodeId, Index>,
+    match_index: HashMap<NodeId, Index>,
+    votes: HashSet<NodeId>,
+    election_timeout: Duration,
+    election_deadline: Instant,
+    heartbeat_deadline: Instant,
+    voting_from: Instant,
+    caught_up: bool,
+    // For randomising election timeouts
+    random: u64,
+    // Set when the node stops being leader or installs a snapshot, the state machine must then be rebuilt
+    reset: bool,
+    outbox: Vec<(NodeId, Message)>
+}
+
+impl Log {
+    fn last_index(&self) -> Index {
+        self.base_index + self.entries.len() as Index
+    }
+
+    fn last_term(&self) -> Term {
+        self.entries.last().map_or(self.base_term, |entry| entry.term)
+    }
+
+    // None if the entry was compacted or doesn't exist yet
+    fn entry(&self, index: Index) -> Option<&Entry> {
+        if index <= self.base_index {
+            None
+        } else {
+            self.entries.get((index - self.base_index - 1) as usize)
+        }
+    }
+
+    fn term_at(&self, index: Index) -> Option<Term> {
+        if index == self.base_index {
+            Some(self.base_term)
+        } else {
+            self.entry(index).map(|entry| entry.term)
+        }
+    }
+
+    fn entries_from(&self, index: Index, max: usize) -> Vec<Entry> {
+        let start = (index - self.base_index - 1) as usize;
+        self.entries.iter().skip(start).take(max).cloned().collect()
+    }
+
+    fn truncate_from(&mut self, index: Index) {
+        let keep = (index - self.base_index - 1) as usize;
+        self.entries.truncate(keep);
+    }
+}
+
+impl Message {
+    pub fn term(&self) -> Term {
+        match *self {
+            Message::RequestVote { term, .. } | Message::Vote { term, .. } | Message::Append { term, .. } |
+            Message::Appended { term, .. } | Message::Snapshot { term, .. } => term
+        }
+    }
+
+    pub fn sender(&self) -> NodeId {
+        match *self {
+            Message::RequestVote { candidate, .. } => candidate,
+            Message::Vote { from, .. } | Message::Appended { from, .. } => from,
+            Message::Append { leader, .. } | Message::Snapshot { leader, .. } => leader
+        }
+    }
+
+    // Written as a RAFT command. Entry data is hex encoded as commands can't contain ;
+    pub fn encode(&self) -> String {
+        let arguments = match *self {
+            Message::RequestVote { term, candidate, last_index, last_term } => {
+                vec!["requestvote".to_string(), term.to_string(), candidate.to_string(), last_index.to_string(), last_term.to_string()]
+            }
+            Message::Vote { term, from, granted } => {
+                vec!["vote".to_string(), term.to_string(), from.to_string(), (granted as u8).to_string()]
+            }
+            Message::Append { term, leader, prev_index, prev_term, commit, ref entries } => {
+                let mut arguments = vec!["append".to_string(), term.to_string(), leader.to_string(), prev_index.to_string(),
+                                         prev_term.to_string(), commit.to_string()];
+                for entry in entries.iter() {
+                    arguments.push(entry.term.to_string());
+                    arguments.push(hex_encode(&entry.data));
+                }
+                arguments
+            }
+            Message::Appended { term, from, success, last_index } => {
+                vec!["appended".to_string(), term.to_string(), from.to_string(), (success as u8).to_string(), last_index.to_string()]
+            }
+            Message::Snapshot { term, leader, index, snapshot_term, ref data } => {
+                let mut arguments = vec!["snapshot".to_string(), term.to_string(), leader.to_string(), index.to_string(),
+                                         snapshot_term.to_string()];
+                arguments.extend(data.iter().map(|operation| hex_encode(operation)));
+                arguments
+            }
+        };
+        let quoted: Vec<String> = arguments.iter().map(|argument| quote(argument)).collect();
+        format!("RAFT {};", quoted.join(" "))
+    }
+
+    // The arguments of a RAFT command
+    pub fn parse(arguments: &[String]) -> Result<Message, String> {
+        let kind = arguments.first().map(|kind| kind.to_lowercase()).unwrap_or_default();
+        let numbers = |count: usize| -> Result<Vec<u64>, String> {
+            if arguments.len() < count + 1 {
+                return Err(format!("Incorrect number of arguments for RAFT {}", kind));
+            }
+            arguments[1..count + 1].iter().map(|argument| {
+                argument.parse::<u64>().map_err(|_| format!("Invalid number: {}", argument))
+            }).collect()
+        };
+        match &kind as &str {
+            "requestvote" if arguments.len() == 5 => {
+                let n = numbers(4)?;
+                Ok(Message::RequestVote { term: n[0], candidate: n[1], last_index: n[2], last_term: n[3] })
+            }
+            "vote" if arguments.len() == 4 => {
+                let n = numbers(3)?;
+                Ok(Message::Vote { term: n[0], from: n[1], granted: n[2] != 0 })
+            }
+            "append" if arguments.len() >= 6 && arguments.len().is_multiple_of(2) => {
+                let n = numbers(5)?;
+                let mut entries = Vec::new();
+                for pair in arguments[6..].chunks(2) {
+                    let term = pair[0].parse::<u64>().map_err(|_| format!("Invalid number: {}", pair[0]))?;
+                    entries.push(Entry { term, data: hex_decode(&pair[1])? });
+                }
+                Ok(Message::Append { term: n[0], leader: n[1], prev_index: n[2], prev_term: n[3], commit: n[4], entries })
+            }
+            "appended" if arguments.len() == 5 => {
+                let n = numbers(4)?;
+                Ok(Message::Appended { term: n[0], from: n[1], success: n[2] != 0, last_index: n[3] })
+            }
+            "snapshot" if arguments.len() >= 5 => {
+                let n = numbers(4)?;
+                let mut data = Vec::new();
+                for operation in arguments[5..].iter() {
+                    data.push(hex_decode(operation)?);
+                }
+                Ok(Message::Snapshot { term: n[0], leader: n[1], index: n[2], snapshot_term: n[3], data })
+            }
+            "requestvote" | "vote" | "append" | "appended" | "snapshot" => {
+                Err(format!("Incorrect number of arguments for RAFT {}", kind))
+            }
+            _ => {
+                Err(format!("Unknown RAFT message: {}", kind))
+            }
+        }
+    }
+}
+
+impl RaftNode {
+    // peers excludes id. Elections time out between election_timeout and twice that.
+    pub fn new(id: NodeId, peers: Vec<NodeId>, election_timeout: Duration, now: Instant) -> RaftNode {
+        let mut node = RaftNode {
+            id,
+            peers,
+            term: 0,
+            voted_for: None,
+            role: Role::Follower,
+            leader: None,
+            log: Log { base_index: 0, base_term: 0, base: Vec::new(), entries: Vec::new() },
+            commit: 0,
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
+            votes: HashSet::new(),
+            election_timeout,
+            election_deadline: now,
+            heartbeat_deadline: now,
+            voting_from: now + election_timeout,
+            caught_up: false,
+            random: 0x9e3779b97f4a7c15 ^ id.wrapping_mul(0xbf58476d1ce4e5b9),
+            reset: false,
+            outbox: Vec::new()
+        };
+        node.reset_election_deadline(now);
+        node
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    pub fn term(&self) -> Term {
+        self.term
+    }
+
+    pub fn leader(&self) -> Option<NodeId> {
+        self.leader
+    }
+
+    pub fn commit_index(&self) -> Index {
+        self.commit
+    }
+
+    pub fn last_index(&self) -> Index {
+        self.log.last_index()
+    }
+
+    pub fn base_index(&self) -> Index {
+        self.log.base_index
+    }
+
+    pub fn base(&self) -> &[String] {
+        &self.log.base
+    }
+
+    // None once compacted
+    pub fn entry(&self, index: Index) -> Option<&Entry> {
+        self.log.entry(index)
+    }
+
+    // For the entry proposed at index in term
+    pub fn entry_state(&self, index: Index, term: Term) -> EntryState {
+        match self.log.term_at(index) {
+            Some(found) if found != term => EntryState::Lost,
+            Some(_) if index <= self.commit => EntryState::Committed,
+            Some(_) => EntryState::Pending,
+            // Compacted entries were committed, though after losing leadership it may not be ours
+            None if index < self.log.base_index => {
+                if self.role == Role::Leader && self.term == term { EntryState::Committed } else { EntryState::Pending }
+            }
+            // Removed by a new leader
+            None => EntryState::Lost
+        }
+    }
+
+    // Whether the state machine has to be rebuilt from the log since the last call
+    pub fn take_reset(&mut self) -> bool {
+        let reset = self.reset;
+        self.reset = false;
+        reset
+    }
+
+    pub fn take_messages(&mut self) -> Vec<(NodeId, Message)> {
+        self.outbox.drain(..).collect()
+    }
+
+    // Returns the index of the new entry, or None when not the leader
+    pub fn propose(&mut self, data: String) -> Option<Index> {
+        if self.role != Role::Leader {
+            return None;
+        }
+        let term = self.term;
+        self.log.entries.push(Entry { term, data });
+        self.advance_commit();
+        Some(self.log.last_index())
+    }
+
+    // Sends the leader's new entries, proposals are only sent once this is called
+    pub fn replicate(&mut self) {
+        if self.role == Role::Leader {
+            for peer in self.peers.clone() {
+                if self.next_index[&peer] <= self.log.last_index() {
+                    self.send_append(peer);
+                }
+            }
+        }
+    }
+
+    // Replaces the log up to index with base, which must recreate the state at index
+    pub fn compact(&mut self, index: Index, base: Vec<String>) {
+        if index <= self.log.base_index || index > self.commit {
+            return;
+        }
+        let term = self.log.term_at(index).unwrap();
+        let keep = self.log.entries.split_off((index - self.log.base_index) as usize);
+        self.log = Log { base_index: index, base_term: term, base, entries: keep };
+    }
+
+    pub fn tick(&mut self, now: Instant) {
+        match self.role {
+            Role::Leader => {
+                if now >= self.heartbeat_deadline {
+                    for peer in self.peers.clone() {
+                        self.send_append(peer);
+                    }
+                    self.heartbeat_deadline = now + self.election_timeout / 10;
+                }
+            }
+            Role::Follower | Role::Candidate => {
+                if now >= self.election_deadline {
+                    self.start_election(now);
+                }
+            }
+        }
+    }
+
+    pub fn step(&mut self, message: Message, now: Instant) {
+        if !self.peers.contains(&message.sender()) {
+            warn!(node = self.id, from = message.sender(); "ignoring message from a node outside the cluster");
+            return;
+        }
+        if message.term() > self.term {
+            let leader = match message {
+                Message::Append { leader, .. } | Message::Snapshot { leader, .. } => Some(leader),
+                _ => None
+            };
+            self.become_follower(message.term(), leader);
+        }
+        match message {
+            Message::RequestVote { term, candidate, last_index, last_term } => {
+                let up_to_date = last_term > self.log.last_term() ||
+                    (last_term == self.log.last_term() && last_index >= self.log.last_index());
+                let granted = term == self.term && up_to_date && now >= self.voting_from &&
+                    (self.caught_up || last_index == 0) &&
+                    self.voted_for.is_none_or(|voted_for| voted_for == candidate);
+                if granted {
+                    self.voted_for = Some(candidate);
+                    self.reset_election_deadline(now);
+                }
+                let reply = Message::Vote { term: self.term, from: self.id, granted };
+                self.outbox.push((candidate, reply));
+            }
+            Message::Vote { term, from, granted } => {
+                if self.role == Role::Candidate && term == self.term && granted {
+                    self.votes.insert(from);
+                    if self.votes.len() >= self.quorum() {
+                        self.become_leader(now);
+                    }
+                }
+            }
+            Message::Append { term, leader, prev_index, prev_term, commit, entries } => {
+                if term < self.term {
+                    let reply = Message::Appended { term: self.term, from: self.id, success: false, last_index: self.log.last_index() };
+                    self.outbox.push((leader, reply));
+                    return;
+                }
+                self.heard_from_leader(leader, now);
+                let reply = self.append(prev_index, prev_term, commit, entries);
+                self.outbox.push((leader, reply));
+            }
+            Message::Appended { term, from, success, last_index } => {
+                if self.role != Role::Leader || term != self.term {
+                    return;
+                }
+                if success {
+                    let matched = self.match_index[&from].max(last_index);
+                    self.match_index.insert(from, matched);
+                    if self.next_index[&from] <= matched {
+                        self.next_index.insert(from, matched + 1);
+                    }
+                    self.advance_commit();
+                } else {
+                    // Pipelined appends are resent from where the follower's log ends
+                    let next = (last_index + 1).min(self.next_index[&from]).max(1);
+                    self.next_index.insert(from, next);
+                    self.send_append(from);
+                }
+            }
+            Message::Snapshot { term, leader, index, snapshot_term, data } => {
+                if term < self.term {
+                    let reply = Message::Appended { term: self.term, from: self.id, success: false, last_index: self.log.last_index() };
+                    self.outbox.push((leader, reply));
+                    return;
+                }
+                self.heard_from_leader(leader, now);
+                if index > self.commit {
+                    let keep = if self.log.term_at(index) == Some(snapshot_term) {
+                        self.log.entries_from(index + 1, usize::MAX)
+                    } else {
+                        Vec::new()
+                    };
+                    self.log = Log { base_index: index, base_term: snapshot_term, base: data, entries: keep };
+                    self.commit = index;
+                    self.reset = true;
+                }
+                let reply = Message::Appended { term: self.term, from: self.id, success: true, last_index: index.max(self.commit) };
+                self.outbox.push((leader, reply));
+            }
+        }
+    }
+
+    fn append(&mut self, prev_index: Index, prev_term: Term, commit: Index, entries: Vec<Entry>) -> Message {
+        let matches = prev_index < self.log.base_index || self.log.term_at(prev_index) == Some(prev_term);
+        if !matches {
+            let hint = self.log.last_index().min(prev_index.saturating_sub(1)).max(self.commit);
+            return Message::Appended { term: self.term, from: self.id, success: false, last_index: hint };
+        }
+        let mut index = prev_index;
+        for entry in entries {
+            index += 1;
+            if index <= self.log.base_index {
+                continue;
+            }
+            match self.log.term_at(index) {
+                Some(term) if term == entry.term => {
+                }
+                Some(_) => {
+                    self.log.truncate_from(index);
+                    self.log.entries.push(entry);
+                }
+                None => {
+                    self.log.entries.push(entry);
+                }
+            }
+        }
+        if commit > self.commit {
+            self.commit = commit.min(index);
+        }
+        if self.commit >= commit {
+            self.caught_up = true;
+        }
+        Message::Appended { term: self.term, from: self.id, success: true, last_index: index }
+    }
+
+    fn heard_from_leader(&mut self, leader: NodeId, now: Instant) {
+        if self.role != Role::Follower {
+            let term = self.term;
+            self.become_follower(term, Some(leader));
+        }
+        self.leader = Some(leader);
+        self.voting_from = now;
+        self.reset_election_deadline(now);
+    }
+
+    fn start_election(&mut self, now: Instant) {
+        self.term += 1;
+        self.role = Role::Candidate;
+        self.leader = None;
+        self.voted_for = Some(self.id);
+        self.votes = HashSet::new();
+        self.votes.insert(self.id);
+        self.reset_election_deadline(now);
+        info!(node = self.id, term = self.term; "starting election");
+        if self.votes.len() >= self.quorum() {
+            self.become_leader(now);
+            return;
+        }
+        let request = Message::RequestVote {
+            term: self.term,
+            candidate: self.id,
+            last_index: self.log.last_index(),
+            last_term: self.log.last_term()
+        };
+        for &peer in self.peers.iter() {
+            self.outbox.push((peer, request.clone()));
+        }
+    }
+
+    // The leader starts its term with an empty entry so earlier entries commit without waiting for a write
+    fn become_leader(&mut self, now: Instant) {
+        info!(node = self.id, term = self.term; "elected leader");
+        self.role = Role::Leader;
+        self.leader = Some(self.id);
+        self.caught_up = true;
+        let next = self.log.last_index() + 1;
+        for &peer in self.peers.iter() {
+            self.next_index.insert(peer, next);
+            self.match_index.insert(peer, 0);
+        }
+        let term = self.term;
+        self.log.entries.push(Entry { term, data: String::new() });
+        self.advance_commit();
+        for peer in self.peers.clone() {
+            self.send_append(peer);
+        }
+        self.heartbeat_deadline = now + self.election_timeout / 10;
+    }
+
+    fn become_follower(&mut self, term: Term, leader: Option<NodeId>) {
+        if self.role == Role::Leader {
+            info!(node = self.id, term = term; "no longer leader");
+            self.reset = true;
+        }
+        if term > self.term {
+            self.term = term;
+            self.voted_for = None;
+        }
+        self.role = Role::Follower;
+        self.leader = leader;
+    }
+
+    // Only entries from the leader's own term are committed by counting, earlier ones follow them
+    fn advance_commit(&mut self) {
+        let mut index = self.log.last_index();
+        while index > self.commit && self.log.term_at(index) == Some(self.term) {
+            let replicas = 1 + self.match_index.values().filter(|&&matched| matched >= index).count();
+            if replicas >= self.quorum() {
+                self.commit = index;
+                return;
+            }
+            index -= 1;
+        }
+    }
+
+    fn send_append(&mut self, peer: NodeId) {
+        let next = self.next_index[&peer];
+        let message = if next <= self.log.base_index {
+            self.next_index.insert(peer, self.log.base_index + 1);
+            Message::Snapshot {
+                term: self.term,
+                leader: self.id,
+                index: self.log.base_index,
+                snapshot_term: self.log.base_term,
+                data: self.log.base.clone()
+            }
+        } else {
+            let entries = self.log.entries_from(next, MAX_APPEND_ENTRIES);
+            self.next_index.insert(peer, next + entries.len() as Index);
+            Message::Append {
+                term: self.term,
+                leader: self.id,
+                prev_index: next - 1,
+                prev_term: self.log.term_at(next - 1).unwrap(),
+                commit: self.commit,
+                entries
+            }
+        };
+        self.outbox.push((peer, message));
+    }
+
+    fn quorum(&self) -> usize {
+        self.peers.len().div_ceil(2) + 1
+    }
+
+    fn reset_election_deadline(&mut self, now: Instant) {
+        // xorshift, good enough to spread out elections
+        self.random ^= self.random << 13;
+        self.random ^= self.random >> 7;
+        self.random ^= self.random << 17;
+        let millis = self.election_timeout.as_millis() as u64;
+        let jitter = if millis == 0 { 0 } else { self.random % millis };
+        self.election_deadline = now + self.election_timeout + Duration::from_millis(jitter);
+    }
+}
+
+fn hex_encode(data: &str) -> String {
+    data.bytes().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn hex_decode(data: &str) -> Result<String, String> {
+    if !data.len().is_multiple_of(2) {
+        return Err("Invalid hex data".to_string());
+    }
+    let bytes: Result<Vec<u8>, String> = (0..data.len()).step_by(2).map(|i| {
+        u8::from_str_radix(&data[i..i + 2], 16).map_err(|_| "Invalid hex data".to_string())
+    }).collect();
+    String::from_utf8(bytes?).map_err(|_| "Non utf8 characters in entry".to_string())
+}
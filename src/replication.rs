@@ -0,0 +1,610 @@
+use std::io;
+use std::io::{Read,Write};
+use std::net::{SocketAddr,TcpStream};
+use std::sync::{Arc,Mutex};
+use std::sync::atomic::{AtomicBool,AtomicU64,AtomicUsize,Ordering};
+use std::thread;
+use std::time::{Duration,Instant};
+
+use auth::{UserName};
+use commands::{Command};
+use parse_commands::{parse_parts,quote};
+use queue_table::{QueueTable,QueueName,MessageGroup,QueueLimits,OverflowPolicy,Item};
+use stats::{ClientId};
+use stream::{GroupName,Offset,Retention};
+
+// A follower this far behind is disconnected and syncs again from a new snapshot
+const MAX_BACKLOG: usize = 64 << 20;
+const READ_BUFFER_SIZE: usize = 4096;
+const CONNECT_TIMEOUT: u64 = 5;
+// How often a follower waiting on its leader checks whether it was promoted
+const READ_TIMEOUT: u64 = 1000;
+// Building the snapshot can take a while for a large leader
+const SYNC_TIMEOUT: u64 = 60;
+const RETRY_DELAY: u64 = 1000;
+
+// How many followers must have a write before the client is answered
+#[derive(PartialEq)]
+#[derive(Debug)]
+#[derive(Clone,Copy)]
+pub struct WriteConcern {
+    pub replicas: usize,
+    pub timeout: Duration
+}
+
+// Sequence number of a published operation, the first is 1
+pub type Sequence = u64;
+
+// Changes to committed data, sent from the leader to its followers.
+// Followers don't apply limits or expire items, the leader sends a Remove for every
+// item it drops or expires, and items rolled back on the leader keep their place on followers.
+#[derive(PartialEq)]
+#[derive(Debug)]
+pub enum Operation {
+    // Starts a snapshot, the follower drops everything it holds
+    Reset,
+    // Ends a snapshot, which holds every operation up to the sequence number
+    Synced(Sequence),
+    Push(QueueName, String, Option<MessageGroup>, Option<Duration>),
+    // Sent in snapshots and for items moved to a dead letter queue
+    Restore(QueueName, String, Option<MessageGroup>, Option<Duration>),
+    // A committed pop, removes the first item with the same data and group
+    Remove(QueueName, String, Option<MessageGroup>),
+    Limit(QueueName, QueueLimits),
+    DeadLetter(QueueName, QueueName),
+    StreamAppend(QueueName, String),
+    StreamCommit(QueueName, GroupName, Offset),
+    StreamRetain(QueueName, Retention),
+    // Only sent in snapshots so stream offsets match the leader's
+    StreamEntry(QueueName, Offset, String),
+    StreamNextOffset(QueueName, Offset),
+    WriteConcern(QueueName, WriteConcern)
+}
+
+// Operations waiting to be sent to one follower
+pub struct Backlog {
+    pub data: Vec<u8>,
+    // Set once the follower fell too far behind, it is then disconnected
+    pub overflowed: bool,
+    // Everything up to this was applied by the follower
+    pub acked: Option<Sequence>
+}
+
+pub type ReplicaFeed = Arc<Mutex<Backlog>>;
+
+struct ReplicationInner {
+    feeds: Mutex<Vec<(ClientId, ReplicaFeed)>>,
+    // Checked before encoding anything so there is no cost without followers
+    count: AtomicUsize,
+    // Set when an operation is published, the event loop then flushes followers
+    dirty: AtomicBool,
+    // Set when a follower acks, the event loop then retries writes waiting on followers
+    acks_received: AtomicBool,
+    last_sequence: AtomicU64,
+    following: AtomicBool,
+    // Held while a follower applies an operation so nothing is applied after PROMOTE
+    leader: Mutex<Option<String>>
+}
+
+// The followers of a leader, or the leader of a follower
+#[derive(Clone)]
+pub struct Replication {
+    inner: Arc<ReplicationInner>
+}
+
+impl WriteConcern {
+    pub fn none() -> WriteConcern {
+        WriteConcern { replicas: 0, timeout: Duration::from_secs(0) }
+    }
+
+    // The stricter of the two, for writes covered by both
+    pub fn combine(&self, other: &WriteConcern) -> WriteConcern {
+        WriteConcern {
+            replicas: self.replicas.max(other.replicas),
+            timeout: self.timeout.max(other.timeout)
+        }
+    }
+}
+
+impl Operation {
+    // The ttl left is sent as the clocks of leader and follower are unrelated
+    pub fn push(queue_name: &QueueName, item: &Item) -> Operation {
+        let (data, group, ttl) = item_parts(item);
+        Operation::Push(queue_name.clone(), data, group, ttl)
+    }
+
+    pub fn restore(queue_name: &QueueName, item: &Item) -> Operation {
+        let (data, group, ttl) = item_parts(item);
+        Operation::Restore(queue_name.clone(), data, group, ttl)
+    }
+
+    pub fn remove(queue_name: &QueueName, item: &Item) -> Operation {
+        Operation::Remove(queue_name.clone(), item.data.clone(), item.group.clone())
+    }
+
+    // Written like a command, operations shared with commands use the same arguments
+    pub fn encode(&self) -> String {
+        match *self {
+            Operation::Reset => {
+                "RESET;".to_string()
+            }
+            Operation::Synced(sequence) => {
+                format!("SYNCED {};", quote(&sequence.to_string()))
+            }
+            Operation::Push(ref queue_name, ref data, ref group, ttl) => {
+                format!("PUSH {} {} {} {};", quote(queue_name), quote(data), quote(group_or_empty(group)), quote(&ttl_millis(ttl)))
+            }
+            Operation::Restore(ref queue_name, ref data, ref group, ttl) => {
+                format!("RESTORE {} {} {} {};", quote(queue_name), quote(data), quote(group_or_empty(group)), quote(&ttl_millis(ttl)))
+            }
+            Operation::Remove(ref queue_name, ref data, ref group) => {
+                format!("REMOVE {} {} {};", quote(queue_name), quote(data), quote(group_or_empty(group)))
+            }
+            Operation::Limit(ref queue_name, ref limits) => {
+                let overflow = match limits.overflow {
+                    OverflowPolicy::Reject => "reject",
+                    OverflowPolicy::DropOldest => "drop",
+                    OverflowPolicy::Block => "block"
+                };
+                format!("LIMIT {} {} {} {};", quote(queue_name), quote(&limits.max_len.unwrap_or(0).to_string()),
+                        quote(&limits.max_bytes.unwrap_or(0).to_string()), quote(overflow))
+            }
+            Operation::DeadLetter(ref queue_name, ref dead_letter_name) => {
+                format!("DEADLETTER {} {};", quote(queue_name), quote(dead_letter_name))
+            }
+            Operation::StreamAppend(ref stream_name, ref data) => {
+                format!("SAPPEND {} {};", quote(stream_name), quote(data))
+            }
+            Operation::StreamCommit(ref stream_name, ref group, offset) => {
+                format!("SCOMMIT {} {} {};", quote(stream_name), quote(group), quote(&offset.to_string()))
+            }
+            Operation::StreamRetain(ref stream_name, ref retention) => {
+                format!("SRETAIN {} {} {};", quote(stream_name), quote(&retention.max_len.unwrap_or(0).to_string()),
+                        quote(&retention.max_age.map_or(0, |max_age| max_age.as_secs()).to_string()))
+            }
+            Operation::StreamEntry(ref stream_name, offset, ref data) => {
+                format!("SENTRY {} {} {};", quote(stream_name), quote(&offset.to_string()), quote(data))
+            }
+            Operation::StreamNextOffset(ref stream_name, offset) => {
+                format!("SNEXT {} {};", quote(stream_name), quote(&offset.to_string()))
+            }
+            Operation::WriteConcern(ref queue_name, ref concern) => {
+                format!("WRITECONCERN {} {} {};", quote(queue_name), quote(&concern.replicas.to_string()),
+                        quote(&concern.timeout.as_millis().to_string()))
+            }
+        }
+    }
+
+    // message excludes the trailing ;
+    pub fn parse(message: Vec<u8>) -> Result<Operation, String> {
+        let (name, mut arguments) = parse_parts(message.clone())?;
+        match (&name as &str, arguments.len()) {
+            ("RESET", 0) => {
+                Ok(Operation::Reset)
+            }
+            ("SYNCED", 1) => {
+                let sequence = arguments[0].parse::<Sequence>().map_err(|_| format!("Invalid sequence: {}", arguments[0]))?;
+                Ok(Operation::Synced(sequence))
+            }
+            ("PUSH", 4) | ("RESTORE", 4) => {
+                let ttl = parse_ttl(&arguments[3])?;
+                let group = empty_as_none(arguments.remove(2));
+                let data = arguments.remove(1);
+                let queue_name = arguments.remove(0);
+                if name == "PUSH" {
+                    Ok(Operation::Push(queue_name, data, group, ttl))
+                } else {
+                    Ok(Operation::Restore(queue_name, data, group, ttl))
+                }
+            }
+            ("REMOVE", 3) => {
+                let group = empty_as_none(arguments.remove(2));
+                let data = arguments.remove(1);
+                Ok(Operation::Remove(arguments.remove(0), data, group))
+            }
+            ("SAPPEND", 2) => {
+                let data = arguments.remove(1);
+                Ok(Operation::StreamAppend(arguments.remove(0), data))
+            }
+            ("SENTRY", 3) => {
+                let offset = parse_offset(&arguments[1])?;
+                let data = arguments.remove(2);
+                Ok(Operation::StreamEntry(arguments.remove(0), offset, data))
+            }
+            ("SNEXT", 2) => {
+                let offset = parse_offset(&arguments[1])?;
+                Ok(Operation::StreamNextOffset(arguments.remove(0), offset))
+            }
+            ("LIMIT", _) | ("DEADLETTER", _) | ("SCOMMIT", _) | ("SRETAIN", _) | ("WRITECONCERN", 3) => {
+                match Command::parse(message)? {
+                    Command::Limit(queue_name, limits) => Ok(Operation::Limit(queue_name, limits)),
+                    Command::DeadLetter(queue_name, dead_letter_name) => Ok(Operation::DeadLetter(queue_name, dead_letter_name)),
+                    Command::StreamCommit(stream_name, group, offset) => Ok(Operation::StreamCommit(stream_name, group, offset)),
+                    Command::StreamRetain(stream_name, retention) => Ok(Operation::StreamRetain(stream_name, retention)),
+                    Command::QueueWriteConcern(queue_name, concern) => Ok(Operation::WriteConcern(queue_name, concern)),
+                    _ => Err(format!("Malformed operation: {}", name))
+                }
+            }
+            _ => {
+                Err(format!("Malformed operation: {}", name))
+            }
+        }
+    }
+}
+
+// The operations that recreate queue_table on a follower, starting with Reset and ending with Synced
+pub fn snapshot(queue_table: &QueueTable) -> Vec<Operation> {
+    let mut operations = vec![Operation::Reset];
+    for (queue_name, queue) in queue_table.queues() {
+        let snapshot = queue.snapshot();
+        operations.push(Operation::Limit(queue_name.clone(), snapshot.limits));
+        if let Some(dead_letter_name) = snapshot.dead_letter {
+            operations.push(Operation::DeadLetter(queue_name.clone(), dead_letter_name));
+        }
+        if snapshot.write_concern.replicas > 0 {
+            operations.push(Operation::WriteConcern(queue_name.clone(), snapshot.write_concern));
+        }
+        for item in snapshot.items.iter() {
+            operations.push(Operation::restore(&queue_name, item));
+        }
+    }
+    for (stream_name, stream) in queue_table.streams() {
+        let snapshot = stream.snapshot();
+        operations.push(Operation::StreamRetain(stream_name.clone(), snapshot.retention));
+        for (offset, data) in snapshot.entries {
+            operations.push(Operation::StreamEntry(stream_name.clone(), offset, data));
+        }
+        operations.push(Operation::StreamNextOffset(stream_name.clone(), snapshot.next_offset));
+        for (group, next) in snapshot.groups {
+            if next > 0 {
+                operations.push(Operation::StreamCommit(stream_name.clone(), group, next - 1));
+            }
+        }
+    }
+    operations.push(Operation::Synced(queue_table.replication().last_sequence()));
+    operations
+}
+
+pub fn apply(queue_table: &QueueTable, operation: Operation) -> Result<(), String> {
+    match operation {
+        Operation::Reset => {
+            queue_table.clear();
+        }
+        Operation::Synced(_) => {
+        }
+        // The leader already checked limits, an item it held may still be queued here
+        Operation::Push(queue_name, data, group, ttl) |
+        Operation::Restore(queue_name, data, group, ttl) => {
+            let item = Item::new(data, group, ttl.map(|ttl| Instant::now() + ttl));
+            queue_table.get_or_create_queue(queue_name).restore(item);
+        }
+        Operation::Remove(queue_name, data, group) => {
+            queue_table.get_or_create_queue(queue_name).remove_item(&data, &group);
+        }
+        Operation::Limit(queue_name, limits) => {
+            queue_table.get_or_create_queue(queue_name).set_limits(limits);
+        }
+        Operation::DeadLetter(queue_name, dead_letter_name) => {
+            queue_table.set_dead_letter(queue_name, dead_letter_name);
+        }
+        Operation::StreamAppend(stream_name, data) => {
+            if queue_table.get_or_create_stream(stream_name.clone()).append(data).is_err() {
+                return Err(format!("append to {} refused: OutOfMemory", stream_name));
+            }
+        }
+        Operation::StreamCommit(stream_name, group, offset) => {
+            queue_table.get_or_create_stream(stream_name).commit(group, offset)?;
+        }
+        Operation::StreamRetain(stream_name, retention) => {
+            queue_table.get_or_create_stream(stream_name).set_retention(retention);
+        }
+        Operation::StreamEntry(stream_name, offset, data) => {
+            queue_table.get_or_create_stream(stream_name).restore_entry(offset, data);
+        }
+        Operation::StreamNextOffset(stream_name, offset) => {
+            queue_table.get_or_create_stream(stream_name).set_next_offset(offset);
+        }
+        Operation::WriteConcern(queue_name, concern) => {
+            queue_table.get_or_create_queue(queue_name).set_write_concern(concern);
+        }
+    }
+    Ok(())
+}
+
+impl Default for Replication {
+    fn default() -> Replication {
+        Replication::new()
+    }
+}
+
+impl Replication {
+    pub fn new() -> Replication {
+        Replication {
+            inner: Arc::new(ReplicationInner {
+                feeds: Mutex::new(Vec::new()),
+                count: AtomicUsize::new(0),
+                dirty: AtomicBool::new(false),
+                acks_received: AtomicBool::new(false),
+                last_sequence: AtomicU64::new(0),
+                following: AtomicBool::new(false),
+                leader: Mutex::new(None)
+            })
+        }
+    }
+
+    pub fn subscribe(&self, client: ClientId) -> ReplicaFeed {
+        let feed = Arc::new(Mutex::new(Backlog { data: Vec::new(), overflowed: false, acked: None }));
+        let mut feeds = self.inner.feeds.lock().unwrap();
+        feeds.push((client, feed.clone()));
+        self.inner.count.store(feeds.len(), Ordering::Relaxed);
+        feed
+    }
+
+    pub fn unsubscribe(&self, client: ClientId) {
+        let mut feeds = self.inner.feeds.lock().unwrap();
+        feeds.retain(|&(id, _)| id != client);
+        self.inner.count.store(feeds.len(), Ordering::Relaxed);
+    }
+
+    // Connected followers
+    pub fn replicas(&self) -> usize {
+        self.inner.count.load(Ordering::Relaxed)
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.replicas() > 0
+    }
+
+    pub fn publish(&self, operation: &Operation) {
+        let encoded = operation.encode();
+        let feeds = self.inner.feeds.lock().unwrap();
+        self.inner.last_sequence.fetch_add(1, Ordering::Relaxed);
+        for (_, feed) in feeds.iter() {
+            let mut feed = feed.lock().unwrap();
+            if feed.overflowed {
+                continue;
+            }
+            if feed.data.len() + encoded.len() > MAX_BACKLOG {
+                feed.overflowed = true;
+                feed.data = Vec::new();
+            } else {
+                feed.data.extend_from_slice(encoded.as_bytes());
+            }
+        }
+        self.inner.dirty.store(true, Ordering::Relaxed);
+    }
+
+    // Snapshots taken now hold every operation up to this
+    pub fn last_sequence(&self) -> Sequence {
+        self.inner.last_sequence.load(Ordering::Relaxed)
+    }
+
+    pub fn ack(&self, client: ClientId, sequence: Sequence) {
+        let feeds = self.inner.feeds.lock().unwrap();
+        for &(id, ref feed) in feeds.iter() {
+            if id == client {
+                let mut feed = feed.lock().unwrap();
+                feed.acked = Some(feed.acked.map_or(sequence, |acked| acked.max(sequence)));
+            }
+        }
+        self.inner.acks_received.store(true, Ordering::Relaxed);
+    }
+
+    // Followers that applied everything up to sequence
+    pub fn acked(&self, sequence: Sequence) -> usize {
+        let feeds = self.inner.feeds.lock().unwrap();
+        feeds.iter().filter(|&(_, feed)| feed.lock().unwrap().acked.is_some_and(|acked| acked >= sequence)).count()
+    }
+
+    // Whether anything was published since the last call
+    pub fn take_dirty(&self) -> bool {
+        self.inner.dirty.swap(false, Ordering::Relaxed)
+    }
+
+    // Whether a follower acked since the last call
+    pub fn take_acks(&self) -> bool {
+        self.inner.acks_received.swap(false, Ordering::Relaxed)
+    }
+
+    // Client commands that change data are refused until promote
+    pub fn set_leader(&self, leader: String) {
+        let mut current = self.inner.leader.lock().unwrap();
+        *current = Some(leader);
+        self.inner.following.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_follower(&self) -> bool {
+        self.inner.following.load(Ordering::Relaxed)
+    }
+
+    pub fn leader(&self) -> Option<String> {
+        self.inner.leader.lock().unwrap().clone()
+    }
+
+    // Returns false if this server wasn't following
+    pub fn promote(&self) -> bool {
+        let mut leader = self.inner.leader.lock().unwrap();
+        *leader = None;
+        self.inner.following.swap(false, Ordering::Relaxed)
+    }
+
+    // Returns Ok(false) without applying anything once promoted
+    pub fn apply_from_leader(&self, queue_table: &QueueTable, operation: Operation) -> Result<bool, String> {
+        let _leader = self.inner.leader.lock().unwrap();
+        if !self.is_follower() {
+            return Ok(false);
+        }
+        apply(queue_table, operation)?;
+        Ok(true)
+    }
+}
+
+// Runs on its own thread until PROMOTE, syncing again whenever the connection to the leader drops
+pub fn follow(leader: SocketAddr, auth: Option<(UserName, String)>, queue_table: QueueTable) {
+    let replication = queue_table.replication().clone();
+    while replication.is_follower() {
+        if let Err(message) = sync(leader, &auth, &queue_table) {
+            warn!(leader:% = leader, error:% = message; "replication interrupted");
+            thread::sleep(Duration::from_millis(RETRY_DELAY));
+        }
+    }
+    info!(leader:% = leader; "stopped following");
+}
+
+fn sync(leader: SocketAddr, auth: &Option<(UserName, String)>, queue_table: &QueueTable) -> Result<(), String> {
+    let stream = TcpStream::connect_timeout(&leader, Duration::from_secs(CONNECT_TIMEOUT)).map_err(|error| error.to_string())?;
+    stream.set_read_timeout(Some(Duration::from_millis(READ_TIMEOUT))).map_err(|error| error.to_string())?;
+    let mut connection = LeaderConnection { stream, buffer: Vec::new() };
+    if let Some((ref user, ref password)) = *auth {
+        connection.request(&format!("AUTH {} {};", quote(user), quote(password)))?;
+    }
+    connection.request("SYNC;")?;
+    info!(leader:% = leader; "syncing from leader");
+    let replication = queue_table.replication();
+    // Sequence numbers start once the snapshot is loaded
+    let mut applied: Option<Sequence> = None;
+    let mut acked: Option<Sequence> = None;
+    loop {
+        let message = match connection.take_message() {
+            Some(message) => message,
+            None => {
+                // Acked once everything received so far is applied
+                if applied != acked {
+                    connection.send(&format!("ACK {};", quote(&applied.unwrap().to_string())))?;
+                    acked = applied;
+                }
+                if !connection.fill()? && !replication.is_follower() {
+                    return Ok(());
+                }
+                continue;
+            }
+        };
+        let operation = Operation::parse(message)?;
+        applied = match operation {
+            Operation::Synced(sequence) => {
+                info!(leader:% = leader; "synced with leader");
+                Some(sequence)
+            }
+            _ => applied.map(|applied| applied + 1)
+        };
+        match replication.apply_from_leader(queue_table, operation) {
+            Ok(true) => {
+            }
+            Ok(false) => {
+                return Ok(());
+            }
+            Err(message) => {
+                warn!(leader:% = leader, error:% = message; "could not apply replicated operation");
+            }
+        }
+    }
+}
+
+struct LeaderConnection {
+    stream: TcpStream,
+    buffer: Vec<u8>
+}
+
+impl LeaderConnection {
+    fn send(&mut self, message: &str) -> Result<(), String> {
+        self.stream.write_all(message.as_bytes()).map_err(|error| error.to_string())
+    }
+
+    // Fails unless the leader replies SUCCESS
+    fn request(&mut self, command: &str) -> Result<(), String> {
+        self.send(command)?;
+        let deadline = Instant::now() + Duration::from_secs(SYNC_TIMEOUT);
+        loop {
+            if let Some(end) = self.buffer.windows(2).position(|end| end == b"\r\n") {
+                let reply = String::from_utf8_lossy(&self.buffer[..end]).into_owned();
+                self.buffer.drain(..end + 2);
+                if reply == "SUCCESS" {
+                    return Ok(());
+                }
+                return Err(format!("leader replied {}", reply));
+            }
+            if !self.fill()? && Instant::now() >= deadline {
+                return Err("leader did not reply".to_string());
+            }
+        }
+    }
+
+    // The next complete message already read, without the trailing ;
+    fn take_message(&mut self) -> Option<Vec<u8>> {
+        message_end(&self.buffer).map(|end| {
+            let mut message: Vec<u8> = self.buffer.drain(..end + 1).collect();
+            message.pop();
+            message
+        })
+    }
+
+    // Returns false on timeout
+    fn fill(&mut self) -> Result<bool, String> {
+        let mut buffer = [0; READ_BUFFER_SIZE];
+        match self.stream.read(&mut buffer) {
+            Ok(0) => {
+                Err("leader closed the connection".to_string())
+            }
+            Ok(n) => {
+                self.buffer.extend_from_slice(&buffer[..n]);
+                Ok(true)
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {
+                Ok(false)
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {
+                Ok(true)
+            }
+            Err(error) => {
+                Err(error.to_string())
+            }
+        }
+    }
+}
+
+// Position of the ; ending the first message, data may contain ; inside quotes
+pub fn message_end(buffer: &[u8]) -> Option<usize> {
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, &c) in buffer.iter().enumerate() {
+        if escaped {
+            escaped = false;
+        } else if quoted && c == b'\\' {
+            escaped = true;
+        } else if c == b'\'' {
+            quoted = !quoted;
+        } else if !quoted && c == b';' {
+            return Some(i);
+        }
+    }
+    None
+}
+
+fn item_parts(item: &Item) -> (String, Option<MessageGroup>, Option<Duration>) {
+    let ttl = item.expires_at.map(|expires_at| expires_at.saturating_duration_since(Instant::now()));
+    (item.data.clone(), item.group.clone(), ttl)
+}
+
+fn group_or_empty(group: &Option<MessageGroup>) -> &str {
+    group.as_ref().map_or("", |group| group as &str)
+}
+
+fn empty_as_none(value: String) -> Option<String> {
+    if value.is_empty() { None } else { Some(value) }
+}
+
+// An empty ttl means the item never expires
+fn ttl_millis(ttl: Option<Duration>) -> String {
+    ttl.map_or(String::new(), |ttl| ttl.as_millis().to_string())
+}
+
+fn parse_ttl(value: &str) -> Result<Option<Duration>, String> {
+    if value.is_empty() {
+        return Ok(None);
+    }
+    value.parse::<u64>().map(|millis| Some(Duration::from_millis(millis))).map_err(|_| format!("Invalid ttl: {}", value))
+}
+
+fn parse_offset(value: &str) -> Result<Offset, String> {
+    value.parse::<Offset>().map_err(|_| format!("Invalid offset: {}", value))
+}
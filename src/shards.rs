@@ -0,0 +1,127 @@
+use std::net::{SocketAddr};
+use std::sync::{Arc,RwLock};
+
+// Queue and stream names are spread over this many slots
+pub const SLOTS: usize = 16384;
+
+pub type Slot = u16;
+
+// Slots start to end inclusive are served at address
+#[derive(PartialEq)]
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct ShardRange {
+    pub start: Slot,
+    pub end: Slot,
+    pub address: SocketAddr
+}
+
+struct ShardsInner {
+    ranges: Vec<ShardRange>,
+    // This server's address in ranges, None when not sharded
+    local: Option<SocketAddr>
+}
+
+// Which server holds each slot. Commands on names held elsewhere are answered with MOVED.
+#[derive(Clone)]
+pub struct Shards {
+    inner: Arc<RwLock<ShardsInner>>
+}
+
+impl Default for Shards {
+    fn default() -> Shards {
+        Shards::new()
+    }
+}
+
+impl Shards {
+    pub fn new() -> Shards {
+        Shards {
+            inner: Arc::new(RwLock::new(ShardsInner { ranges: Vec::new(), local: None }))
+        }
+    }
+
+    // ranges must cover every slot, see validate
+    pub fn configure(&self, ranges: Vec<ShardRange>, local: SocketAddr) {
+        let mut inner = self.inner.write().unwrap();
+        inner.ranges = ranges;
+        inner.ranges.sort_by_key(|range| range.start);
+        inner.local = Some(local);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.inner.read().unwrap().local.is_some()
+    }
+
+    // None if the slot is held here
+    pub fn owner(&self, slot: Slot) -> Option<SocketAddr> {
+        let inner = self.inner.read().unwrap();
+        let local = match inner.local {
+            Some(local) => local,
+            None => {
+                return None;
+            }
+        };
+        inner.ranges.iter()
+            .find(|range| range.start <= slot && slot <= range.end)
+            .map(|range| range.address)
+            .and_then(|address| if address == local { None } else { Some(address) })
+    }
+
+    // Sorted by first slot
+    pub fn ranges(&self) -> Vec<ShardRange> {
+        self.inner.read().unwrap().ranges.clone()
+    }
+}
+
+// Only the part between the first { and the following } is hashed when it isn't empty,
+// so queues like {orders}.new and {orders}.failed share a slot and can be used in one transaction
+pub fn slot(name: &str) -> Slot {
+    let bytes = name.as_bytes();
+    let hashed = match bytes.iter().position(|&c| c == b'{') {
+        Some(open) => {
+            match bytes[open + 1..].iter().position(|&c| c == b'}') {
+                Some(length) if length > 0 => &bytes[open + 1..open + 1 + length],
+                _ => bytes
+            }
+        }
+        None => bytes
+    };
+    (crc16(hashed) as usize % SLOTS) as Slot
+}
+
+// Every slot must be in exactly one range and local must serve at least one
+pub fn validate(ranges: &[ShardRange], local: SocketAddr) -> Result<(), String> {
+    let mut sorted: Vec<&ShardRange> = ranges.iter().collect();
+    sorted.sort_by_key(|range| range.start);
+    let mut next = 0;
+    for range in sorted {
+        if range.start as usize != next {
+            return Err(if (range.start as usize) < next {
+                format!("slot {} is in more than one shard", range.start)
+            } else {
+                format!("slots {}-{} are not in any shard", next, range.start - 1)
+            });
+        }
+        next = range.end as usize + 1;
+    }
+    if next != SLOTS {
+        return Err(format!("slots {}-{} are not in any shard", next, SLOTS - 1));
+    }
+    if !ranges.iter().any(|range| range.address == local) {
+        return Err(format!("shard-self {} doesn't serve any slots", local));
+    }
+    Ok(())
+}
+
+// CRC-16/XMODEM, as used by Redis Cluster so client libraries can share the slot code
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    crc
+}
@@ -0,0 +1,99 @@
+use std::collections::VecDeque;
+use std::sync::{Arc,Mutex};
+use std::sync::atomic::{AtomicI64,AtomicU64,AtomicUsize,Ordering};
+use std::time::{Duration,SystemTime,UNIX_EPOCH};
+
+use stats::{ClientId};
+
+pub const DEFAULT_SLOWER_THAN: i64 = 10000;
+pub const DEFAULT_MAX_LEN: usize = 128;
+
+#[derive(PartialEq)]
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct SlowLogEntry {
+    pub id: u64,
+    // Seconds since the unix epoch when the command finished
+    pub timestamp: u64,
+    pub duration: Duration,
+    pub client: ClientId,
+    pub peer: String,
+    pub command: String
+}
+
+struct SlowLogInner {
+    entries: Mutex<VecDeque<SlowLogEntry>>,
+    next_id: AtomicU64,
+    // Microseconds, negative disables the slow log
+    slower_than: AtomicI64,
+    max_len: AtomicUsize
+}
+
+// The most recent commands that took longer than slower_than to run, newest first
+#[derive(Clone)]
+pub struct SlowLog {
+    inner: Arc<SlowLogInner>
+}
+
+impl Default for SlowLog {
+    fn default() -> SlowLog {
+        SlowLog::new()
+    }
+}
+
+impl SlowLog {
+    pub fn new() -> SlowLog {
+        SlowLog {
+            inner: Arc::new(SlowLogInner {
+                entries: Mutex::new(VecDeque::new()),
+                next_id: AtomicU64::new(0),
+                slower_than: AtomicI64::new(DEFAULT_SLOWER_THAN),
+                max_len: AtomicUsize::new(DEFAULT_MAX_LEN)
+            })
+        }
+    }
+
+    pub fn set_slower_than(&self, micros: i64) {
+        self.inner.slower_than.store(micros, Ordering::Relaxed);
+    }
+
+    pub fn set_max_len(&self, max_len: usize) {
+        self.inner.max_len.store(max_len, Ordering::Relaxed);
+        let mut entries = self.inner.entries.lock().unwrap();
+        entries.truncate(max_len);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.inner.slower_than.load(Ordering::Relaxed) >= 0
+    }
+
+    // Only takes the lock for commands that were slow
+    pub fn record(&self, duration: Duration, client: ClientId, peer: &str, command: String) {
+        let slower_than = self.inner.slower_than.load(Ordering::Relaxed);
+        if slower_than < 0 || (duration.as_micros() as i64) < slower_than {
+            return;
+        }
+        let entry = SlowLogEntry {
+            id: self.inner.next_id.fetch_add(1, Ordering::Relaxed),
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_secs()).unwrap_or(0),
+            duration,
+            client,
+            peer: peer.to_string(),
+            command
+        };
+        let max_len = self.inner.max_len.load(Ordering::Relaxed);
+        let mut entries = self.inner.entries.lock().unwrap();
+        entries.push_front(entry);
+        entries.truncate(max_len);
+    }
+
+    pub fn entries(&self, count: Option<usize>) -> Vec<SlowLogEntry> {
+        let entries = self.inner.entries.lock().unwrap();
+        entries.iter().take(count.unwrap_or(entries.len())).cloned().collect()
+    }
+
+    pub fn reset(&self) {
+        let mut entries = self.inner.entries.lock().unwrap();
+        entries.clear();
+    }
+}
@@ -0,0 +1,146 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64,AtomicUsize,Ordering};
+use std::time::{Duration,Instant};
+
+pub type ClientId = u64;
+
+// Upper bounds in seconds of the BPOP wait time histogram buckets, +Inf is implied
+pub const BPOP_WAIT_BUCKETS: [f64; 10] = [0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0];
+
+struct StatsInner {
+    started: Instant,
+    connected_clients: AtomicUsize,
+    open_transactions: AtomicUsize,
+    blocked_clients: AtomicUsize,
+    connections: AtomicU64,
+    commits: AtomicU64,
+    aborts: AtomicU64,
+    // One more than BPOP_WAIT_BUCKETS for +Inf, not cumulative
+    bpop_wait_buckets: Vec<AtomicU64>,
+    bpop_wait_micros: AtomicU64
+}
+
+#[derive(PartialEq)]
+#[derive(Debug)]
+pub struct Histogram {
+    // Cumulative counts for each of BPOP_WAIT_BUCKETS then +Inf
+    pub buckets: Vec<u64>,
+    pub sum: Duration,
+    pub count: u64
+}
+
+// Server wide gauges and counters kept up to date by each Connection.
+// Atomics so connections never wait on each other to update them.
+#[derive(Clone)]
+pub struct ServerStats {
+    inner: Arc<StatsInner>
+}
+
+impl Default for ServerStats {
+    fn default() -> ServerStats {
+        ServerStats::new()
+    }
+}
+
+impl ServerStats {
+    pub fn new() -> ServerStats {
+        ServerStats {
+            inner: Arc::new(StatsInner {
+                started: Instant::now(),
+                connected_clients: AtomicUsize::new(0),
+                open_transactions: AtomicUsize::new(0),
+                blocked_clients: AtomicUsize::new(0),
+                connections: AtomicU64::new(0),
+                commits: AtomicU64::new(0),
+                aborts: AtomicU64::new(0),
+                bpop_wait_buckets: (0..BPOP_WAIT_BUCKETS.len() + 1).map(|_| AtomicU64::new(0)).collect(),
+                bpop_wait_micros: AtomicU64::new(0)
+            })
+        }
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.inner.started.elapsed()
+    }
+
+    pub fn connected_clients(&self) -> usize {
+        self.inner.connected_clients.load(Ordering::Relaxed)
+    }
+
+    pub fn open_transactions(&self) -> usize {
+        self.inner.open_transactions.load(Ordering::Relaxed)
+    }
+
+    pub fn blocked_clients(&self) -> usize {
+        self.inner.blocked_clients.load(Ordering::Relaxed)
+    }
+
+    // Total since start up
+    pub fn connections(&self) -> u64 {
+        self.inner.connections.load(Ordering::Relaxed)
+    }
+
+    pub fn commits(&self) -> u64 {
+        self.inner.commits.load(Ordering::Relaxed)
+    }
+
+    // Includes transactions rolled back because the client went away
+    pub fn aborts(&self) -> u64 {
+        self.inner.aborts.load(Ordering::Relaxed)
+    }
+
+    pub fn bpop_wait(&self) -> Histogram {
+        let mut total = 0;
+        let buckets = self.inner.bpop_wait_buckets.iter().map(|bucket| {
+            total += bucket.load(Ordering::Relaxed);
+            total
+        }).collect();
+        Histogram {
+            buckets,
+            sum: Duration::from_micros(self.inner.bpop_wait_micros.load(Ordering::Relaxed)),
+            count: total
+        }
+    }
+
+    // Ids count up from 1 and are never reused
+    pub fn client_connected(&self) -> ClientId {
+        adjust(&self.inner.connected_clients, true);
+        self.inner.connections.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    pub fn client_disconnected(&self) {
+        adjust(&self.inner.connected_clients, false);
+    }
+
+    pub fn record_commit(&self) {
+        self.inner.commits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_abort(&self) {
+        self.inner.aborts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // How long a BPOP waited before it got an item
+    pub fn record_bpop_wait(&self, wait: Duration) {
+        let seconds = wait.as_secs() as f64 + wait.subsec_nanos() as f64 / 1e9;
+        let bucket = BPOP_WAIT_BUCKETS.iter().position(|bound| seconds <= *bound).unwrap_or(BPOP_WAIT_BUCKETS.len());
+        self.inner.bpop_wait_buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.inner.bpop_wait_micros.fetch_add(wait.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn set_in_transaction(&self, in_transaction: bool) {
+        adjust(&self.inner.open_transactions, in_transaction);
+    }
+
+    pub fn set_blocked(&self, blocked: bool) {
+        adjust(&self.inner.blocked_clients, blocked);
+    }
+}
+
+fn adjust(gauge: &AtomicUsize, up: bool) {
+    if up {
+        gauge.fetch_add(1, Ordering::Relaxed);
+    } else {
+        gauge.fetch_sub(1, Ordering::Relaxed);
+    }
+}
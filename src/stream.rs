@@ -0,0 +1,214 @@
+use std::sync::{Arc,Mutex};
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::time::{Duration,Instant};
+
+use memory::{Memory,OutOfMemory};
+
+pub type Offset = u64;
+pub type GroupName = String;
+
+#[derive(PartialEq)]
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct Retention {
+    pub max_len: Option<usize>,
+    pub max_age: Option<Duration>
+}
+
+struct StreamEntry {
+    offset: Offset,
+    data: String,
+    appended_at: Instant
+}
+
+struct StreamInner {
+    entries: VecDeque<StreamEntry>,
+    next_offset: Offset,
+    // Offset of the next entry each group should read
+    groups: HashMap<GroupName, Offset>,
+    retention: Retention,
+    memory: Memory
+}
+
+// Everything a follower needs to recreate a stream with the same offsets
+pub struct StreamSnapshot {
+    pub retention: Retention,
+    pub entries: Vec<(Offset, String)>,
+    pub next_offset: Offset,
+    // Offset of the next entry each group should read
+    pub groups: Vec<(GroupName, Offset)>
+}
+
+// Unlike Queue, reading from a Stream never removes data.
+// Entries are only dropped by the retention policy.
+pub struct Stream {
+    inner: Arc<Mutex<StreamInner>>
+}
+
+impl Retention {
+    pub fn unlimited() -> Retention {
+        Retention { max_len: None, max_age: None }
+    }
+}
+
+impl StreamInner {
+    fn apply_retention(&mut self) {
+        if let Some(max_len) = self.retention.max_len {
+            while self.entries.len() > max_len {
+                self.drop_oldest();
+            }
+        }
+        if let Some(max_age) = self.retention.max_age {
+            loop {
+                match self.entries.front() {
+                    Some(entry) if entry.appended_at.elapsed() > max_age => {}
+                    _ => { break; }
+                }
+                self.drop_oldest();
+            }
+        }
+    }
+
+    fn drop_oldest(&mut self) {
+        if let Some(entry) = self.entries.pop_front() {
+            self.memory.release(entry.data.len());
+        }
+    }
+}
+
+impl Default for Stream {
+    fn default() -> Stream {
+        Stream::new()
+    }
+}
+
+impl Stream {
+    pub fn new() -> Stream {
+        Stream::with_memory(Memory::new())
+    }
+
+    pub fn with_memory(memory: Memory) -> Stream {
+        Stream {
+            inner: Arc::new(Mutex::new(StreamInner {
+                entries: VecDeque::new(),
+                next_offset: 0,
+                groups: HashMap::new(),
+                retention: Retention::unlimited(),
+                memory
+            }))
+        }
+    }
+
+    pub fn append(&self, value: String) -> Result<Offset,OutOfMemory> {
+        let mut stream = self.inner.lock().unwrap();
+        stream.apply_retention();
+        stream.memory.reserve(value.len())?;
+        let offset = stream.next_offset;
+        stream.next_offset += 1;
+        stream.entries.push_back(StreamEntry {
+            offset,
+            data: value,
+            appended_at: Instant::now()
+        });
+        stream.apply_retention();
+        Ok(offset)
+    }
+
+    // Returns up to count entries after the group's committed offset.
+    // The offset is not moved, reading again returns the same entries.
+    pub fn read(&self, group: &GroupName, count: usize) -> Vec<(Offset, String)> {
+        let mut stream = self.inner.lock().unwrap();
+        stream.apply_retention();
+        let from = stream.groups.get(group).cloned().unwrap_or(0);
+        stream.entries.iter()
+            .filter(|entry| entry.offset >= from)
+            .take(count)
+            .map(|entry| (entry.offset, entry.data.clone()))
+            .collect()
+    }
+
+    // Whether offset has been appended, so a group can commit it
+    pub fn check_offset(&self, offset: Offset) -> Result<(),String> {
+        let stream = self.inner.lock().unwrap();
+        if offset >= stream.next_offset {
+            return Err("OFFSET OUT OF RANGE".to_string());
+        }
+        Ok(())
+    }
+
+    // Marks everything up to and including offset as processed by the group
+    pub fn commit(&self, group: GroupName, offset: Offset) -> Result<(),String> {
+        let mut stream = self.inner.lock().unwrap();
+        if offset >= stream.next_offset {
+            return Err("OFFSET OUT OF RANGE".to_string());
+        }
+        let next = stream.groups.entry(group).or_insert(0);
+        if offset + 1 > *next {
+            *next = offset + 1;
+        }
+        Ok(())
+    }
+
+    pub fn set_retention(&self, retention: Retention) {
+        let mut stream = self.inner.lock().unwrap();
+        stream.retention = retention;
+        stream.apply_retention();
+    }
+
+    pub fn snapshot(&self) -> StreamSnapshot {
+        let mut stream = self.inner.lock().unwrap();
+        stream.apply_retention();
+        let mut groups: Vec<(GroupName, Offset)> = stream.groups.iter().map(|(group, offset)| (group.clone(), *offset)).collect();
+        groups.sort();
+        StreamSnapshot {
+            retention: stream.retention.clone(),
+            entries: stream.entries.iter().map(|entry| (entry.offset, entry.data.clone())).collect(),
+            next_offset: stream.next_offset,
+            groups
+        }
+    }
+
+    // Adds an entry from a leader's snapshot, entries are restored in offset order
+    pub fn restore_entry(&self, offset: Offset, value: String) {
+        let mut stream = self.inner.lock().unwrap();
+        stream.memory.force_reserve(value.len());
+        stream.entries.push_back(StreamEntry {
+            offset,
+            data: value,
+            appended_at: Instant::now()
+        });
+        stream.next_offset = offset + 1;
+        stream.apply_retention();
+    }
+
+    pub fn set_next_offset(&self, offset: Offset) {
+        let mut stream = self.inner.lock().unwrap();
+        stream.next_offset = offset;
+    }
+
+    pub fn clear(&self) {
+        let mut stream = self.inner.lock().unwrap();
+        while !stream.entries.is_empty() {
+            stream.drop_oldest();
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        let mut stream = self.inner.lock().unwrap();
+        stream.apply_retention();
+        stream.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Clone for Stream {
+    fn clone(&self) -> Stream {
+        Stream {
+            inner: self.inner.clone()
+        }
+    }
+}
@@ -0,0 +1,155 @@
+use std::fs::File;
+use std::io;
+use std::io::{BufReader,Read,Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use mio::net::TcpStream;
+use rustls;
+use rustls::{RootCertStore,ServerConfig,ServerConnection};
+use rustls::pki_types::{CertificateDer,PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls_pemfile;
+
+// Client certificates are only asked for when a CA is given, and are then required
+pub fn server_config(cert: &Path, key: &Path, ca_cert: Option<&Path>) -> Result<Arc<ServerConfig>, String> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = match ServerConfig::builder_with_provider(provider.clone()).with_safe_default_protocol_versions() {
+        Ok(builder) => builder,
+        Err(error) => {
+            return Err(format!("could not set up TLS: {}", error));
+        }
+    };
+    let builder = match ca_cert {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in read_certs(path)? {
+                if let Err(error) = roots.add(cert) {
+                    return Err(format!("invalid certificate in {}: {}", path.display(), error));
+                }
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider).build();
+            match verifier {
+                Ok(verifier) => builder.with_client_cert_verifier(verifier),
+                Err(error) => {
+                    return Err(format!("could not use {} to verify clients: {}", path.display(), error));
+                }
+            }
+        }
+        None => {
+            builder.with_no_client_auth()
+        }
+    };
+    let certs = read_certs(cert)?;
+    let key = read_key(key)?;
+    match builder.with_single_cert(certs, key) {
+        Ok(config) => Ok(Arc::new(config)),
+        Err(error) => Err(format!("invalid TLS certificate or key: {}", error))
+    }
+}
+
+fn open(path: &Path) -> Result<BufReader<File>, String> {
+    File::open(path).map(BufReader::new).map_err(|error| format!("could not read {}: {}", path.display(), error))
+}
+
+fn read_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, String> {
+    let mut reader = open(path)?;
+    let certs: Result<Vec<_>, _> = rustls_pemfile::certs(&mut reader).collect();
+    match certs {
+        Ok(ref certs) if certs.is_empty() => Err(format!("no certificates in {}", path.display())),
+        Ok(certs) => Ok(certs),
+        Err(error) => Err(format!("could not read {}: {}", path.display(), error))
+    }
+}
+
+fn read_key(path: &Path) -> Result<PrivateKeyDer<'static>, String> {
+    let mut reader = open(path)?;
+    match rustls_pemfile::private_key(&mut reader) {
+        Ok(Some(key)) => Ok(key),
+        Ok(None) => Err(format!("no private key in {}", path.display())),
+        Err(error) => Err(format!("could not read {}: {}", path.display(), error))
+    }
+}
+
+// Wraps a non-blocking socket so the event loop can read and write plaintext as usual.
+// Encrypted data that couldn't be sent yet is kept by the session, see wants_write.
+pub struct TlsStream {
+    pub socket: TcpStream,
+    session: ServerConnection
+}
+
+impl TlsStream {
+    pub fn new(socket: TcpStream, config: Arc<ServerConfig>) -> io::Result<TlsStream> {
+        let session = ServerConnection::new(config).map_err(io::Error::other)?;
+        Ok(TlsStream {
+            socket,
+            session
+        })
+    }
+
+    pub fn wants_write(&self) -> bool {
+        self.session.wants_write()
+    }
+
+    fn write_tls(&mut self) -> io::Result<()> {
+        while self.session.wants_write() {
+            self.session.write_tls(&mut self.socket)?;
+        }
+        Ok(())
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.session.reader().read(buf) {
+                Ok(n) => {
+                    return Ok(n);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                }
+                Err(e) => {
+                    return Err(e);
+                }
+            }
+            if self.session.read_tls(&mut self.socket)? == 0 {
+                return Ok(0);
+            }
+            if let Err(error) = self.session.process_new_packets() {
+                // Let the client know why before giving up
+                let _ = self.write_tls();
+                return Err(io::Error::new(io::ErrorKind::InvalidData, error));
+            }
+            // Handshake replies, anything left over is sent once the socket is writable
+            match self.write_tls() {
+                Ok(()) => {
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                }
+                Err(e) => {
+                    return Err(e);
+                }
+            }
+        }
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.session.writer().write(buf)?;
+        if n == 0 && !buf.is_empty() {
+            // The session's send buffer is full until the socket drains
+            self.write_tls()?;
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "TLS send buffer full"));
+        }
+        match self.write_tls() {
+            Ok(()) => Ok(n),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(n),
+            Err(e) => Err(e)
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_tls()
+    }
+}
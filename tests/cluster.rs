@@ -0,0 +1,177 @@
+#![feature(plugin)]
+#![cfg_attr(test, plugin(stainless))]
+
+#[cfg(test)]
+mod tests {
+    extern crate queue_experiments;
+    pub use self::queue_experiments::connection::{Connection};
+    pub use self::queue_experiments::parse_commands::{parse_parts};
+    pub use self::queue_experiments::queue_table::{QueueTable};
+    pub use self::queue_experiments::raft::{RaftNode,Message,Entry,NodeId,Role,EntryState};
+    pub use std::time::{Duration,Instant};
+
+    describe! raft {
+        before_each {
+            let timeout = Duration::from_secs(1);
+            let start = Instant::now();
+            let mut nodes: Vec<RaftNode> = (1..4).map(|id| RaftNode::new(id, (1..4).filter(|&peer| peer != id).collect(), timeout, start)).collect();
+            // Delivers messages until there are none left, dropping those to or from a cut off node
+            let deliver = |nodes: &mut Vec<RaftNode>, now: Instant, cut_off: Option<NodeId>| {
+                loop {
+                    let mut messages = Vec::new();
+                    for node in nodes.iter_mut() {
+                        let from = node.id();
+                        messages.extend(node.take_messages().into_iter().map(|(to, message)| (from, to, message)));
+                    }
+                    if messages.is_empty() {
+                        return;
+                    }
+                    for (from, to, message) in messages {
+                        if cut_off != Some(from) && cut_off != Some(to) {
+                            nodes[(to - 1) as usize].step(message, now);
+                        }
+                    }
+                }
+            };
+        }
+
+        it "elects_a_leader_and_commits_entries_on_a_majority" {
+            let now = start + timeout * 2;
+            nodes[0].tick(now);
+            deliver(&mut nodes, now, None);
+            assert_eq!(nodes.iter().map(|node| node.role()).collect::<Vec<Role>>(), vec![Role::Leader, Role::Follower, Role::Follower]);
+
+            assert_eq!(nodes[0].propose("PUSH 'q' 'a' '' '';".to_string()), Some(2));
+            nodes[0].replicate();
+            deliver(&mut nodes, now, Some(3));
+            assert_eq!(nodes[0].commit_index(), 2);
+            assert_eq!(nodes[0].entry_state(2, 1), EntryState::Committed);
+
+            // Followers learn the commit index from the next heartbeat
+            nodes[0].tick(now + timeout);
+            deliver(&mut nodes, now, None);
+            assert_eq!(nodes.iter().map(|node| node.commit_index()).collect::<Vec<u64>>(), vec![2, 2, 2]);
+            assert_eq!(nodes[2].entry(2).map(|entry| entry.data.clone()), Some("PUSH 'q' 'a' '' '';".to_string()));
+        }
+
+        it "a_cut_off_leader_loses_its_uncommitted_entries" {
+            let now = start + timeout * 2;
+            nodes[0].tick(now);
+            deliver(&mut nodes, now, None);
+            nodes[0].propose("PUSH 'q' 'x' '' '';".to_string());
+            nodes[0].replicate();
+            deliver(&mut nodes, now, Some(1));
+            assert_eq!(nodes[0].entry_state(2, 1), EntryState::Pending);
+
+            let later = now + timeout * 2;
+            nodes[1].tick(later);
+            deliver(&mut nodes, later, Some(1));
+            assert_eq!(nodes[1].role(), Role::Leader);
+            nodes[1].propose("PUSH 'q' 'y' '' '';".to_string());
+
+            nodes[1].tick(later + timeout);
+            deliver(&mut nodes, later, None);
+            assert_eq!(nodes[0].role(), Role::Follower);
+            assert!(nodes[0].take_reset());
+            assert_eq!(nodes[0].entry_state(2, 1), EntryState::Lost);
+            assert_eq!(nodes[0].entry(3), Some(&Entry { term: 2, data: "PUSH 'q' 'y' '' '';".to_string() }));
+        }
+
+        it "a_restarted_node_doesnt_vote_until_it_has_caught_up" {
+            let now = start + timeout * 2;
+            nodes[0].tick(now);
+            deliver(&mut nodes, now, None);
+            nodes[0].propose("PUSH 'q' 'a' '' '';".to_string());
+            nodes[0].replicate();
+            deliver(&mut nodes, now, Some(3));
+            assert_eq!(nodes[0].commit_index(), 2);
+
+            // Node 2 held the entry's only other copy, node 3 mustn't win without it
+            nodes[1] = RaftNode::new(2, vec![1, 3], timeout, now);
+            let later = now + timeout * 2;
+            nodes[2].tick(later);
+            deliver(&mut nodes, later, Some(1));
+            assert_eq!(nodes[2].role(), Role::Candidate);
+
+            // Once back, node 1 steps down and wins again with node 3's vote
+            nodes[0].tick(later);
+            deliver(&mut nodes, later, None);
+            nodes[0].tick(later + timeout * 2);
+            deliver(&mut nodes, later + timeout * 2, None);
+            assert_eq!(nodes[0].role(), Role::Leader);
+            assert_eq!(nodes[1].commit_index(), 2);
+
+            let last = later + timeout * 4;
+            nodes[1].tick(last);
+            deliver(&mut nodes, last, Some(1));
+            assert_eq!(nodes[1].role(), Role::Leader);
+        }
+
+        it "a_node_behind_the_compacted_log_gets_a_snapshot" {
+            let now = start + timeout * 2;
+            nodes[0].tick(now);
+            deliver(&mut nodes, now, Some(3));
+            nodes[0].propose("PUSH 'q' 'a' '' '';".to_string());
+            nodes[0].replicate();
+            deliver(&mut nodes, now, Some(3));
+            nodes[0].compact(2, vec!["RESET;".to_string(), "RESTORE 'q' 'a' '' '';".to_string()]);
+
+            nodes[0].tick(now + timeout);
+            deliver(&mut nodes, now + timeout, None);
+            assert!(nodes[2].take_reset());
+            assert_eq!(nodes[2].base_index(), 2);
+            assert_eq!(nodes[2].base().to_vec(), vec!["RESET;".to_string(), "RESTORE 'q' 'a' '' '';".to_string()]);
+            assert_eq!(nodes[2].commit_index(), 2);
+        }
+
+        it "round_trips_messages_through_raft_commands" {
+            let message = Message::Append {
+                term: 3, leader: 1, prev_index: 4, prev_term: 2, commit: 4,
+                entries: vec![Entry { term: 3, data: "PUSH 'q' 'it\\'s; here' '' '';".to_string() }]
+            };
+            let encoded = message.encode();
+            assert_eq!(encoded.matches(';').count(), 1);
+
+            let (name, arguments) = parse_parts(encoded[..encoded.len() - 1].as_bytes().to_vec()).unwrap();
+            assert_eq!(name, "RAFT".to_string());
+            assert_eq!(Message::parse(&arguments), Ok(message));
+        }
+    }
+
+    describe! cluster {
+        before_each {
+            let queue_table = QueueTable::new();
+            let nodes = vec![(1, "127.0.0.1:5001".parse().unwrap()), (2, "127.0.0.1:5002".parse().unwrap())];
+        }
+
+        it "a_single_node_cluster_commits_writes_once_elected" {
+            queue_table.cluster().enable(1, vec![nodes[0]], Duration::from_millis(0));
+            let mut connection = Connection::new(&queue_table);
+            connection.feed(b"PUSH 'q' 'a';");
+
+            queue_table.cluster().tick();
+            queue_table.cluster().apply(&queue_table);
+            connection.feed(b"PUSH 'q' 'a';");
+
+            assert_eq!(String::from_utf8(connection.take_output()).unwrap(), "NO LEADER\r\nSUCCESS\r\n".to_string());
+            assert_eq!(queue_table.cluster().info().map(|info| (info.role, info.commit_index)), Some((Role::Leader, 2)));
+        }
+
+        it "followers_redirect_writes_to_the_leader" {
+            queue_table.cluster().enable(1, nodes, Duration::from_secs(10));
+            let mut connection = Connection::new(&queue_table);
+            let append = Message::Append {
+                term: 1, leader: 2, prev_index: 0, prev_term: 0, commit: 1,
+                entries: vec![Entry { term: 1, data: String::new() }, Entry { term: 1, data: "PUSH 'q' 'a' '' '';".to_string() }]
+            };
+
+            connection.feed(append.encode().as_bytes());
+            queue_table.cluster().apply(&queue_table);
+            assert_eq!(queue_table.get_queue(&"q".to_string()).map(|queue| queue.len()), None);
+            connection.feed(b"PUSH 'q' 'b';POP 'q';MEMORY;");
+
+            assert_eq!(String::from_utf8(connection.take_output()).unwrap(),
+                       "REDIRECT 127.0.0.1:5002\r\nREDIRECT 127.0.0.1:5002\r\nused_memory:0 maxmemory:0\r\n".to_string());
+        }
+    }
+}
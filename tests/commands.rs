@@ -5,12 +5,30 @@
 mod tests {
     extern crate queue_experiments;
     pub use self::queue_experiments::commands::{Command};
+    pub use self::queue_experiments::stream::{Retention};
+    pub use self::queue_experiments::queue_table::{QueueLimits,OverflowPolicy};
+    pub use self::queue_experiments::replication::{WriteConcern};
+    pub use std::time::{Duration};
 
     describe! commands {
         it "it_parses_push_commands" {
             assert_eq!(
                 Command::parse("PUSH 'a' 'b'".to_string().into_bytes()),
-                Ok(Command::Push("b".to_string(), "a".to_string()))
+                Ok(Command::Push("b".to_string(), "a".to_string(), None, None))
+                );
+        }
+
+        it "it_parses_push_commands_with_message_group" {
+            assert_eq!(
+                Command::parse("PUSH 'a' 'b' 'c'".to_string().into_bytes()),
+                Ok(Command::Push("b".to_string(), "a".to_string(), Some("c".to_string()), None))
+                );
+        }
+
+        it "it_parses_push_commands_with_ttl" {
+            assert_eq!(
+                Command::parse("PUSH 'a' 'b' '' '60'".to_string().into_bytes()),
+                Ok(Command::Push("b".to_string(), "a".to_string(), None, Some(Duration::from_secs(60))))
                 );
         }
 
@@ -24,8 +42,13 @@ mod tests {
         it "it_parses_bpop_commands" {
             assert_eq!(
                 Command::parse("BPOP 'a'".to_string().into_bytes()),
-                Ok(Command::BlockingPop("a".to_string()))
-                )
+                Ok(Command::BlockingPop("a".to_string(), None, false))
+                );
+            assert_eq!(
+                Command::parse("BPOP 'a' '500' 'withattempts'".to_string().into_bytes()),
+                Ok(Command::BlockingPop("a".to_string(), Some(Duration::from_millis(500)), true))
+                );
+            assert!(Command::parse("BPOP 'a' '500' 'other'".to_string().into_bytes()).is_err());
         }
 
         it "it_parses_quit_commands" {
@@ -45,7 +68,11 @@ mod tests {
         it "it_parses_abort_commands" {
             assert_eq!(
                 Command::parse("ABORT".to_string().into_bytes()),
-                Ok(Command::Abort)
+                Ok(Command::Abort(None))
+                );
+            assert_eq!(
+                Command::parse("ABORT '100'".to_string().into_bytes()),
+                Ok(Command::Abort(Some(Duration::from_millis(100))))
                 );
         }
 
@@ -114,9 +141,142 @@ mod tests {
 
         it "it_returns_err_for_wrong_number_of_arguments" {
             assert_eq!(
-                Command::parse("PUSH 'a' 'b' 'c'".to_string().into_bytes()),
+                Command::parse("PUSH 'a' 'b' 'c' '1' 'd'".to_string().into_bytes()),
                 Err("Incorrect number of arguments for PUSH".to_string())
                 );
         }
+
+        it "it_parses_spush_commands" {
+            assert_eq!(
+                Command::parse("SPUSH 'a' 'b'".to_string().into_bytes()),
+                Ok(Command::StreamPush("b".to_string(), "a".to_string()))
+                );
+        }
+
+        it "it_parses_sread_commands_with_default_count" {
+            assert_eq!(
+                Command::parse("SREAD 'a' 'g'".to_string().into_bytes()),
+                Ok(Command::StreamRead("a".to_string(), "g".to_string(), 1))
+                );
+        }
+
+        it "it_parses_sread_commands_with_count" {
+            assert_eq!(
+                Command::parse("SREAD 'a' 'g' '10'".to_string().into_bytes()),
+                Ok(Command::StreamRead("a".to_string(), "g".to_string(), 10))
+                );
+        }
+
+        it "it_parses_scommit_commands" {
+            assert_eq!(
+                Command::parse("SCOMMIT 'a' 'g' '3'".to_string().into_bytes()),
+                Ok(Command::StreamCommit("a".to_string(), "g".to_string(), 3))
+                );
+        }
+
+        it "it_parses_sretain_commands" {
+            assert_eq!(
+                Command::parse("SRETAIN 'a' '100' '0'".to_string().into_bytes()),
+                Ok(Command::StreamRetain("a".to_string(), Retention { max_len: Some(100), max_age: None }))
+                );
+        }
+
+        it "it_returns_err_for_non_numeric_offset" {
+            assert_eq!(
+                Command::parse("SCOMMIT 'a' 'g' 'x'".to_string().into_bytes()),
+                Err("Invalid offset: x".to_string())
+                );
+        }
+
+        it "it_parses_limit_commands" {
+            assert_eq!(
+                Command::parse("LIMIT 'a' '10' '0' 'drop'".to_string().into_bytes()),
+                Ok(Command::Limit("a".to_string(), QueueLimits { max_len: Some(10), max_bytes: None, overflow: OverflowPolicy::DropOldest }))
+                );
+        }
+
+        it "it_returns_err_for_unknown_overflow_policy" {
+            assert_eq!(
+                Command::parse("LIMIT 'a' '10' '0' 'foo'".to_string().into_bytes()),
+                Err("Unknown overflow policy: FOO".to_string())
+                );
+        }
+
+        it "it_parses_maxmemory_commands" {
+            assert_eq!(
+                Command::parse("MAXMEMORY '1024'".to_string().into_bytes()),
+                Ok(Command::MaxMemory(1024))
+                );
+        }
+
+        it "it_parses_auth_commands" {
+            assert_eq!(
+                Command::parse("AUTH 'user' 'password'".to_string().into_bytes()),
+                Ok(Command::Auth("user".to_string(), "password".to_string()))
+                );
+        }
+
+        it "it_parses_info_commands" {
+            assert_eq!(
+                Command::parse("INFO".to_string().into_bytes()),
+                Ok(Command::Info)
+                );
+        }
+
+        it "it_parses_slowlog_commands" {
+            assert_eq!(Command::parse("SLOWLOG".to_string().into_bytes()), Ok(Command::SlowLog(None)));
+            assert_eq!(Command::parse("SLOWLOG '5'".to_string().into_bytes()), Ok(Command::SlowLog(Some(5))));
+            assert_eq!(Command::parse("SLOWLOG 'reset'".to_string().into_bytes()), Ok(Command::SlowLogReset));
+        }
+
+        it "it_parses_client_commands" {
+            assert_eq!(Command::parse("CLIENT 'list'".to_string().into_bytes()), Ok(Command::ClientList));
+            assert_eq!(Command::parse("CLIENT 'kill' '3'".to_string().into_bytes()), Ok(Command::ClientKill(3)));
+            assert_eq!(Command::parse("CLIENT 'setname' 'worker-1'".to_string().into_bytes()), Ok(Command::ClientSetName("worker-1".to_string())));
+            assert!(Command::parse("CLIENT 'setname' 'two words'".to_string().into_bytes()).is_err());
+        }
+
+        it "it_parses_transaction_commands" {
+            assert_eq!(Command::parse("TXLIST".to_string().into_bytes()), Ok(Command::TransactionList));
+            assert_eq!(Command::parse("TXABORT '3'".to_string().into_bytes()), Ok(Command::TransactionAbort(3)));
+        }
+
+        it "it_parses_replication_commands" {
+            assert_eq!(Command::parse("SYNC".to_string().into_bytes()), Ok(Command::Sync));
+            assert_eq!(Command::parse("PROMOTE".to_string().into_bytes()), Ok(Command::Promote));
+            assert_eq!(Command::parse("ACK '12'".to_string().into_bytes()), Ok(Command::Ack(12)));
+        }
+
+        it "it_parses_write_concerns_for_the_connection_and_for_queues" {
+            let concern = WriteConcern { replicas: 2, timeout: Duration::from_millis(500) };
+            assert_eq!(Command::parse("WRITECONCERN '2' '500'".to_string().into_bytes()), Ok(Command::WriteConcern(concern)));
+            assert_eq!(Command::parse("WRITECONCERN 'queue' '2' '500'".to_string().into_bytes()),
+                       Ok(Command::QueueWriteConcern("queue".to_string(), concern)));
+            assert!(Command::parse("WRITECONCERN '2'".to_string().into_bytes()).is_err());
+        }
+
+        it "it_encodes_commands_that_parse_back_the_same" {
+            let commands = vec![
+                Command::Push("it's \\ here".to_string(), "a".to_string(), Some("g".to_string()), Some(Duration::from_secs(60))),
+                Command::Push("b".to_string(), "a".to_string(), None, None),
+                Command::BlockingPop("a".to_string(), None, false),
+                Command::BlockingPop("a".to_string(), None, true),
+                Command::BlockingPop("a".to_string(), Some(Duration::from_millis(500)), false),
+                Command::Begin,
+                Command::Abort(Some(Duration::from_millis(100))),
+                Command::SlowLog(None),
+                Command::SlowLogReset,
+                Command::ClientSetName("worker".to_string()),
+                Command::StreamRetain("s".to_string(), Retention { max_len: Some(10), max_age: None }),
+                Command::Limit("a".to_string(), QueueLimits { max_len: None, max_bytes: Some(100), overflow: OverflowPolicy::DropOldest }),
+                Command::QueueWriteConcern("a".to_string(), WriteConcern { replicas: 1, timeout: Duration::from_millis(250) }),
+                Command::ClusterKeySlot("a".to_string())
+            ];
+            for command in commands {
+                let message = command.encode();
+                assert!(message.ends_with(';'));
+                assert_eq!(Command::parse(message[..message.len() - 1].to_string().into_bytes()), Ok(command));
+            }
+        }
     }
 }
@@ -0,0 +1,219 @@
+#![feature(plugin)]
+#![cfg_attr(test, plugin(stainless))]
+
+#[cfg(test)]
+mod tests {
+    extern crate queue_experiments;
+    pub use self::queue_experiments::acl::{Permission,SERVER};
+    pub use self::queue_experiments::auth::{PasswordHash};
+    pub use self::queue_experiments::config::{Config};
+    pub use self::queue_experiments::logging::{LogFormat};
+    pub use self::queue_experiments::persistence::{FsyncPolicy};
+    pub use self::queue_experiments::queue_table::{OverflowPolicy};
+    pub use self::queue_experiments::shards::{ShardRange};
+    pub use std::path::PathBuf;
+
+    describe! config {
+        it "it_defaults_to_listening_on_localhost" {
+            assert_eq!(
+                Config::from_args(vec![]).unwrap().listen,
+                vec!["127.0.0.1:5248".parse().unwrap()]
+                );
+        }
+
+        it "it_reads_settings_from_file_contents" {
+            let config = Config::from_file_contents("# comment\n\nmaxmemory 1024\noverflow drop\n").unwrap();
+            assert_eq!(config.maxmemory, 1024);
+            assert_eq!(config.queue_limits.overflow, OverflowPolicy::DropOldest);
+        }
+
+        it "it_accepts_multiple_listen_addresses" {
+            let config = Config::from_file_contents("listen 127.0.0.1:1\nlisten [::1]:2\n").unwrap();
+            assert_eq!(config.listen, vec!["127.0.0.1:1".parse().unwrap(), "[::1]:2".parse().unwrap()]);
+        }
+
+        it "it_applies_command_line_flags" {
+            let args = vec!["--maxmemory".to_string(), "10".to_string(), "--max-queue-length".to_string(), "5".to_string()];
+            let config = Config::from_args(args).unwrap();
+            assert_eq!(config.maxmemory, 10);
+            assert_eq!(config.queue_limits.max_len, Some(5));
+        }
+
+        it "it_reads_the_leader_to_follow" {
+            let config = Config::from_file_contents("follow 127.0.0.1:5248\nfollow-auth replica secret\n").unwrap();
+            assert_eq!(config.follow, Some("127.0.0.1:5248".parse().unwrap()));
+            assert_eq!(config.follow_auth, Some(("replica".to_string(), "secret".to_string())));
+        }
+
+        it "it_reads_the_cluster_nodes" {
+            let auth = format!("user node {}\ncluster-auth node secret\n", PasswordHash::new("secret"));
+            let config = Config::from_file_contents(&format!("{}cluster-node 1 127.0.0.1:5001\ncluster-node 2 127.0.0.1:5002\ncluster-id 2\n", auth)).unwrap();
+            assert_eq!(config.cluster_nodes, vec![(1, "127.0.0.1:5001".parse().unwrap()), (2, "127.0.0.1:5002".parse().unwrap())]);
+            assert_eq!(config.cluster_id, Some(2));
+            assert_eq!(
+                Config::from_file_contents(&format!("{}cluster-node 1 127.0.0.1:5001\ncluster-id 3\n", auth)),
+                Err("cluster-id 3 is not one of the cluster-node settings".to_string())
+                );
+            assert_eq!(
+                Config::from_file_contents(&format!("{}cluster-node 1 127.0.0.1:5001\ncluster-node 1 127.0.0.1:5002\ncluster-id 1\n", auth)),
+                Err("cluster-node 1 is set more than once".to_string())
+                );
+        }
+
+        it "it_requires_cluster_auth_in_cluster_mode" {
+            let hash = PasswordHash::new("secret");
+            let nodes = "cluster-node 1 127.0.0.1:5001\ncluster-id 1\n";
+            assert_eq!(
+                Config::from_file_contents(nodes),
+                Err("cluster mode requires cluster-auth and user settings".to_string())
+                );
+            assert_eq!(
+                Config::from_file_contents(&format!("{}user node {}\ncluster-auth node wrong\n", nodes, hash)),
+                Err("cluster-auth must be the name and password of a user setting".to_string())
+                );
+            assert_eq!(
+                Config::from_file_contents(&format!("{}user node {}\ncluster-auth node secret\nacl node admin *\n", nodes, hash)),
+                Err("cluster-auth user node needs the server permission".to_string())
+                );
+        }
+
+        it "it_reads_the_shards" {
+            let config = Config::from_file_contents("shard 0-8191 127.0.0.1:5001\nshard 8192-16383 127.0.0.1:5002\nshard-self 127.0.0.1:5002\n").unwrap();
+            assert_eq!(config.shards, vec![
+                ShardRange { start: 0, end: 8191, address: "127.0.0.1:5001".parse().unwrap() },
+                ShardRange { start: 8192, end: 16383, address: "127.0.0.1:5002".parse().unwrap() }
+            ]);
+            assert_eq!(config.shard_self, Some("127.0.0.1:5002".parse().unwrap()));
+            assert_eq!(
+                Config::from_file_contents("shard 0-8000 127.0.0.1:5001\nshard 8192-16383 127.0.0.1:5002\nshard-self 127.0.0.1:5001\n"),
+                Err("slots 8001-8191 are not in any shard".to_string())
+                );
+            assert_eq!(
+                Config::from_file_contents("shard 0-16384 127.0.0.1:5001\n"),
+                Err("config line 1: invalid slot range 0-16384".to_string())
+                );
+        }
+
+        it "it_returns_err_for_unknown_settings" {
+            assert_eq!(
+                Config::from_file_contents("foo 1\n"),
+                Err("config line 1: unknown setting foo".to_string())
+                );
+        }
+
+        it "it_returns_err_for_invalid_values" {
+            assert_eq!(
+                Config::from_args(vec!["--maxmemory".to_string(), "abc".to_string()]),
+                Err("--maxmemory: invalid number abc".to_string())
+                );
+        }
+
+        it "it_returns_err_for_flags_without_values" {
+            assert_eq!(
+                Config::from_args(vec!["--listen".to_string()]),
+                Err("--listen requires a value".to_string())
+                );
+        }
+
+        it "it_allows_unix_socket_instead_of_tcp" {
+            let config = Config::from_file_contents("listen none\nunixsocket /tmp/queue.sock\nunixsocketperm 770\n").unwrap();
+            assert_eq!(config.listen, vec![]);
+            assert_eq!(config.unixsocket, Some(PathBuf::from("/tmp/queue.sock")));
+            assert_eq!(config.unixsocketperm, 0o770);
+        }
+
+        it "it_returns_err_when_there_is_nothing_to_listen_on" {
+            assert_eq!(
+                Config::from_file_contents("listen none\n"),
+                Err("nothing to listen on, set listen, tls-listen or unixsocket".to_string())
+                );
+        }
+
+        it "it_reads_tls_settings" {
+            let config = Config::from_file_contents("tls-listen 127.0.0.1:5249\ntls-cert cert.pem\ntls-key key.pem\n").unwrap();
+            assert_eq!(config.tls_listen, vec!["127.0.0.1:5249".parse().unwrap()]);
+            assert_eq!(config.tls_cert, Some(PathBuf::from("cert.pem")));
+            assert_eq!(config.tls_ca_cert, None);
+        }
+
+        it "it_returns_err_for_tls_without_certificate" {
+            assert_eq!(
+                Config::from_file_contents("tls-listen 127.0.0.1:5249\n"),
+                Err("tls-listen requires tls-cert and tls-key".to_string())
+                );
+        }
+
+        it "it_reads_users" {
+            let hash = PasswordHash::new("secret");
+            let config = Config::from_file_contents(&format!("user worker {}\n", hash)).unwrap();
+            assert!(config.users.authenticate("worker", "secret"));
+            assert!(!config.users.authenticate("worker", "wrong"));
+        }
+
+        it "it_returns_err_for_invalid_password_hashes" {
+            assert_eq!(
+                Config::from_file_contents("user worker secret\n"),
+                Err("config line 1: invalid password hash, expected pbkdf2-sha256$iterations$salt$hash".to_string())
+                );
+        }
+
+        it "it_reads_acl_rules" {
+            let config = Config::from_file_contents("acl web push,pop billing.*\n").unwrap();
+            assert!(config.users.allows("web", Permission::Push, "billing.invoices"));
+            assert!(!config.users.allows("web", Permission::Admin, "billing.invoices"));
+            assert!(!config.users.allows("web", Permission::Push, "email"));
+        }
+
+        it "it_keeps_server_permission_apart_from_admin_on_every_queue" {
+            let config = Config::from_file_contents("acl ops admin *\nacl root server *\nacl all all *\n").unwrap();
+            assert!(config.users.allows("ops", Permission::Admin, "*"));
+            assert!(!config.users.allows("ops", Permission::Server, SERVER));
+            assert!(config.users.allows("root", Permission::Server, SERVER));
+            assert!(!config.users.allows("root", Permission::Admin, "billing"));
+            assert!(config.users.allows("all", Permission::Server, SERVER));
+        }
+
+        it "it_returns_err_for_server_permission_on_a_queue_pattern" {
+            assert_eq!(
+                Config::from_file_contents("acl ops server billing.*\n"),
+                Err("config line 1: the server permission needs the pattern *".to_string())
+                );
+        }
+
+        it "it_matches_acl_patterns_with_many_wildcards_quickly" {
+            let config = Config::from_file_contents("acl web push a*a*a*a*a*a*a*a*a*a*a*a*b\n").unwrap();
+            let name = "a".repeat(200);
+            assert!(!config.users.allows("web", Permission::Push, &name));
+            assert!(config.users.allows("web", Permission::Push, &(name + "b")));
+            assert!(config.users.allows("web", Permission::Push, "aaaaaaaaaaaab"));
+            assert!(!config.users.allows("web", Permission::Push, "aaaaaaaaaaab"));
+        }
+
+        it "it_returns_err_for_unknown_permissions" {
+            assert_eq!(
+                Config::from_file_contents("acl web read *\n"),
+                Err("config line 1: unknown permission read".to_string())
+                );
+        }
+
+        it "it_reads_persistence_settings" {
+            let config = Config::from_file_contents("appendonly /tmp/queue.aof\nappendfsync always\n").unwrap();
+            assert_eq!(config.appendonly, Some(PathBuf::from("/tmp/queue.aof")));
+            assert_eq!(config.appendfsync, FsyncPolicy::Always);
+            assert_eq!(
+                Config::from_file_contents("appendonly /tmp/queue.aof\nfollow 127.0.0.1:5248\n"),
+                Err("appendonly can't be used with follow or in cluster mode".to_string())
+                );
+            assert_eq!(
+                Config::from_file_contents("appendfsync sometimes\n"),
+                Err("config line 1: unknown appendfsync policy sometimes".to_string())
+                );
+        }
+
+        it "it_reads_log_settings" {
+            let config = Config::from_file_contents("log-level debug\nlog-format json\ntrace-commands yes\n").unwrap();
+            assert_eq!(config.log_format, LogFormat::Json);
+            assert!(config.trace_commands);
+        }
+    }
+}
@@ -4,70 +4,459 @@
 #[cfg(test)]
 mod tests {
     extern crate queue_experiments;
-    pub use self::queue_experiments::connection::{Connection};
-    pub use self::queue_experiments::queue_table::{QueueTable};
-    pub use std::io::Cursor;
+    pub use self::queue_experiments::acl::{AclRule};
+    pub use self::queue_experiments::auth::{Users,PasswordHash,Verifier};
+    pub use self::queue_experiments::clients::{ClientAction};
+    pub use self::queue_experiments::connection::{Connection,Status};
+    pub use self::queue_experiments::queue_table::{QueueTable,Item};
+    pub use std::thread;
+    pub use std::time::{Duration,Instant};
 
     describe! connection {
         before_each {
             let queue_table = QueueTable::new();
             let _queue = queue_table.get_or_create_queue("queue".to_string());
 
-            let mut writer = Cursor::new(Vec::new());
+            let mut connection = Connection::new(&queue_table);
         }
 
-        describe! listen {
-            it "listen_stops_for_quit_command" {
-                let mut reader = Cursor::new(b"QUIT");
-                let mut connection = Connection::new(&mut reader, &mut writer, &queue_table);
-                connection.listen();
+        describe! close {
+            it "feed_closes_for_quit_command" {
+                assert_eq!(connection.feed(b"QUIT;"), Status::Closed);
+            }
+
+            it "feed_closes_when_too_much_input_is_buffered" {
+                connection.feed(b"BPOP 'other';");
+                let commands = b"PUSH 'queue' 'data';".repeat(1 << 20);
+
+                assert_eq!(connection.feed(&commands[..]), Status::Blocked);
+                assert_eq!(connection.feed(&commands[..]), Status::Blocked);
+                assert_eq!(connection.feed(&commands[..]), Status::Blocked);
+                assert_eq!(connection.feed(&commands[..]), Status::Closed);
+                assert_eq!(_queue.len(), 0);
+            }
+
+            it "close_rolls_back_open_transaction" {
+                _queue.push_back("data".to_string()).unwrap();
+
+                connection.feed(b"BEGIN;POP 'queue';");
+                connection.close();
+
+                assert_eq!(_queue.pop_front(), Some("data".to_string()));
             }
         }
 
-        describe! process_message {
-            it "process_message_executes_push" {
-                let mut reader = Cursor::new(b"PUSH 'queue' 'data';");
+        describe! auth {
+            before_each {
+                let mut users = Users::new();
+                users.add("worker".to_string(), PasswordHash::new("secret"));
+                users.add_rule(AclRule::parse("worker pop,push queue").unwrap());
+                let mut connection = Connection::with_users(&queue_table, &users);
+            }
 
-                {
-                    let mut connection = Connection::new(&mut reader, &mut writer, &queue_table);
-                    connection.process_message();
+            it "feed_rejects_commands_before_auth" {
+                connection.feed(b"PUSH 'queue' 'data';");
+
+                assert_eq!(String::from_utf8(connection.take_output()).unwrap(), "NOT AUTHENTICATED\r\n".to_string());
+                assert_eq!(_queue.pop_front(), None);
+            }
+
+            it "feed_accepts_commands_after_auth" {
+                connection.feed(b"AUTH 'worker' 'wrong';AUTH 'worker' 'secret';PUSH 'queue' 'data';");
+
+                assert_eq!(String::from_utf8(connection.take_output()).unwrap(), "INVALID USER OR PASSWORD\r\nSUCCESS\r\nSUCCESS\r\n".to_string());
+            }
+
+            it "feed_closes_the_connection_after_repeated_failed_auth" {
+                let status = connection.feed(b"AUTH 'worker' 'a';AUTH 'nobody' 'b';AUTH 'worker' 'c';AUTH 'worker' 'secret';");
+
+                assert_eq!(status, Status::Closed);
+                assert_eq!(String::from_utf8(connection.take_output()).unwrap(), "INVALID USER OR PASSWORD\r\n".repeat(3));
+            }
+
+            it "feed_blocks_auth_until_the_verifier_has_checked_the_password" {
+                connection.set_verifier(&Verifier::new(|| {}));
+
+                assert_eq!(connection.feed(b"AUTH 'worker' 'secret';PUSH 'queue' 'data';"), Status::Blocked);
+                let mut status = Status::Blocked;
+                for _ in 0..100 {
+                    status = connection.retry_blocked();
+                    if status != Status::Blocked {
+                        break;
+                    }
+                    thread::sleep(Duration::from_millis(50));
                 }
 
+                assert_eq!(status, Status::Ready);
+                assert_eq!(String::from_utf8(connection.take_output()).unwrap(), "SUCCESS\r\nSUCCESS\r\n".to_string());
+            }
+
+            it "feed_rejects_commands_not_granted_by_acl" {
+                connection.feed(b"AUTH 'worker' 'secret';PUSH 'other' 'data';LIMIT 'queue' '1' '0' 'reject';");
+
+                assert_eq!(String::from_utf8(connection.take_output()).unwrap(), "SUCCESS\r\nNOT ALLOWED\r\nNOT ALLOWED\r\n".to_string());
+            }
+
+            it "feed_rejects_dead_letter_queues_the_user_cant_push_to" {
+                let mut users = Users::new();
+                users.add("ops".to_string(), PasswordHash::new("secret"));
+                users.add_rule(AclRule::parse("ops admin queue").unwrap());
+                users.add_rule(AclRule::parse("ops push failed").unwrap());
+                let mut connection = Connection::with_users(&queue_table, &users);
+                connection.feed(b"AUTH 'ops' 'secret';DEADLETTER 'queue' 'other';DEADLETTER 'queue' 'failed';");
+
+                assert_eq!(String::from_utf8(connection.take_output()).unwrap(), "SUCCESS\r\nNOT ALLOWED\r\nSUCCESS\r\n".to_string());
+            }
+
+            it "feed_rejects_pushes_in_transaction_not_granted_by_acl" {
+                connection.feed(b"AUTH 'worker' 'secret';BEGIN;PUSH 'queue' 'a';PUSH 'other' 'b';COMMIT;");
+
+                assert_eq!(String::from_utf8(connection.take_output()).unwrap(), "SUCCESS\r\nNOT ALLOWED\r\n".to_string());
+                assert_eq!(_queue.pop_front(), Some("a".to_string()));
+                assert_eq!(queue_table.get_queue(&"other".to_string()).map(|queue| queue.len()), None);
+            }
+        }
+
+        describe! shutdown {
+            it "shutdown_releases_blocked_bpop" {
+                connection.feed(b"BPOP 'queue';");
+
+                assert_eq!(connection.shutdown(), Status::Closed);
+                assert_eq!(String::from_utf8(connection.take_output()).unwrap(), "SHUTTING DOWN\r\n".to_string());
+            }
+
+            it "shutdown_lets_open_transaction_commit" {
+                connection.feed(b"BEGIN;PUSH 'queue' 'data';");
+
+                assert_eq!(connection.shutdown(), Status::Ready);
+                assert_eq!(connection.feed(b"COMMIT;"), Status::Closed);
                 assert_eq!(_queue.pop_front(), Some("data".to_string()));
             }
+        }
 
-            it "process_message_outputs_success_for_push" {
-                let mut reader = Cursor::new(b"PUSH 'queue' 'data';");
+        describe! feed {
+            it "feed_executes_push" {
+                connection.feed(b"PUSH 'queue' 'data';");
 
-                {
-                    let mut connection = Connection::new(&mut reader, &mut writer, &queue_table);
-                    connection.process_message();
-                }
+                assert_eq!(_queue.pop_front(), Some("data".to_string()));
+            }
 
-                assert_eq!(String::from_utf8(writer.into_inner()).unwrap(), "SUCCESS\r\n".to_string());
+            it "feed_outputs_success_for_push" {
+                connection.feed(b"PUSH 'queue' 'data';");
+
+                assert_eq!(String::from_utf8(connection.take_output()).unwrap(), "SUCCESS\r\n".to_string());
             }
 
-            it "process_message_executes_pop" {
-                let mut reader = Cursor::new(b"POP 'queue';");
-                _queue.push_back("data".to_string());
+            it "feed_executes_pop" {
+                _queue.push_back("data".to_string()).unwrap();
 
-                {
-                    let mut connection = Connection::new(&mut reader, &mut writer, &queue_table);
-                    connection.process_message();
-                }
+                connection.feed(b"POP 'queue';");
 
                 assert_eq!(_queue.pop_front(), None);
             }
 
-            it "process_message_outputs_value_for_pop" {
-                let mut reader = Cursor::new(b"POP 'queue';");
-                _queue.push_back("data".to_string());
+            it "feed_outputs_value_for_pop" {
+                _queue.push_back("data".to_string()).unwrap();
 
-                {
-                    let mut connection = Connection::new(&mut reader, &mut writer, &queue_table);
-                    connection.process_message();
-                }
-                assert_eq!(String::from_utf8(writer.into_inner()).unwrap(), "data\r\n".to_string());
+                connection.feed(b"POP 'queue';");
+
+                assert_eq!(String::from_utf8(connection.take_output()).unwrap(), "data\r\n".to_string());
+            }
+
+            it "feed_waits_for_end_of_message" {
+                connection.feed(b"PUSH 'queue' ");
+                connection.feed(b"'data';");
+
+                assert_eq!(_queue.pop_front(), Some("data".to_string()));
+            }
+
+            it "feed_blocks_bpop_until_data_is_pushed" {
+                assert_eq!(connection.feed(b"BPOP 'queue';PUSH 'queue' 'other';"), Status::Blocked);
+
+                _queue.push_back("data".to_string()).unwrap();
+
+                assert_eq!(connection.retry_blocked(), Status::Ready);
+                assert_eq!(String::from_utf8(connection.take_output()).unwrap(), "data\r\nSUCCESS\r\n".to_string());
+            }
+
+            it "feed_answers_bpop_with_no_data_once_its_timeout_passes" {
+                assert_eq!(connection.feed(b"BPOP 'queue' '10';PUSH 'queue' 'other';"), Status::Blocked);
+                assert!(connection.deadline().is_some());
+
+                thread::sleep(Duration::from_millis(10));
+
+                assert_eq!(connection.retry_blocked(), Status::Ready);
+                assert_eq!(String::from_utf8(connection.take_output()).unwrap(), "NO DATA\r\nSUCCESS\r\n".to_string());
+            }
+
+            it "feed_counts_rollbacks_in_the_attempt_of_bpop_with_attempts" {
+                _queue.push_back("data".to_string()).unwrap();
+
+                connection.feed(b"BEGIN;BPOP 'queue' '0' 'withattempts';ABORT;BEGIN;BPOP 'queue' '0' 'withattempts';ABORT;BPOP 'queue' '0' 'withattempts';");
+
+                assert_eq!(String::from_utf8(connection.take_output()).unwrap(), "1 data\r\n2 data\r\n3 data\r\n".to_string());
+            }
+
+            it "feed_holds_items_rolled_back_with_a_delay_until_it_passes" {
+                _queue.push_back("data".to_string()).unwrap();
+
+                connection.feed(b"BEGIN;POP 'queue';ABORT '10';");
+                queue_table.requeue_delayed();
+                assert_eq!(_queue.pop_front(), None);
+                assert_eq!(_queue.snapshot().items.len(), 1);
+
+                thread::sleep(Duration::from_millis(10));
+                queue_table.requeue_delayed();
+
+                assert_eq!(queue_table.next_requeue(), None);
+                assert_eq!(_queue.pop_front(), Some("data".to_string()));
+            }
+
+            it "feed_holds_message_group_in_transaction" {
+                _queue.push_back_with_group("a1".to_string(), Some("a".to_string())).unwrap();
+                _queue.push_back_with_group("a2".to_string(), Some("a".to_string())).unwrap();
+                _queue.push_back_with_group("b1".to_string(), Some("b".to_string())).unwrap();
+
+                connection.feed(b"BEGIN;POP 'queue';");
+
+                assert_eq!(_queue.pop_front(), Some("b1".to_string()));
+                assert_eq!(_queue.pop_front(), None);
+            }
+
+            it "feed_releases_message_group_on_commit" {
+                _queue.push_back_with_group("a1".to_string(), Some("a".to_string())).unwrap();
+                _queue.push_back_with_group("a2".to_string(), Some("a".to_string())).unwrap();
+
+                connection.feed(b"BEGIN;POP 'queue';COMMIT;");
+
+                assert_eq!(_queue.pop_front(), Some("a2".to_string()));
+            }
+
+            it "feed_keeps_message_group_order_on_abort" {
+                _queue.push_back_with_group("a1".to_string(), Some("a".to_string())).unwrap();
+                _queue.push_back_with_group("a2".to_string(), Some("a".to_string())).unwrap();
+
+                connection.feed(b"BEGIN;POP 'queue';ABORT;");
+
+                assert_eq!(_queue.pop_front(), Some("a1".to_string()));
+            }
+
+            it "feed_rejects_push_to_full_queue" {
+                connection.feed(b"LIMIT 'queue' '1' '0' 'reject';PUSH 'queue' 'a';PUSH 'queue' 'b';");
+
+                assert_eq!(String::from_utf8(connection.take_output()).unwrap(), "SUCCESS\r\nSUCCESS\r\nQUEUE FULL\r\n".to_string());
+                assert_eq!(_queue.pop_front(), Some("a".to_string()));
+            }
+
+            it "feed_drops_oldest_from_full_queue" {
+                connection.feed(b"LIMIT 'queue' '0' '2' 'drop';PUSH 'queue' 'a';PUSH 'queue' 'b';PUSH 'queue' 'c';");
+
+                assert_eq!(_queue.pop_front(), Some("b".to_string()));
+                assert_eq!(_queue.pop_front(), Some("c".to_string()));
+            }
+
+            it "feed_blocks_push_until_queue_has_room" {
+                assert_eq!(connection.feed(b"LIMIT 'queue' '1' '0' 'block';PUSH 'queue' 'a';PUSH 'queue' 'b';"), Status::Blocked);
+
+                assert_eq!(_queue.pop_front(), Some("a".to_string()));
+
+                assert_eq!(connection.retry_blocked(), Status::Ready);
+                assert_eq!(_queue.pop_front(), Some("b".to_string()));
+            }
+
+            it "feed_blocks_commit_until_queue_has_room_for_every_push" {
+                assert_eq!(connection.feed(b"LIMIT 'queue' '2' '0' 'block';PUSH 'queue' 'x';BEGIN;PUSH 'queue' 'a';PUSH 'queue' 'b';COMMIT;"), Status::Blocked);
+                assert_eq!(_queue.len(), 1);
+
+                assert_eq!(_queue.pop_front(), Some("x".to_string()));
+
+                assert_eq!(connection.retry_blocked(), Status::Ready);
+                assert_eq!((_queue.pop_front(), _queue.pop_front()), (Some("a".to_string()), Some("b".to_string())));
+            }
+
+            it "feed_refuses_commit_with_more_pushes_than_a_blocking_queue_holds" {
+                assert_eq!(connection.feed(b"LIMIT 'queue' '1' '0' 'block';BEGIN;PUSH 'queue' 'a';PUSH 'queue' 'b';COMMIT;"), Status::Ready);
+
+                assert_eq!(String::from_utf8(connection.take_output()).unwrap(), "SUCCESS\r\nQUEUE FULL: queue\r\n".to_string());
+                assert_eq!(_queue.len(), 0);
+            }
+
+            it "feed_rolls_back_commit_when_a_push_doesnt_fit" {
+                connection.feed(b"PUSH 'source' 'item';LIMIT 'queue' '1' '0' 'reject';PUSH 'queue' 'x';BEGIN;POP 'source';PUSH 'other' 'a';PUSH 'queue' 'b';COMMIT;");
+
+                assert_eq!(String::from_utf8(connection.take_output()).unwrap(), "SUCCESS\r\nSUCCESS\r\nSUCCESS\r\nitem\r\nQUEUE FULL: queue\r\n".to_string());
+                assert_eq!(queue_table.get_queue(&"source".to_string()).unwrap().pop_front(), Some("item".to_string()));
+                assert_eq!(queue_table.get_queue(&"other".to_string()).map_or(0, |queue| queue.len()), 0);
+                assert_eq!((_queue.len(), queue_table.memory().used()), (1, 1));
+            }
+
+            it "feed_refuses_push_over_maxmemory" {
+                connection.feed(b"MAXMEMORY '5';PUSH 'queue' 'abc';PUSH 'queue' 'abc';MEMORY;");
+
+                assert_eq!(String::from_utf8(connection.take_output()).unwrap(), "SUCCESS\r\nSUCCESS\r\nOUT OF MEMORY\r\nused_memory:3 maxmemory:5\r\n".to_string());
+            }
+
+            it "feed_counts_transaction_buffers_in_memory" {
+                connection.feed(b"BEGIN;PUSH 'queue' 'abc';MEMORY;ABORT;MEMORY;");
+
+                assert_eq!(String::from_utf8(connection.take_output()).unwrap(), "used_memory:3 maxmemory:0\r\nused_memory:0 maxmemory:0\r\n".to_string());
+            }
+
+            it "feed_skips_expired_items_on_pop" {
+                let expired = Instant::now() - Duration::from_secs(1);
+                _queue.push_item(Item::new("old".to_string(), None, Some(expired))).unwrap();
+                _queue.push_back("new".to_string()).unwrap();
+
+                connection.feed(b"POP 'queue';");
+
+                assert_eq!(String::from_utf8(connection.take_output()).unwrap(), "new\r\n".to_string());
+                assert_eq!(queue_table.memory().used(), 0);
+            }
+
+            it "feed_moves_expired_items_to_dead_letter_queue" {
+                let expired = Instant::now() - Duration::from_secs(1);
+                _queue.push_item(Item::new("old".to_string(), None, Some(expired))).unwrap();
+
+                connection.feed(b"DEADLETTER 'queue' 'dead';POP 'queue';POP 'dead';");
+
+                assert_eq!(String::from_utf8(connection.take_output()).unwrap(), "SUCCESS\r\nNO DATA\r\nold\r\n".to_string());
+            }
+
+            it "feed_moves_expired_items_to_a_full_dead_letter_queue" {
+                let expired = Instant::now() - Duration::from_secs(1);
+                _queue.push_item(Item::new("old".to_string(), None, Some(expired))).unwrap();
+
+                connection.feed(b"LIMIT 'dead' '1' '0' 'reject';PUSH 'dead' 'full';DEADLETTER 'queue' 'dead';POP 'queue';POP 'dead';POP 'dead';");
+
+                assert_eq!(String::from_utf8(connection.take_output()).unwrap(), "SUCCESS\r\nSUCCESS\r\nSUCCESS\r\nNO DATA\r\nfull\r\nold\r\n".to_string());
+            }
+
+            it "feed_does_not_remove_stream_entries_on_read" {
+                connection.feed(b"SPUSH 'stream' 'data';SREAD 'stream' 'group';SREAD 'stream' 'group';");
+
+                assert_eq!(String::from_utf8(connection.take_output()).unwrap(), "0\r\n1\r\n0 data\r\n1\r\n0 data\r\n".to_string());
+            }
+
+            it "feed_reads_after_committed_stream_offset" {
+                connection.feed(b"SPUSH 'stream' 'a';SPUSH 'stream' 'b';SCOMMIT 'stream' 'group' '0';SREAD 'stream' 'group';SREAD 'stream' 'other';");
+
+                assert_eq!(String::from_utf8(connection.take_output()).unwrap(), "0\r\n1\r\nSUCCESS\r\n1\r\n1 b\r\n1\r\n0 a\r\n".to_string());
+            }
+
+            it "feed_does_not_commit_stream_offset_on_abort" {
+                connection.feed(b"SPUSH 'stream' 'a';BEGIN;SCOMMIT 'stream' 'group' '0';ABORT;SREAD 'stream' 'group';");
+
+                assert_eq!(String::from_utf8(connection.take_output()).unwrap(), "0\r\n1\r\n0 a\r\n".to_string());
+            }
+
+            it "feed_reports_queue_counters_in_info" {
+                connection.feed(b"PUSH 'queue' 'a';PUSH 'queue' 'b';BEGIN;POP 'queue';COMMIT;BEGIN;POP 'queue';ABORT;");
+                connection.take_output();
+
+                let stats = queue_table.get_queue(&"queue".to_string()).unwrap().stats();
+                assert_eq!((stats.len, stats.pushed, stats.popped, stats.committed, stats.rolled_back), (1, 2, 2, 1, 1));
+            }
+
+            it "feed_counts_open_transactions_and_blocked_clients" {
+                let mut other = Connection::new(&queue_table);
+                connection.feed(b"BEGIN;");
+                other.feed(b"BPOP 'queue';");
+
+                assert_eq!(queue_table.stats().connected_clients(), 2);
+                assert_eq!(queue_table.stats().open_transactions(), 1);
+                assert_eq!(queue_table.stats().blocked_clients(), 1);
+
+                other.close();
+                assert_eq!(queue_table.stats().blocked_clients(), 0);
+            }
+
+            it "feed_records_slow_commands" {
+                queue_table.slowlog().set_slower_than(0);
+
+                connection.feed(b"PUSH 'queue' 'data';AUTH 'user' 'password';");
+
+                let commands: Vec<String> = queue_table.slowlog().entries(None).into_iter().map(|entry| entry.command).collect();
+                assert_eq!(commands, vec!["AUTH (redacted)".to_string(), "PUSH 'queue' 'data'".to_string()]);
+            }
+
+            it "feed_streams_other_clients_commands_to_monitor" {
+                let mut other = Connection::new(&queue_table);
+                connection.feed(b"MONITOR;");
+                connection.take_output();
+
+                other.feed(b"PUSH 'queue' 'data';");
+
+                let output = String::from_utf8(connection.take_output()).unwrap();
+                assert!(output.ends_with(&format!("[{} unknown] PUSH 'queue' 'data'\r\n", other.id())));
+            }
+
+            it "feed_lists_clients_with_name_command_and_transaction_size" {
+                let mut other = Connection::new(&queue_table);
+                other.set_peer("127.0.0.1:4000".to_string());
+                other.feed(b"CLIENT 'setname' 'worker';BEGIN;PUSH 'queue' 'a';PUSH 'queue' 'b';");
+
+                connection.feed(b"CLIENT 'list';");
+
+                let output = String::from_utf8(connection.take_output()).unwrap();
+                assert_eq!(output, format!("2\r\nid={} peer=unknown name=- cmd=CLIENT tx=0 idle=0\r\nid={} peer=127.0.0.1:4000 name=worker cmd=PUSH tx=2 idle=0\r\n",
+                                           connection.id(), other.id()));
+            }
+
+            it "feed_kills_other_clients" {
+                let other = Connection::new(&queue_table);
+                connection.feed(b"CLIENT 'kill' '1000';");
+                connection.feed(format!("CLIENT 'kill' '{}';", other.id()).as_bytes());
+
+                assert_eq!(String::from_utf8(connection.take_output()).unwrap(), "NO SUCH CLIENT\r\nSUCCESS\r\n".to_string());
+                assert_eq!(queue_table.clients().take_actions(), vec![(other.id(), ClientAction::Kill)]);
+            }
+
+            it "feed_lists_open_transactions" {
+                _queue.push_back("a".to_string()).unwrap();
+                _queue.push_back("b".to_string()).unwrap();
+                let mut other = Connection::new(&queue_table);
+                other.feed(b"BEGIN;POP 'queue';POP 'queue';PUSH 'other' 'c';");
+
+                connection.feed(b"TXLIST;");
+
+                let output = String::from_utf8(connection.take_output()).unwrap();
+                assert!(output.starts_with(&format!("1\r\nclient={} peer=unknown name=- began=", other.id())));
+                assert!(output.ends_with(" age=0 pushes=1 pops=queue:2\r\n"));
+            }
+
+            it "abort_transaction_rolls_back_until_client_ends_it" {
+                _queue.push_back("a".to_string()).unwrap();
+                let mut other = Connection::new(&queue_table);
+                other.feed(b"BEGIN;POP 'queue';");
+                other.take_output();
+
+                connection.feed(format!("TXABORT '{}';TXABORT '{}';", other.id(), connection.id()).as_bytes());
+                assert_eq!(String::from_utf8(connection.take_output()).unwrap(), "SUCCESS\r\nNO SUCH TRANSACTION\r\n".to_string());
+                other.abort_transaction();
+
+                assert_eq!(_queue.len(), 1);
+                other.feed(b"POP 'queue';COMMIT;POP 'queue';");
+                assert_eq!(String::from_utf8(other.take_output()).unwrap(), "TRANSACTION ABORTED\r\nTRANSACTION ABORTED\r\na\r\n".to_string());
+            }
+
+            it "feed_refuses_stream_commits_in_transaction_past_the_end" {
+                connection.feed(b"SPUSH 'stream' 'a';BEGIN;SCOMMIT 'stream' 'group' '1';SCOMMIT 'missing' 'group' '0';SCOMMIT 'stream' 'group' '0';COMMIT;SREAD 'stream' 'group';");
+
+                assert_eq!(String::from_utf8(connection.take_output()).unwrap(), "0\r\nOFFSET OUT OF RANGE\r\nNO SUCH STREAM\r\nNO DATA\r\n".to_string());
+            }
+
+            it "feed_applies_stream_retention_in_transaction_on_commit" {
+                connection.feed(b"SPUSH 'stream' 'a';SPUSH 'stream' 'b';BEGIN;SRETAIN 'stream' '1' '0';SREAD 'stream' 'group';");
+                assert_eq!(String::from_utf8(connection.take_output()).unwrap(), "0\r\n1\r\n1\r\n0 a\r\n".to_string());
+
+                connection.feed(b"COMMIT;SREAD 'stream' 'group';");
+                assert_eq!(String::from_utf8(connection.take_output()).unwrap(), "1\r\n1 b\r\n".to_string());
+            }
+
+            it "feed_trims_stream_by_retention" {
+                connection.feed(b"SRETAIN 'stream' '1' '0';SPUSH 'stream' 'a';SPUSH 'stream' 'b';SREAD 'stream' 'group';");
+
+                assert_eq!(String::from_utf8(connection.take_output()).unwrap(), "SUCCESS\r\n0\r\n1\r\n1\r\n1 b\r\n".to_string());
             }
         }
     }
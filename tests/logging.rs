@@ -0,0 +1,42 @@
+#![feature(plugin)]
+#![cfg_attr(test, plugin(stainless))]
+
+#[cfg(test)]
+mod tests {
+    extern crate log;
+    extern crate queue_experiments;
+    pub use self::log::{Level,Record};
+    pub use self::queue_experiments::logging::{format_record,LogFormat};
+
+    describe! logging {
+        it "it_formats_text_records_with_fields" {
+            let fields = [("client", "1"), ("error", "Unknown Command: BAD")];
+            let record = Record::builder()
+                .level(Level::Warn)
+                .target("queue_experiments::connection")
+                .args(format_args!("could not parse command"))
+                .key_values(&fields)
+                .build();
+
+            assert_eq!(
+                format_record(LogFormat::Text, &record, "2026-01-02T03:04:05.678Z"),
+                "2026-01-02T03:04:05.678Z WARN could not parse command client=1 error=\"Unknown Command: BAD\"\n".to_string()
+                );
+        }
+
+        it "it_formats_json_records_with_numbers_unquoted" {
+            let fields = [("client", 7)];
+            let record = Record::builder()
+                .level(Level::Info)
+                .target("queue_experiments::event_loop")
+                .args(format_args!("connection \"opened\""))
+                .key_values(&fields)
+                .build();
+
+            assert_eq!(
+                format_record(LogFormat::Json, &record, "2026-01-02T03:04:05.678Z"),
+                "{\"ts\":\"2026-01-02T03:04:05.678Z\",\"level\":\"INFO\",\"msg\":\"connection \\\"opened\\\"\",\"client\":7}\n".to_string()
+                );
+        }
+    }
+}
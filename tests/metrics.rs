@@ -0,0 +1,43 @@
+#![feature(plugin)]
+#![cfg_attr(test, plugin(stainless))]
+
+#[cfg(test)]
+mod tests {
+    extern crate queue_experiments;
+    pub use self::queue_experiments::metrics::{render};
+    pub use self::queue_experiments::queue_table::{QueueTable};
+    pub use std::time::{Duration};
+
+    describe! metrics {
+        before_each {
+            let queue_table = QueueTable::new();
+        }
+
+        it "it_renders_queue_depth_with_queue_label" {
+            queue_table.get_or_create_queue("emails".to_string()).push_back("data".to_string()).unwrap();
+
+            let metrics = render(&queue_table);
+
+            assert!(metrics.contains("# TYPE queue_length gauge\n"));
+            assert!(metrics.contains("queue_length{queue=\"emails\"} 1\n"));
+            assert!(metrics.contains("queue_pushed_total{queue=\"emails\"} 1\n"));
+        }
+
+        it "it_escapes_queue_names_in_labels" {
+            queue_table.get_or_create_queue("a\"b".to_string());
+
+            assert!(render(&queue_table).contains("queue_length{queue=\"a\\\"b\"} 0\n"));
+        }
+
+        it "it_renders_cumulative_bpop_wait_buckets" {
+            queue_table.stats().record_bpop_wait(Duration::from_millis(2));
+            queue_table.stats().record_bpop_wait(Duration::from_secs(2));
+
+            let metrics = render(&queue_table);
+
+            assert!(metrics.contains("queue_bpop_wait_seconds_bucket{le=\"0.005\"} 1\n"));
+            assert!(metrics.contains("queue_bpop_wait_seconds_bucket{le=\"5\"} 2\n"));
+            assert!(metrics.contains("queue_bpop_wait_seconds_count 2\n"));
+        }
+    }
+}
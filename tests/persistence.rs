@@ -0,0 +1,74 @@
+#![feature(plugin)]
+#![cfg_attr(test, plugin(stainless))]
+
+#[cfg(test)]
+mod tests {
+    extern crate queue_experiments;
+    pub use self::queue_experiments::connection::{Connection};
+    pub use self::queue_experiments::persistence::{FsyncPolicy};
+    pub use self::queue_experiments::queue_table::{QueueTable};
+    pub use std::fs;
+    pub use std::io::Write;
+    pub use std::path::{PathBuf};
+    pub use std::thread;
+
+    describe! persistence {
+        before_each {
+            let path = std::env::temp_dir().join(format!("queue_experiments_{}_{}.aof", std::process::id(), thread::current().name().unwrap_or("test").replace("::", "_")));
+            let _ = fs::remove_file(&path);
+            let queue_table = QueueTable::new();
+            queue_table.persistence().open(&path, FsyncPolicy::No, &queue_table).unwrap();
+        }
+
+        after_each {
+            let _ = fs::remove_file(&path);
+        }
+
+        it "open_replays_what_was_written_before_a_restart" {
+            let mut connection = Connection::new(&queue_table);
+            connection.feed(b"PUSH 'queue' 'a';PUSH 'queue' 'b';POP 'queue';LIMIT 'queue' '5' '0' 'reject';SPUSH 'stream' 'x';SPUSH 'stream' 'y';SCOMMIT 'stream' 'group' '0';BEGIN;POP 'queue';");
+
+            let restarted = QueueTable::new();
+            assert_eq!(restarted.persistence().open(&path, FsyncPolicy::No, &restarted), Ok(9));
+
+            let queue = restarted.get_queue(&"queue".to_string()).unwrap();
+            assert_eq!((queue.pop_front(), queue.pop_front()), (Some("b".to_string()), None));
+            assert_eq!(queue.snapshot().limits.max_len, Some(5));
+            assert_eq!(restarted.get_or_create_stream("stream".to_string()).read(&"group".to_string(), 10), vec![(1, "y".to_string())]);
+        }
+
+        it "open_rewrites_the_log_as_a_snapshot" {
+            let mut connection = Connection::new(&queue_table);
+            for _ in 0..10 {
+                connection.feed(b"PUSH 'queue' 'a';POP 'queue';");
+            }
+            connection.feed(b"PUSH 'queue' 'b';");
+
+            let restarted = QueueTable::new();
+            restarted.persistence().open(&path, FsyncPolicy::No, &restarted).unwrap();
+            let again = QueueTable::new();
+
+            assert_eq!(again.persistence().open(&path, FsyncPolicy::No, &again), Ok(4));
+            assert_eq!(again.get_queue(&"queue".to_string()).unwrap().pop_front(), Some("b".to_string()));
+        }
+
+        it "open_ignores_an_operation_cut_off_by_a_crash" {
+            let mut connection = Connection::new(&queue_table);
+            connection.feed(b"PUSH 'queue' 'a';");
+            fs::OpenOptions::new().append(true).open(&path).unwrap().write_all(b"PUSH 'queue' 'b").unwrap();
+
+            let restarted = QueueTable::new();
+            restarted.persistence().open(&path, FsyncPolicy::No, &restarted).unwrap();
+
+            let queue = restarted.get_queue(&"queue".to_string()).unwrap();
+            assert_eq!((queue.pop_front(), queue.pop_front()), (Some("a".to_string()), None));
+        }
+
+        it "open_fails_for_a_corrupt_log" {
+            fs::write(&path, b"PUSH 'queue';").unwrap();
+
+            let restarted = QueueTable::new();
+            assert!(restarted.persistence().open(&path, FsyncPolicy::No, &restarted).is_err());
+        }
+    }
+}
@@ -0,0 +1,141 @@
+#![feature(plugin)]
+#![cfg_attr(test, plugin(stainless))]
+
+#[cfg(test)]
+mod tests {
+    extern crate queue_experiments;
+    pub use self::queue_experiments::connection::{Connection,Status};
+    pub use self::queue_experiments::queue_table::{QueueTable,Item};
+    pub use self::queue_experiments::replication::{Operation,apply,snapshot};
+    pub use std::time::{Duration,Instant};
+
+    describe! replication {
+        before_each {
+            let leader = QueueTable::new();
+            let follower = QueueTable::new();
+        }
+
+        it "it_round_trips_operations_through_their_encoding" {
+            let operation = Operation::Push("queue".to_string(), "it's \\ data".to_string(), Some("group".to_string()), Some(Duration::from_millis(1500)));
+            let encoded = operation.encode();
+
+            assert_eq!(encoded, "PUSH 'queue' 'it\\'s \\\\ data' 'group' '1500';".to_string());
+            assert_eq!(Operation::parse(encoded[..encoded.len() - 1].as_bytes().to_vec()), Ok(operation));
+        }
+
+        it "snapshot_recreates_queues_streams_and_held_items" {
+            let mut connection = Connection::new(&leader);
+            connection.feed(b"PUSH 'queue' 'a';PUSH 'queue' 'b';LIMIT 'queue' '5' '0' 'drop';SPUSH 'stream' 'x';SPUSH 'stream' 'y';SCOMMIT 'stream' 'group' '0';BEGIN;POP 'queue';");
+
+            for operation in snapshot(&leader) {
+                apply(&follower, operation).unwrap();
+            }
+
+            let queue = follower.get_queue(&"queue".to_string()).unwrap();
+            assert_eq!((queue.pop_front(), queue.pop_front()), (Some("a".to_string()), Some("b".to_string())));
+            assert_eq!(follower.get_or_create_stream("stream".to_string()).read(&"group".to_string(), 10), vec![(1, "y".to_string())]);
+            assert_eq!(follower.get_or_create_stream("stream".to_string()).append("z".to_string()), Ok(2));
+        }
+
+        it "sync_streams_committed_operations_to_the_follower" {
+            let mut replica = Connection::new(&leader);
+            let mut connection = Connection::new(&leader);
+            replica.feed(b"SYNC;");
+            connection.feed(b"PUSH 'queue' 'a';PUSH 'queue' 'b';BEGIN;POP 'queue';PUSH 'queue' 'c';");
+
+            let output = String::from_utf8(replica.take_output()).unwrap();
+            for message in output["SUCCESS\r\n".len()..].split_terminator(';') {
+                apply(&follower, Operation::parse(message.as_bytes().to_vec()).unwrap()).unwrap();
+            }
+            assert_eq!(follower.get_queue(&"queue".to_string()).unwrap().len(), 2);
+
+            connection.feed(b"COMMIT;");
+            let output = String::from_utf8(replica.take_output()).unwrap();
+            assert_eq!(output, "REMOVE 'queue' 'a' '';PUSH 'queue' 'c' '' '';".to_string());
+        }
+
+        it "follower_applies_pushes_the_leader_accepted_past_its_limits" {
+            let mut replica = Connection::new(&leader);
+            let mut consumer = Connection::new(&leader);
+            let mut producer = Connection::new(&leader);
+            replica.feed(b"SYNC;");
+            producer.feed(b"LIMIT 'queue' '1' '0' 'reject';PUSH 'queue' 'a';");
+            consumer.feed(b"BEGIN;POP 'queue';");
+            producer.feed(b"PUSH 'queue' 'b';");
+
+            // The held item still counts on the follower until the commit removes it
+            let output = String::from_utf8(replica.take_output()).unwrap();
+            for message in output["SUCCESS\r\n".len()..].split_terminator(';') {
+                apply(&follower, Operation::parse(message.as_bytes().to_vec()).unwrap()).unwrap();
+            }
+            let queue = follower.get_queue(&"queue".to_string()).unwrap();
+            assert_eq!((queue.pop_front(), queue.pop_front()), (Some("a".to_string()), Some("b".to_string())));
+        }
+
+        it "follower_drops_what_the_leader_dropped_to_make_room" {
+            let mut replica = Connection::new(&leader);
+            let mut producer = Connection::new(&leader);
+            replica.feed(b"SYNC;");
+            producer.feed(b"LIMIT 'queue' '1' '0' 'drop';PUSH 'queue' 'a';PUSH 'queue' 'b';");
+
+            let output = String::from_utf8(replica.take_output()).unwrap();
+            for message in output["SUCCESS\r\n".len()..].split_terminator(';') {
+                apply(&follower, Operation::parse(message.as_bytes().to_vec()).unwrap()).unwrap();
+            }
+            let queue = follower.get_queue(&"queue".to_string()).unwrap();
+            assert_eq!((queue.pop_front(), queue.pop_front()), (Some("b".to_string()), None));
+        }
+
+        it "follower_moves_what_the_leader_expired_to_the_dead_letter_queue" {
+            let expired = Instant::now() - Duration::from_secs(1);
+            let mut connection = Connection::new(&leader);
+            connection.feed(b"DEADLETTER 'queue' 'dead';");
+            leader.get_or_create_queue("queue".to_string()).push_item(Item::new("old".to_string(), None, Some(expired))).unwrap();
+            let mut replica = Connection::new(&leader);
+            replica.feed(b"SYNC;");
+            connection.feed(b"POP 'queue';");
+
+            let output = String::from_utf8(replica.take_output()).unwrap();
+            for message in output["SUCCESS\r\n".len()..].split_terminator(';') {
+                apply(&follower, Operation::parse(message.as_bytes().to_vec()).unwrap()).unwrap();
+            }
+            assert_eq!(follower.get_queue(&"queue".to_string()).unwrap().len(), 0);
+            assert_eq!(follower.get_queue(&"dead".to_string()).unwrap().pop_front(), Some("old".to_string()));
+        }
+
+        it "write_concern_holds_the_reply_until_the_follower_acks" {
+            let mut replica = Connection::new(&leader);
+            let mut connection = Connection::new(&leader);
+            replica.feed(b"SYNC;");
+            replica.take_output();
+
+            assert_eq!(connection.feed(b"WRITECONCERN '1' '10000';PUSH 'queue' 'a';"), Status::Blocked);
+            assert_eq!(String::from_utf8(connection.take_output()).unwrap(), "SUCCESS\r\n".to_string());
+
+            replica.feed(b"ACK '1';");
+            assert_eq!(connection.retry_blocked(), Status::Ready);
+            assert_eq!(String::from_utf8(connection.take_output()).unwrap(), "SUCCESS\r\n".to_string());
+            assert_eq!(replica.take_output(), b"PUSH 'queue' 'a' '' '';".to_vec());
+        }
+
+        it "queue_write_concern_times_out_without_followers" {
+            let mut connection = Connection::new(&leader);
+
+            connection.feed(b"WRITECONCERN 'queue' '1' '0';PUSH 'queue' 'a';BEGIN;PUSH 'queue' 'b';COMMIT;PUSH 'other' 'c';");
+
+            assert_eq!(String::from_utf8(connection.take_output()).unwrap(),
+                       "SUCCESS\r\nREPLICATION TIMEOUT\r\nREPLICATION TIMEOUT\r\nSUCCESS\r\n".to_string());
+            assert_eq!(leader.get_queue(&"queue".to_string()).unwrap().len(), 2);
+        }
+
+        it "follower_refuses_commands_that_change_data_until_promoted" {
+            leader.replication().set_leader("127.0.0.1:5248".to_string());
+            let mut connection = Connection::new(&leader);
+
+            connection.feed(b"PUSH 'queue' 'a';MEMORY;PROMOTE;PUSH 'queue' 'a';PROMOTE;");
+
+            assert_eq!(String::from_utf8(connection.take_output()).unwrap(),
+                       "READONLY\r\nused_memory:0 maxmemory:0\r\nSUCCESS\r\nSUCCESS\r\nNOT A FOLLOWER\r\n".to_string());
+        }
+    }
+}
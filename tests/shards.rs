@@ -0,0 +1,54 @@
+#![feature(plugin)]
+#![cfg_attr(test, plugin(stainless))]
+
+#[cfg(test)]
+mod tests {
+    extern crate queue_experiments;
+    pub use self::queue_experiments::connection::{Connection};
+    pub use self::queue_experiments::queue_table::{QueueTable};
+    pub use self::queue_experiments::shards::{ShardRange,slot};
+
+    describe! shards {
+        before_each {
+            let queue_table = QueueTable::new();
+            let local = "127.0.0.1:5001".parse().unwrap();
+            queue_table.shards().configure(vec![
+                ShardRange { start: 8192, end: 16383, address: "127.0.0.1:5002".parse().unwrap() },
+                ShardRange { start: 0, end: 8191, address: local }
+            ], local);
+            let mut connection = Connection::new(&queue_table);
+        }
+
+        it "hashes_names_like_redis_cluster" {
+            assert_eq!(slot("123456789"), 12739);
+            assert_eq!(slot("foo"), 12182);
+            assert_eq!(slot("{foo}.failed"), 12182);
+            assert!(slot("{}foo") != slot("foo"));
+        }
+
+        it "moves_commands_on_queues_held_elsewhere" {
+            connection.feed(b"PUSH 'foo' 'a';PUSH 'bar' 'b';CLUSTER 'keyslot' 'foo';");
+            assert_eq!(String::from_utf8(connection.take_output()).unwrap(),
+                       "MOVED 12182 127.0.0.1:5002\r\nSUCCESS\r\n12182\r\n".to_string());
+            assert!(queue_table.get_queue(&"foo".to_string()).is_none());
+        }
+
+        it "keeps_transactions_to_one_slot" {
+            connection.feed(b"BEGIN;PUSH 'bar' 'a';PUSH 'baz' 'b';PUSH '{bar}.failed' 'c';COMMIT;");
+            assert_eq!(String::from_utf8(connection.take_output()).unwrap(), "CROSSSLOT\r\n".to_string());
+            assert_eq!(queue_table.get_queue(&"{bar}.failed".to_string()).map(|queue| queue.len()), Some(1));
+            assert!(queue_table.get_queue(&"baz".to_string()).is_none());
+
+            // The next transaction can pick another slot
+            connection.feed(b"BEGIN;PUSH 'baz' 'a';COMMIT;");
+            assert_eq!(String::from_utf8(connection.take_output()).unwrap(), "".to_string());
+            assert_eq!(queue_table.get_queue(&"baz".to_string()).map(|queue| queue.len()), Some(1));
+        }
+
+        it "lists_the_slot_ranges" {
+            connection.feed(b"CLUSTER 'slots';");
+            assert_eq!(String::from_utf8(connection.take_output()).unwrap(),
+                       "2\r\n0 8191 127.0.0.1:5001\r\n8192 16383 127.0.0.1:5002\r\n".to_string());
+        }
+    }
+}